@@ -0,0 +1,173 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{Error, ErrorKind::InvalidData, Result};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkcs5::pbkdf2_hmac;
+use openssl::sha::Sha256;
+
+const PBKDF2_SHA256: &str = "pbkdf2-sha256";
+
+/// passlib's default rounds, used for unknown users when no hash sets any.
+const DEFAULT_ROUNDS: usize = 29000;
+
+/// Password hashes by user name, read from a file of `user:hash` lines. Hashes are PBKDF2-SHA256
+/// in the passlib format, `$pbkdf2-sha256$<rounds>$<salt>$<checksum>`, e.g. as printed by
+/// `python3 -c 'from passlib.hash import pbkdf2_sha256; print(pbkdf2_sha256.hash("secret"))'`.
+pub struct Credentials {
+    hashes: HashMap<String, PasswordHash>,
+    /// Checked in place of an unknown user's hash, so unknown users take as long to refuse.
+    unknown: PasswordHash,
+    /// Digests of credentials already verified, sparing their key derivation on later requests.
+    verified: Mutex<HashSet<[u8; 32]>>,
+}
+
+impl Credentials {
+    pub fn load<P: AsRef<Path>>( path: P ) -> Result<Self> {
+        let path = path.as_ref();
+        let context = |e: String| Error::new( InvalidData, format!( "invalid credentials file {}: {}", path.display(), e ) );
+
+        let mut hashes = HashMap::new();
+        for ( number, line ) in fs::read_to_string( path )?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with( '#' ) {
+                continue;
+            }
+
+            let ( user, hash ) = line.split_at( line.find( ':' ).ok_or_else( || context( format!( "line {} has no user", number + 1 ) ) )? );
+            let hash = hash[1..].parse::<PasswordHash>().map_err( |e| context( format!( "line {}: {}", number + 1, e ) ) )?;
+            hashes.insert( user.to_string(), hash );
+        }
+
+        let rounds = hashes.values().map( |h: &PasswordHash| h.rounds ).max().unwrap_or( DEFAULT_ROUNDS );
+        let unknown = PasswordHash { rounds, salt: vec![ 0; 16 ], checksum: vec![ 0; 32 ], };
+        Ok( Credentials { hashes, unknown, verified: Mutex::new( HashSet::new() ), } )
+    }
+
+    /// Whether `password` is the password of `user`. Deriving the key is slow by design, so this
+    /// should not be called on a worker unless `remembers` the credentials.
+    pub fn verify( &self, user: &str, password: &str ) -> bool {
+        if self.remembers( user, password ) {
+            return true;
+        }
+
+        let verified = match self.hashes.get( user ) {
+            Some(hash) => hash.verify( password ),
+            None => {
+                self.unknown.verify( password );
+                false
+            },
+        };
+        if verified {
+            self.verified.lock().unwrap().insert( digest( user, password ) );
+        }
+        verified
+    }
+
+    /// Whether the credentials verified before, so checking them again is cheap.
+    pub fn remembers( &self, user: &str, password: &str ) -> bool {
+        self.verified.lock().unwrap().contains( &digest( user, password ) )
+    }
+}
+
+fn digest( user: &str, password: &str ) -> [u8; 32] {
+    let mut digest = Sha256::new();
+    digest.update( user.as_bytes() );
+    digest.update( b"\0" );
+    digest.update( password.as_bytes() );
+    digest.finish()
+}
+
+struct PasswordHash {
+    rounds: usize,
+    salt: Vec<u8>,
+    checksum: Vec<u8>,
+}
+
+impl PasswordHash {
+    fn verify( &self, password: &str ) -> bool {
+        let mut key = vec![ 0; self.checksum.len() ];
+        pbkdf2_hmac( password.as_bytes(), &self.salt, self.rounds, MessageDigest::sha256(), &mut key )
+            .map( |_| memcmp::eq( &key, &self.checksum ) )
+            .unwrap_or( false )
+    }
+}
+
+impl FromStr for PasswordHash {
+    type Err = String;
+
+    fn from_str( hash: &str ) -> std::result::Result<Self, Self::Err> {
+        let parts = hash.split( '$' ).collect::<Vec<&str>>();
+        match parts.as_slice() {
+            [ "", PBKDF2_SHA256, rounds, salt, checksum ] => {
+                let rounds = rounds.parse::<usize>().map_err( |e| format!( "invalid rounds {}: {}", rounds, e ) )?;
+                let checksum = ab64_decode( checksum )?;
+                if checksum.is_empty() {
+                    return Err( "empty checksum".to_string() );
+                }
+
+                Ok( PasswordHash { rounds, salt: ab64_decode( salt )?, checksum, } )
+            },
+            _ => Err( format!( "unsupported password hash; expected ${}$<rounds>$<salt>$<checksum>", PBKDF2_SHA256 ) ),
+        }
+    }
+}
+
+/// Decodes passlib's base64 variant, which uses `.` for `+` and omits padding.
+fn ab64_decode( value: &str ) -> std::result::Result<Vec<u8>, String> {
+    base64::decode_config( &value.replace( '.', "+" ), base64::STANDARD_NO_PAD )
+        .map_err( |e| format!( "invalid base64 {}: {}", value, e ) )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `secret` hashed by passlib with 1000 rounds and the salt `egress-proxy-salt`.
+    const SECRET_HASH: &str = "$pbkdf2-sha256$1000$ZWdyZXNzLXByb3h5LXNhbHQ$dEUwVDoKpCTN801vyAECm7fS.P3FTpsbCldIWVuaayQ";
+
+    fn load( name: &str, contents: &str ) -> Result<Credentials> {
+        let path = std::env::temp_dir().join( format!( "egress-proxy-credentials-{}-{}", std::process::id(), name ) );
+        fs::write( &path, contents ).unwrap();
+        let credentials = Credentials::load( &path );
+        fs::remove_file( &path ).unwrap();
+        credentials
+    }
+
+    #[test]
+    fn verifies_passlib_hashes() {
+        let credentials = load( "valid", &format!( "# callers\n\nbilling:{}\n", SECRET_HASH ) ).unwrap();
+        assert!( !credentials.remembers( "billing", "secret" ) );
+        assert!( credentials.verify( "billing", "secret" ) );
+        assert!( credentials.remembers( "billing", "secret" ) );
+
+        assert!( !credentials.verify( "billing", "Secret" ) );
+        assert!( !credentials.verify( "search", "secret" ) );
+        assert!( !credentials.remembers( "billing", "Secret" ) );
+    }
+
+    #[test]
+    fn checks_unknown_users_as_slowly_as_known_ones() {
+        let credentials = load( "rounds", &format!( "billing:{}\n", SECRET_HASH ) ).unwrap();
+        assert_eq!( credentials.unknown.rounds, 1000 );
+        assert_eq!( load( "empty", "" ).unwrap().unknown.rounds, DEFAULT_ROUNDS );
+    }
+
+    #[test]
+    fn refuses_invalid_lines() {
+        assert!( load( "no-user", "billing\n" ).is_err() );
+        assert!( load( "scheme", "billing:$pbkdf2-sha512$1000$c2FsdA$c2FsdA\n" ).is_err() );
+        assert!( load( "bad-rounds", "billing:$pbkdf2-sha256$many$c2FsdA$c2FsdA\n" ).is_err() );
+        assert!( load( "no-checksum", "billing:$pbkdf2-sha256$1000$c2FsdA$\n" ).is_err() );
+        assert!( load( "bad-base64", "billing:$pbkdf2-sha256$1000$c2FsdA$not*base64\n" ).is_err() );
+    }
+
+    #[test]
+    fn decodes_passlib_base64() {
+        assert_eq!( ab64_decode( "ab.c" ).unwrap(), base64::decode( "ab+c" ).unwrap() );
+        assert_eq!( ab64_decode( "c2FsdA" ).unwrap(), b"salt" );
+    }
+}
@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind::InvalidData, Result};
+use std::path::Path;
+use openssl::sha::sha256;
+
+/// Caller names by the SHA-256 digest of their bearer token, read from a file of `name:digest`
+/// lines with hex digests, e.g. as printed by `printf %s "$TOKEN" | sha256sum`.
+pub struct BearerTokens( HashMap<[u8; 32], String> );
+
+impl BearerTokens {
+    pub fn load<P: AsRef<Path>>( path: P ) -> Result<Self> {
+        let path = path.as_ref();
+        let context = |line: usize| {
+            Error::new( InvalidData, format!( "invalid tokens file {}: line {} is not name:sha256", path.display(), line ) )
+        };
+
+        let mut tokens = HashMap::new();
+        for ( number, line ) in fs::read_to_string( path )?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with( '#' ) {
+                continue;
+            }
+
+            let mut parts = line.rsplitn( 2, ':' );
+            let digest = parts.next().and_then( hex_digest ).ok_or_else( || context( number + 1 ) )?;
+            let name = parts.next().filter( |n| !n.is_empty() ).ok_or_else( || context( number + 1 ) )?;
+            tokens.insert( digest, name.to_string() );
+        }
+
+        Ok( BearerTokens( tokens ) )
+    }
+
+    /// The name of the caller holding `token`.
+    pub fn identify( &self, token: &str ) -> Option<&str> {
+        self.0.get( &sha256( token.as_bytes() ) ).map( |name| name.as_str() )
+    }
+}
+
+fn hex_digest( hex: &str ) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut digest = [0; 32];
+    for ( i, byte ) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix( &hex[2 * i..2 * i + 2], 16 ).ok()?;
+    }
+    Some( digest )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// SHA-256 of `token-for-billing`.
+    const BILLING_DIGEST: &str = "f229d839b8a21c1d528e58f22caa34fb51afbdf887e7fce49825cdd7aefb08d1";
+
+    fn load( name: &str, contents: &str ) -> Result<BearerTokens> {
+        let path = std::env::temp_dir().join( format!( "egress-proxy-tokens-{}-{}", std::process::id(), name ) );
+        fs::write( &path, contents ).unwrap();
+        let tokens = BearerTokens::load( &path );
+        fs::remove_file( &path ).unwrap();
+        tokens
+    }
+
+    #[test]
+    fn identifies_callers_by_token_digest() {
+        let tokens = load( "valid", &format!( "# callers\n\nbilling:{}\n", BILLING_DIGEST ) ).unwrap();
+        assert_eq!( tokens.identify( "token-for-billing" ), Some( "billing" ) );
+        assert_eq!( tokens.identify( "token-for-billing " ), None );
+        assert_eq!( tokens.identify( BILLING_DIGEST ), None );
+
+        let tokens = load( "upper", &format!( "ci:billing:{}\n", BILLING_DIGEST.to_uppercase() ) ).unwrap();
+        assert_eq!( tokens.identify( "token-for-billing" ), Some( "ci:billing" ) );
+    }
+
+    #[test]
+    fn refuses_invalid_lines() {
+        assert!( load( "no-name", &format!( ":{}\n", BILLING_DIGEST ) ).is_err() );
+        assert!( load( "no-digest", "billing\n" ).is_err() );
+        assert!( load( "short", &format!( "billing:{}\n", &BILLING_DIGEST[2..] ) ).is_err() );
+        assert!( load( "not-hex", &format!( "billing:{}\n", BILLING_DIGEST.replace( 'f', "g" ) ) ).is_err() );
+    }
+}
@@ -1,19 +1,40 @@
 #[macro_use] extern crate log;
 
-use actix_web::{client::Client, middleware::Logger, App, HttpServer, web, HttpResponse};
+use std::time::Duration;
+
+use actix_http::HttpService;
+use actix_server::Server;
+use actix_service::{IntoNewService, NewService};
+use actix_web::{middleware::Logger, App, web, HttpResponse};
 use egress_proxy::{
     config::Config,
+    handlers,
     handlers::proxy,
-    handlers::metrics,
+    metrics,
     middleware::latency::MeasureLatencyCollection,
     metrics::MetricsCollection,
 };
-use egress_proxy::middleware::proxy_filter::ProxyFilterCollection;
-use egress_proxy::border::host_control::HostControlBuilder;
+use egress_proxy::middleware::proxy_filter::{FailMode, ProxyFilterCollection};
+use egress_proxy::middleware::authentication::AuthenticationCollection;
+use egress_proxy::middleware::learning::LearningCollection;
+use egress_proxy::middleware::quota::QuotaCollection;
+use egress_proxy::middleware::rate_limit::RateLimitCollection;
 use egress_proxy::border::BorderControlBuilder;
+use egress_proxy::border::policy::ReloadableBorder;
+use egress_proxy::config::reload::{CertificateWatcher, KeySetWatcher, PolicyWatcher};
+use egress_proxy::listener::AcceptError;
+use egress_proxy::listener::proxy_protocol::ProxyProtocolAcceptor;
+use egress_proxy::listener::tls::{ReloadableAcceptor, TlsStream};
+use egress_proxy::listener::tunnel::Tunnels;
+use egress_proxy::border::learning::Learner;
+use egress_proxy::auth::Authenticator;
+use egress_proxy::limits::quota::QuotaLedger;
+use egress_proxy::upstream::UpstreamClients;
+
+const DEFAULT_LOG_FILTER: &str = "egress_proxy=debug,actix_server=debug,actix_web=debug,main=debug,mio=info,tokio_reactor=info";
 
-fn setup_logger() {
-    std::env::set_var( "RUST_LOG", "egress_proxy=debug,actix_server=debug,actix_web=debug,main=debug,mio=info,tokio_reactor=info" );
+fn setup_logger( filter: Option<&str> ) {
+    std::env::set_var( "RUST_LOG", filter.unwrap_or( DEFAULT_LOG_FILTER ) );
 //    std::env::set_var( "RUST_LOG", "trace" );
 //    env_logger::init();
 
@@ -30,40 +51,202 @@ fn setup_logger() {
         .unwrap();
 }
 
-fn main() -> std::io::Result<()> {
-    setup_logger();
+fn proxy_filter( border: ReloadableBorder, shadow: Option<ReloadableBorder>, fail_mode: FailMode, decision_timeout: Duration ) -> ProxyFilterCollection {
+    let filter = ProxyFilterCollection::new()
+        .with_async_border( Box::new( border ) )
+        .with_fail_mode( fail_mode )
+        .with_decision_timeout( decision_timeout );
+    match shadow {
+        Some(shadow) => filter.with_shadow_border( Box::new( shadow ) ),
+        None => filter,
+    }
+}
 
+fn quota_collection( ledger: Option<QuotaLedger> ) -> QuotaCollection {
+    match ledger {
+        Some(ledger) => QuotaCollection::new().with_ledger( ledger ),
+        None => QuotaCollection::new(),
+    }
+}
+
+fn learning_collection( learner: Option<Learner> ) -> LearningCollection {
+    match learner {
+        Some(learner) => LearningCollection::new().with_learner( learner ),
+        None => LearningCollection::new(),
+    }
+}
+
+fn authentication_collection( authenticator: Option<Authenticator> ) -> AuthenticationCollection {
+    match authenticator {
+        Some(authenticator) => AuthenticationCollection::new().with_authenticator( authenticator ),
+        None => AuthenticationCollection::new(),
+    }
+}
+
+fn main() -> std::io::Result<()> {
     let cfg = Config::from_args();
+    if let Some(ref test) = cfg.policy_test {
+        match test.run() {
+            Ok(passed) => std::process::exit( if passed { 0 } else { 1 } ),
+            Err(e) => {
+                eprintln!( "{}", e );
+                std::process::exit( 2 );
+            },
+        }
+    }
+
+    setup_logger( cfg.log_filter() );
     info!( "App Config = {:?}", cfg );
-    let forward_url = cfg.forward_url.clone();
 
-    HttpServer::new( move || {
+    if let Some(ref settings) = cfg.settings {
+        metrics::set_const_labels( settings.metrics.labels.clone() );
+    }
+
+    let tls_profiles = cfg.tls_profiles()?;
+    let border = ReloadableBorder::new( cfg.border_builder()?.build() );
+    if let Some(ref path) = cfg.config_file {
+        PolicyWatcher::new( path.clone(), border.clone() ).with_tls_profiles( tls_profiles.clone() ).spawn()?;
+    }
+
+    let shadow = cfg.shadow_border_builder()?.map( |builder| ReloadableBorder::shadow( builder.build() ) );
+    if let ( Some(path), Some(shadow) ) = ( cfg.shadow_config_file.as_ref(), shadow.as_ref() ) {
+        PolicyWatcher::new( path.clone(), shadow.clone() ).spawn()?;
+    }
+
+    let learner = cfg.learn_file.as_ref().map( |_| Learner::new() );
+    if let ( Some(path), Some(learner) ) = ( cfg.learn_file.as_ref(), learner.as_ref() ) {
+        learner.clone().spawn_writer( path.clone() )?;
+    }
+
+    let limiter = cfg.rate_limiter()?;
+    let quotas = cfg.quota_ledger()?;
+    let authenticator = cfg.authenticator()?;
+    if let Some(validator) = authenticator.as_ref().and_then( |a| a.jwt_validator() ) {
+        KeySetWatcher::new( validator.clone() ).spawn()?;
+    }
+
+    let policy = cfg.policy_settings();
+    let ( fail_mode, decision_timeout ) = ( policy.fail_mode(), policy.decision_timeout()? );
+    let admin_app = {
+        let ( quotas, learner, border ) = ( quotas.clone(), learner.clone(), border.clone() );
+        move || {
+            App::new()
+                .data( quotas.clone() )
+                .data( learner.clone() )
+                .data( border.clone() )
+                .wrap( Logger::new( r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %D"# ) )
+                .service(
+                    web::resource("/__proxy/metrics" )
+                        .default_service(
+                            web::route().to( HttpResponse::MethodNotAllowed ),
+                        )
+                        .route(web::get().to_async(handlers::metrics::gather ) ),
+                )
+                .service(
+                    web::resource("/__proxy/quotas" )
+                        .default_service(
+                            web::route().to( HttpResponse::MethodNotAllowed ),
+                        )
+                        .route(web::get().to(handlers::quotas::usage ) ),
+                )
+                .service(
+                    web::resource("/__proxy/learned" )
+                        .default_service(
+                            web::route().to( HttpResponse::MethodNotAllowed ),
+                        )
+                        .route(web::get().to(handlers::learned::config ) ),
+                )
+                .service(
+                    web::resource("/__proxy/policy/simulate" )
+                        .default_service(
+                            web::route().to( HttpResponse::MethodNotAllowed ),
+                        )
+                        .route(web::post().to_async(handlers::simulate::simulate ) ),
+                )
+        }
+    };
+
+    let app = move || {
         App::new()
-            .data( Client::new() )
-            .data( forward_url.clone() )
+            .data( UpstreamClients::new( &tls_profiles ) )
             .data( MetricsCollection::new() )
             .wrap( Logger::new( r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %D"# ) )
             .default_service(
                 web::resource("")
                     .wrap(MeasureLatencyCollection::new() )
-                    .wrap(
-                        ProxyFilterCollection::new().with_border(
-                            HostControlBuilder::new()
-                                .with_default_destination( forward_url.clone() )
-                                .build()
-                        )
-                    )
+                    .wrap( quota_collection( quotas.clone() ) )
+                    .wrap( learning_collection( learner.clone() ) )
+                    .wrap( RateLimitCollection::new().with_limiter( limiter.clone() ) )
+                    .wrap( proxy_filter( border.clone(), shadow.clone(), fail_mode, decision_timeout ) )
+                    .wrap( authentication_collection( authenticator.clone() ) )
                     .to_async( proxy::forward )
             )
+            // admin endpoints other than metrics are served on the admin listener only
             .service(
                 web::resource("/__proxy/metrics" )
                     .default_service(
-                        web::route().to( || HttpResponse::MethodNotAllowed() ),
+                        web::route().to( HttpResponse::MethodNotAllowed ),
                     )
-                    .route(web::get().to_async(metrics::gather ) ),
+                    .route(web::get().to_async(handlers::metrics::gather ) ),
             )
-    } )
-        .listen( cfg.tcp_listener().unwrap() )?
-        .system_exit()
-        .run()
+            .service( web::resource("/__proxy/{endpoint:.*}" ).to( HttpResponse::NotFound ) )
+    };
+
+    let mut server = Server::build();
+    match cfg.admin_socket_address {
+        Some(addr) => {
+            info!( "serving admin endpoints on {}", addr );
+            server = server.bind( "egress-proxy-admin", addr, move || HttpService::build().finish( admin_app() ) )?;
+        },
+        None => info!( "no admin listener configured; quota, learned config and simulation endpoints are not served" ),
+    }
+
+    for listener in cfg.tcp_listeners()? {
+        let addr = listener.local_addr()?;
+        let name = format!( "egress-proxy-{}", addr );
+        let app = app.clone();
+
+        let proxy_protocol = cfg.proxy_protocol( &addr )?;
+        if proxy_protocol.is_some() {
+            info!( "expecting the PROXY protocol on {}", addr );
+        }
+
+        server = match cfg.listener_tls( &addr ) {
+            Some(tls) => {
+                let acceptor = ReloadableAcceptor::new( tls )?;
+                CertificateWatcher::new( acceptor.clone() ).spawn()?;
+                info!( "terminating TLS on {}", addr );
+
+                server.listen( name, listener, move || {
+                    ProxyProtocolAcceptor::new( proxy_protocol.clone() )
+                        .and_then( acceptor.service() )
+                        .map_err( AcceptError::Accept )
+                        .and_then(
+                            HttpService::build()
+                                .on_connect( |io: &TlsStream<_>| io.peer_identity() )
+                                .upgrade( Tunnels::new( app().into_new_service() ) )
+                                .finish( app() )
+                                .map_err( AcceptError::Service )
+                                .map_init_err( |_| () )
+                        )
+                } )?
+            },
+
+            None => server.listen( name, listener, move || {
+                ProxyProtocolAcceptor::new( proxy_protocol.clone() )
+                    .map_err( AcceptError::Accept )
+                    .and_then(
+                        HttpService::build()
+                            .upgrade( Tunnels::new( app().into_new_service() ) )
+                            .finish( app() )
+                            .map_err( AcceptError::Service )
+                            .map_init_err( |_| () )
+                    )
+            } )?,
+        };
+    }
+
+    let sys = actix_rt::System::new( "egress-proxy" );
+    server.system_exit().start();
+    sys.run()
 }
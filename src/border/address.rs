@@ -0,0 +1,272 @@
+use std::fmt;
+use std::net::{AddrParseError, IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use futures::Future;
+use futures::future::{err, ok};
+use ipnet::IpNet;
+use log::{debug, warn};
+use url::Host;
+use actix_web::dev::ServiceRequest;
+use super::{AsyncBorderControl, BorderControl, VisaFuture};
+use super::denial::DenialReason;
+use super::visa::Visa;
+
+/// Address ranges internal to our networks that egress traffic must never reach: loopback,
+/// RFC1918 and carrier-grade NAT, link-local (including the cloud metadata service), IPv6
+/// unique local and link-local, unspecified, reserved, benchmarking, broadcast and multicast
+/// addresses, and the IPv6 ranges translated to IPv4 addresses (IPv4-compatible, NAT64, 6to4).
+pub const INTERNAL_RANGES: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "255.255.255.255/32",
+    "::/96",
+    "::/128",
+    "::1/128",
+    "64:ff9b::/96",
+    "2002::/16",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// CIDR allow and deny lists applied to the addresses an egress destination resolves to. An
+/// address in an allowed range is admitted even if it also falls in a denied range, so `allow`
+/// carves exceptions out of `deny`. IPv6 addresses translated to IPv4 are checked both as they
+/// are and as the IPv4 address they reach.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AddressPolicy {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl Default for AddressPolicy {
+    fn default() -> Self {
+        AddressPolicy {
+            allow: Vec::new(),
+            deny: INTERNAL_RANGES.iter().map( |r| r.parse().unwrap() ).collect(),
+        }
+    }
+}
+
+impl AddressPolicy {
+    /// A policy admitting every address.
+    pub fn open() -> Self {
+        AddressPolicy { allow: Vec::new(), deny: Vec::new(), }
+    }
+
+    pub fn allow( mut self, net: IpNet ) -> Self {
+        self.allow.push( net );
+        self
+    }
+
+    pub fn deny( mut self, net: IpNet ) -> Self {
+        self.deny.push( net );
+        self
+    }
+
+    pub fn admits( &self, addr: &IpAddr ) -> bool {
+        let addrs = [ *addr, canonical( addr ) ];
+        let any = |nets: &[IpNet]| nets.iter().any( |net| addrs.iter().any( |a| net.contains( a ) ) );
+        any( &self.allow ) || !any( &self.deny )
+    }
+}
+
+/// Parses a CIDR range, accepting a bare address as a single-host range.
+pub fn parse_net( s: &str ) -> Result<IpNet, AddrParseError> {
+    s.parse::<IpNet>()
+        .or_else( |_| s.parse::<IpAddr>().map( IpNet::from ) )
+}
+
+/// IPv4-mapped IPv6 addresses are the IPv4 address they carry.
+pub(crate) fn unmapped( addr: &IpAddr ) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map( IpAddr::V4 ).unwrap_or( *addr ),
+        IpAddr::V4(_) => *addr,
+    }
+}
+
+/// The IPv4 address an IPv6 address reaches when it is IPv4-mapped (`::ffff:a.b.c.d`),
+/// IPv4-compatible (`::a.b.c.d`), NAT64 (`64:ff9b::a.b.c.d`) or 6to4 (`2002:aabb:ccdd::/48`);
+/// other addresses are their own.
+pub(crate) fn canonical( addr: &IpAddr ) -> IpAddr {
+    let v6 = match addr {
+        IpAddr::V6(v6) => v6,
+        IpAddr::V4(_) => return *addr,
+    };
+
+    let s = v6.segments();
+    let embedded = |high: u16, low: u16| IpAddr::V4( Ipv4Addr::from( u32::from( high ) << 16 | u32::from( low ) ) );
+    match s {
+        [ 0, 0, 0, 0, 0, 0xffff, high, low ] => embedded( high, low ),
+        [ 0, 0, 0, 0, 0, 0, high, low ] => embedded( high, low ),
+        [ 0x64, 0xff9b, 0, 0, 0, 0, high, low ] => embedded( high, low ),
+        [ 0x2002, high, low, .. ] => embedded( high, low ),
+        _ => *addr,
+    }
+}
+
+/// Resolves the destination granted by the wrapped border and refuses the visa if any resolved
+/// address falls outside the address policy. The visa is pinned to the checked address, so the
+/// proxy connects to exactly what was checked and DNS rebinding cannot redirect the request.
+/// Names are resolved asynchronously, on the resolver of the worker deciding the request.
+pub struct ResolvedAddressBorder {
+    inner: Box<dyn BorderControl>,
+    policy: Arc<AddressPolicy>,
+}
+
+impl ResolvedAddressBorder {
+    pub fn new( inner: Box<dyn BorderControl>, policy: AddressPolicy ) -> Self {
+        ResolvedAddressBorder { inner, policy: Arc::new( policy ), }
+    }
+}
+
+fn resolve( visa: &Visa ) -> Box<dyn Future<Item = Vec<IpAddr>, Error = DenialReason>> {
+    let destination = visa.destination.to_string();
+    let unresolvable = move |e: &dyn fmt::Display| {
+        warn!( "failed to resolve egress destination {}: {}", destination, e );
+        DenialReason::UnresolvableDestination( destination.clone() )
+    };
+
+    match visa.destination.host {
+        Host::Ipv4(ip) => Box::new( ok( vec![ IpAddr::V4( ip ) ] ) ),
+        Host::Ipv6(ip) => Box::new( ok( vec![ IpAddr::V6( ip ) ] ) ),
+        Host::Domain(ref name) => Box::new(
+            actix_connect::start_default_resolver()
+                .lookup_ip( name.as_str() )
+                .map( |ips| ips.iter().collect() )
+                .map_err( move |e| unresolvable( &e ) )
+        ),
+    }
+}
+
+/// Pins the visa to the first resolved address, provided the policy admits them all.
+fn pin( policy: &AddressPolicy, mut visa: Visa, addrs: Vec<IpAddr> ) -> Result<Visa, DenialReason> {
+    let destination = visa.destination.to_string();
+
+    if let Some(forbidden) = addrs.iter().find( |a| !policy.admits( a ) ) {
+        return Err(
+            DenialReason::ForbiddenAddress { destination, address: forbidden.to_string() }
+        );
+    }
+
+    let addr = addrs.into_iter()
+        .next()
+        .map( |ip| SocketAddr::new( ip, visa.destination.port ) )
+        .ok_or( DenialReason::UnresolvableDestination( destination ) )?;
+
+    debug!( "egress destination {} pinned to {}", visa.destination, addr );
+    visa.resolved = Some( addr );
+    Ok( visa )
+}
+
+impl AsyncBorderControl for ResolvedAddressBorder {
+    fn apply_for_visa( &self, req: &ServiceRequest ) -> VisaFuture {
+        let visa = match self.inner.request_visa( req ) {
+            Ok(visa) => visa,
+            Err(reason) => return Box::new( err( reason ) ),
+        };
+
+        let policy = self.policy.clone();
+        Box::new( resolve( &visa ).and_then( move |addrs| pin( &policy, visa, addrs ) ) )
+    }
+
+    /// None: a visa unchecked against the address policy could reach any internal address by a
+    /// slow or rebinding name, so an undecided request is never granted.
+    fn decide_without_io( &self, _req: &ServiceRequest ) -> Option<Result<Visa, DenialReason>> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn admitted( policy: &AddressPolicy, addr: &str ) -> bool {
+        policy.admits( &addr.parse().unwrap() )
+    }
+
+    #[test]
+    fn admits_public_addresses() {
+        let policy = AddressPolicy::default();
+        for addr in &[ "93.184.216.34", "8.8.8.8", "2606:2800:220:1:248:1893:25c8:1946" ] {
+            assert!( admitted( &policy, addr ), "{} refused", addr );
+        }
+    }
+
+    #[test]
+    fn refuses_internal_addresses() {
+        let policy = AddressPolicy::default();
+        let internal = [
+            "127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "100.64.0.1", "169.254.169.254",
+            "0.0.0.0", "192.0.0.170", "198.18.0.1", "240.0.0.1", "255.255.255.255", "224.0.0.1",
+            "::", "::1", "fd00::1", "fe80::1", "ff02::1",
+        ];
+        for addr in internal.iter() {
+            assert!( !admitted( &policy, addr ), "{} admitted", addr );
+        }
+    }
+
+    #[test]
+    fn refuses_internal_addresses_reached_through_translation() {
+        let policy = AddressPolicy::default();
+        let translated = [
+            "::ffff:127.0.0.1", "::ffff:169.254.169.254", "::127.0.0.1", "::a9fe:a9fe",
+            "64:ff9b::a9fe:a9fe", "64:ff9b::10.0.0.1", "2002:a9fe:a9fe::1", "2002:7f00:1::",
+        ];
+        for addr in translated.iter() {
+            assert!( !admitted( &policy, addr ), "{} admitted", addr );
+        }
+    }
+
+    #[test]
+    fn unwraps_translated_addresses() {
+        let v4 = |addr: &str| canonical( &addr.parse().unwrap() ).to_string();
+        assert_eq!( v4( "::ffff:10.1.2.3" ), "10.1.2.3" );
+        assert_eq!( v4( "::10.1.2.3" ), "10.1.2.3" );
+        assert_eq!( v4( "64:ff9b::10.1.2.3" ), "10.1.2.3" );
+        assert_eq!( v4( "2002:a01:203::1" ), "10.1.2.3" );
+        assert_eq!( v4( "2001:db8::1" ), "2001:db8::1" );
+        assert_eq!( v4( "10.1.2.3" ), "10.1.2.3" );
+    }
+
+    #[test]
+    fn allowed_ranges_carve_exceptions_out_of_denied_ones() {
+        let policy = AddressPolicy::default().allow( "10.20.0.0/16".parse().unwrap() );
+        assert!( admitted( &policy, "10.20.1.2" ) );
+        assert!( admitted( &policy, "::ffff:10.20.1.2" ) );
+        assert!( admitted( &policy, "64:ff9b::10.20.1.2" ) );
+        assert!( !admitted( &policy, "10.21.1.2" ) );
+    }
+
+    #[test]
+    fn denies_added_ranges() {
+        let policy = AddressPolicy::open().deny( "203.0.113.0/24".parse().unwrap() );
+        assert!( !admitted( &policy, "203.0.113.9" ) );
+        assert!( !admitted( &policy, "::ffff:203.0.113.9" ) );
+        assert!( admitted( &policy, "127.0.0.1" ) );
+    }
+
+    #[test]
+    fn never_decides_without_resolving() {
+        let inner: Box<dyn BorderControl> = Box::new( crate::border::combinators::Refuse::new( "any" ) );
+        let border = ResolvedAddressBorder::new( inner, AddressPolicy::default() );
+        let req = actix_web::test::TestRequest::default().to_srv_request();
+        assert!( border.decide_without_io( &req ).is_none() );
+    }
+
+    #[test]
+    fn parses_bare_addresses_as_single_hosts() {
+        assert_eq!( parse_net( "10.0.0.10" ).unwrap(), "10.0.0.10/32".parse::<IpNet>().unwrap() );
+        assert_eq!( parse_net( "10.0.0.0/24" ).unwrap(), "10.0.0.0/24".parse::<IpNet>().unwrap() );
+        assert!( parse_net( "10.0.0.0/33" ).is_err() );
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use actix_web::HttpMessage;
+use actix_web::dev::ServiceRequest;
+use super::BorderControl;
+use super::denial::DenialReason;
+use super::visa::Visa;
+
+/// Callers not otherwise listed in a caller policy.
+pub static ANY_CALLER: &str = "*";
+
+/// Who is making a request, as established by the listener or by authentication. An identity
+/// has one or more names, e.g. the subject and alternative names of a client certificate; the
+/// first is the one reported. Identities established from token claims also carry attributes,
+/// e.g. the token's scopes, that policies may require.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CallerIdentity {
+    names: Vec<String>,
+    attributes: BTreeMap<String, Vec<String>>,
+}
+
+impl CallerIdentity {
+    pub fn new<S: Into<String>>( name: S ) -> Self {
+        CallerIdentity { names: vec![ name.into() ], attributes: BTreeMap::new(), }
+    }
+
+    /// An identity known by several names, or none if there are no names.
+    pub fn from_names( names: Vec<String> ) -> Option<Self> {
+        if names.is_empty() { None } else { Some( CallerIdentity { names, attributes: BTreeMap::new(), } ) }
+    }
+
+    /// Sets the values of an attribute; an attribute without values is left unset.
+    pub fn with_attribute( mut self, name: &str, values: Vec<String> ) -> Self {
+        if !values.is_empty() {
+            self.attributes.insert( name.to_string(), values );
+        }
+        self
+    }
+
+    pub fn name( &self ) -> &str { &self.names[0] }
+
+    pub fn names( &self ) -> &[String] { &self.names }
+
+    /// The values of an attribute, empty if the identity does not have it.
+    pub fn attribute( &self, name: &str ) -> &[String] {
+        self.attributes.get( name ).map( |v| v.as_slice() ).unwrap_or( &[] )
+    }
+
+    pub fn attributes( &self ) -> &BTreeMap<String, Vec<String>> { &self.attributes }
+
+    /// The identity of the caller of a request, if it has one.
+    pub fn of<R: HttpMessage>( req: &R ) -> Option<CallerIdentity> {
+        let extensions = req.extensions();
+        extensions.get::<CallerIdentity>()
+            .cloned()
+            .or_else( || extensions.get::<ConnectionIdentity>().and_then( |c| c.0.clone() ) )
+    }
+}
+
+impl fmt::Display for CallerIdentity {
+    fn fmt( &self, f: &mut fmt::Formatter ) -> fmt::Result {
+        write!( f, "{}", self.name() )
+    }
+}
+
+/// The identity established for a connection, e.g. from its client certificate, attached to
+/// every request made over it.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionIdentity( pub Option<CallerIdentity> );
+
+/// Restricts callers to the destinations listed for them. A caller is known by the first of its
+/// names with a listing; callers without one, including those with no identity, may use the
+/// destinations listed for `ANY_CALLER` and are otherwise denied.
+pub struct CallerBorder {
+    border: Box<dyn BorderControl>,
+    callers: HashMap<String, HashSet<String>>,
+}
+
+impl CallerBorder {
+    pub fn new( border: Box<dyn BorderControl>, callers: HashMap<String, HashSet<String>> ) -> Self {
+        CallerBorder { border, callers, }
+    }
+
+    fn allowed( &self, identity: Option<&CallerIdentity> ) -> Option<&HashSet<String>> {
+        identity.and_then( |id| id.names().iter().find_map( |name| self.callers.get( name ) ) )
+            .or_else( || self.callers.get( ANY_CALLER ) )
+    }
+}
+
+impl BorderControl for CallerBorder {
+    fn request_visa( &self, req: &ServiceRequest ) -> Result<Visa, DenialReason> {
+        let visa = self.border.request_visa( req )?;
+        let identity = CallerIdentity::of( req );
+
+        if self.allowed( identity.as_ref() ).is_some_and( |destinations| destinations.contains( &visa.rule ) ) {
+            Ok( visa )
+        } else {
+            Err( DenialReason::CallerNotAllowed {
+                caller: identity.map( |id| id.name().to_string() ).unwrap_or_else( || "anonymous".to_string() ),
+                destination: visa.rule,
+            } )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use url::Url;
+    use crate::border::host_control::Destination;
+    use super::*;
+
+    /// Grants every request a visa under the rule its `X-DESTINATION` names.
+    struct Named;
+
+    impl BorderControl for Named {
+        fn request_visa( &self, req: &ServiceRequest ) -> Result<Visa, DenialReason> {
+            let rule = req.headers().get( "x-destination" ).unwrap().to_str().unwrap();
+            let destination: Destination = Url::parse( "https://destination.example.com" ).unwrap().into();
+            Ok( destination.visa( rule ) )
+        }
+    }
+
+    fn border( callers: &[( &str, &[&str] )] ) -> CallerBorder {
+        let callers = callers.iter()
+            .map( |( caller, destinations )| ( caller.to_string(), destinations.iter().map( |d| d.to_string() ).collect() ) )
+            .collect();
+        CallerBorder::new( Box::new( Named ), callers )
+    }
+
+    fn request( destination: &str, identity: Option<CallerIdentity> ) -> ServiceRequest {
+        let req = TestRequest::with_header( "X-DESTINATION", destination ).to_srv_request();
+        if let Some(identity) = identity {
+            req.extensions_mut().insert( identity );
+        }
+        req
+    }
+
+    fn decide( border: &CallerBorder, destination: &str, identity: Option<CallerIdentity> ) -> Result<String, DenialReason> {
+        border.request_visa( &request( destination, identity ) ).map( |visa| visa.rule )
+    }
+
+    #[test]
+    fn restricts_callers_to_their_destinations() {
+        let border = border( &[ ( "billing", &[ "vendor", "payments" ] ), ( "search", &[ "index" ] ) ] );
+        assert_eq!( decide( &border, "vendor", Some( CallerIdentity::new( "billing" ) ) ).unwrap(), "vendor" );
+        assert_eq!( decide( &border, "payments", Some( CallerIdentity::new( "billing" ) ) ).unwrap(), "payments" );
+
+        match decide( &border, "index", Some( CallerIdentity::new( "billing" ) ) ) {
+            Err(DenialReason::CallerNotAllowed { caller, destination }) => {
+                assert_eq!( caller, "billing" );
+                assert_eq!( destination, "index" );
+            },
+            other => panic!( "not refused: {:?}", other ),
+        }
+    }
+
+    #[test]
+    fn knows_callers_by_the_first_listed_name() {
+        let border = border( &[ ( "billing.internal", &[ "vendor" ] ), ( "billing-old", &[ "index" ] ) ] );
+        let identity = || CallerIdentity::from_names( vec![ "CN=billing".to_string(), "billing.internal".to_string(), "billing-old".to_string() ] );
+        assert!( decide( &border, "vendor", identity() ).is_ok() );
+        assert!( decide( &border, "index", identity() ).is_err() );
+    }
+
+    #[test]
+    fn falls_back_to_any_caller() {
+        let border = border( &[ ( "billing", &[ "vendor" ] ), ( ANY_CALLER, &[ "status" ] ) ] );
+        assert!( decide( &border, "status", Some( CallerIdentity::new( "search" ) ) ).is_ok() );
+        assert!( decide( &border, "status", None ).is_ok() );
+        assert!( decide( &border, "vendor", None ).is_err() );
+        // a listed caller is not also given the destinations of any caller
+        assert!( decide( &border, "status", Some( CallerIdentity::new( "billing" ) ) ).is_err() );
+    }
+
+    #[test]
+    fn refuses_unlisted_callers_without_any_caller() {
+        let border = border( &[ ( "billing", &[ "vendor" ] ) ] );
+        match decide( &border, "vendor", None ) {
+            Err(DenialReason::CallerNotAllowed { caller, .. }) => assert_eq!( caller, "anonymous" ),
+            other => panic!( "not refused: {:?}", other ),
+        }
+        assert!( decide( &border, "vendor", Some( CallerIdentity::new( "search" ) ) ).is_err() );
+    }
+
+    #[test]
+    fn uses_the_connection_identity() {
+        let border = border( &[ ( "billing", &[ "vendor" ] ) ] );
+        let req = request( "vendor", None );
+        req.extensions_mut().insert( ConnectionIdentity( Some( CallerIdentity::new( "billing" ) ) ) );
+        assert!( border.request_visa( &req ).is_ok() );
+
+        // an identity established for the request takes the place of the connection's
+        req.extensions_mut().insert( CallerIdentity::new( "search" ) );
+        assert!( border.request_visa( &req ).is_err() );
+    }
+}
@@ -0,0 +1,385 @@
+use actix_web::dev::ServiceRequest;
+use actix_http::http::{HeaderName, Method};
+use regex::Regex;
+use super::{AsyncBorderControl, BorderControl, BorderControlBuilder};
+use super::caller::CallerIdentity;
+use super::denial::DenialReason;
+use super::visa::Visa;
+
+/// A predicate over requests used to guard border policies.
+pub trait RequestGuard: Send + Sync {
+    fn check( &self, req: &ServiceRequest ) -> bool;
+}
+
+/// Passes requests using one of the given methods.
+pub struct WhenMethod( Vec<Method> );
+
+impl WhenMethod {
+    pub fn new( methods: &[Method] ) -> Self { WhenMethod( methods.to_vec() ) }
+}
+
+impl RequestGuard for WhenMethod {
+    fn check( &self, req: &ServiceRequest ) -> bool {
+        self.0.contains( req.method() )
+    }
+}
+
+/// Passes requests carrying a header, optionally with a value matching a pattern.
+pub struct WhenHeader {
+    name: HeaderName,
+    value: Option<Regex>,
+}
+
+impl WhenHeader {
+    pub fn present( name: HeaderName ) -> Self {
+        WhenHeader { name, value: None, }
+    }
+
+    pub fn equals( name: HeaderName, value: &str ) -> Self {
+        let value = Regex::new( &format!( "^{}$", regex::escape( value ) ) ).unwrap();
+        WhenHeader { name, value: Some( value ), }
+    }
+
+    pub fn matches( name: HeaderName, value: Regex ) -> Self {
+        WhenHeader { name, value: Some( value ), }
+    }
+}
+
+impl RequestGuard for WhenHeader {
+    fn check( &self, req: &ServiceRequest ) -> bool {
+        req.headers()
+            .get_all( &self.name )
+            .any( |v| {
+                match ( &self.value, v.to_str() ) {
+                    ( None, _ ) => true,
+                    ( Some(re), Ok(v) ) => re.is_match( v ),
+                    ( Some(_), Err(_) ) => false,
+                }
+            } )
+    }
+}
+
+/// Passes requests whose caller has an attribute with one of the given values, e.g. a token
+/// scope.
+pub struct WhenCallerAttribute {
+    name: String,
+    values: Vec<String>,
+}
+
+impl WhenCallerAttribute {
+    pub fn new( name: &str, values: &[String] ) -> Self {
+        WhenCallerAttribute { name: name.to_string(), values: values.to_vec(), }
+    }
+}
+
+impl RequestGuard for WhenCallerAttribute {
+    fn check( &self, req: &ServiceRequest ) -> bool {
+        CallerIdentity::of( req ).is_some_and( |id| id.attribute( &self.name ).iter().any( |v| self.values.contains( v ) ) )
+    }
+}
+
+/// Inverts a guard.
+pub struct Not( Box<dyn RequestGuard> );
+
+impl Not {
+    pub fn new<G: RequestGuard + 'static>( guard: G ) -> Self { Not( Box::new( guard ) ) }
+}
+
+impl RequestGuard for Not {
+    fn check( &self, req: &ServiceRequest ) -> bool { !self.0.check( req ) }
+}
+
+/// Applies a border only to requests passing its guard; other requests do not match the rule.
+pub struct Guarded {
+    guard: Box<dyn RequestGuard>,
+    border: Box<dyn BorderControl>,
+}
+
+impl Guarded {
+    pub fn new<G: RequestGuard + 'static>( guard: G, border: Box<dyn BorderControl> ) -> Self {
+        Guarded { guard: Box::new( guard ), border, }
+    }
+}
+
+impl BorderControl for Guarded {
+    fn request_visa( &self, req: &ServiceRequest ) -> Result<Visa, DenialReason> {
+        if self.guard.check( req ) {
+            self.border.request_visa( req )
+        } else {
+            Err( DenialReason::NoMatchingRule )
+        }
+    }
+}
+
+/// Refuses every request with a policy denial naming the rule.
+pub struct Refuse( String );
+
+impl Refuse {
+    pub fn new( rule: &str ) -> Self { Refuse( rule.to_string() ) }
+}
+
+impl BorderControl for Refuse {
+    fn request_visa( &self, _req: &ServiceRequest ) -> Result<Visa, DenialReason> {
+        Err( DenialReason::PolicyDenied { rule: self.0.clone() } )
+    }
+}
+
+/// Grants only if every member grants. The first member's visa is issued, carrying the
+/// obligations of all members.
+pub struct AllOf( Vec<Box<dyn BorderControl>> );
+
+impl AllOf {
+    pub fn new( members: Vec<Box<dyn BorderControl>> ) -> Self { AllOf( members ) }
+}
+
+impl BorderControl for AllOf {
+    fn request_visa( &self, req: &ServiceRequest ) -> Result<Visa, DenialReason> {
+        let mut members = self.0.iter();
+        let mut visa = members.next()
+            .ok_or( DenialReason::NoMatchingRule )?
+            .request_visa( req )?;
+
+        for member in members {
+            let other = member.request_visa( req )?;
+            visa.obligations.merge( &other.obligations );
+        }
+
+        Ok( visa )
+    }
+}
+
+/// Grants the first visa any member grants. When none grants, the first denial other than a
+/// non-matching rule is reported.
+pub struct AnyOf( Vec<Box<dyn BorderControl>> );
+
+impl AnyOf {
+    pub fn new( members: Vec<Box<dyn BorderControl>> ) -> Self { AnyOf( members ) }
+}
+
+impl BorderControl for AnyOf {
+    fn request_visa( &self, req: &ServiceRequest ) -> Result<Visa, DenialReason> {
+        let mut denial = DenialReason::NoMatchingRule;
+
+        for member in self.0.iter() {
+            match member.request_visa( req ) {
+                Ok(visa) => return Ok( visa ),
+                Err(reason) => {
+                    if denial == DenialReason::NoMatchingRule {
+                        denial = reason;
+                    }
+                },
+            }
+        }
+
+        Err( denial )
+    }
+}
+
+/// Decides by the first member whose rule matches the request, whether it grants or denies.
+pub struct FirstMatch( Vec<Box<dyn BorderControl>> );
+
+impl FirstMatch {
+    pub fn new( members: Vec<Box<dyn BorderControl>> ) -> Self { FirstMatch( members ) }
+}
+
+impl BorderControl for FirstMatch {
+    fn request_visa( &self, req: &ServiceRequest ) -> Result<Visa, DenialReason> {
+        for member in self.0.iter() {
+            match member.request_visa( req ) {
+                Err(DenialReason::NoMatchingRule) => continue,
+                decision => return decision,
+            }
+        }
+
+        Err( DenialReason::NoMatchingRule )
+    }
+}
+
+enum Composition {
+    AllOf,
+    AnyOf,
+    FirstMatch,
+}
+
+/// Composes border policies in code; compositions cannot be configured in the settings file,
+/// whose destinations make up a single `HostControlBuilder` policy. E.g.:
+///
+/// ```
+/// use actix_http::http::{HeaderName, Method};
+/// use actix_web::dev::ServiceRequest;
+/// use egress_proxy::border::BorderControl;
+/// use egress_proxy::border::combinators::{Not, PolicyBuilder, Refuse, WhenHeader, WhenMethod};
+/// use egress_proxy::border::denial::DenialReason;
+/// use egress_proxy::border::host_control::Destination;
+/// use egress_proxy::border::visa::Visa;
+/// use url::Url;
+///
+/// /// Grants every request it is asked about to one destination.
+/// struct Grant( &'static str, Destination );
+///
+/// impl BorderControl for Grant {
+///     fn request_visa( &self, _req: &ServiceRequest ) -> Result<Visa, DenialReason> {
+///         Ok( self.1.visa( self.0 ) )
+///     }
+/// }
+///
+/// let ingest = Grant( "ingest", Url::parse( "https://ingest.example.com" ).unwrap().into() );
+/// let shared = Grant( "shared", Url::parse( "https://api.example.com" ).unwrap().into() );
+/// let caller = HeaderName::from_static( "x-caller" );
+///
+/// let border = PolicyBuilder::first_match()
+///     .rule( Not::new( WhenMethod::new( &[Method::GET, Method::HEAD] ) ), Box::new( Refuse::new( "read-only" ) ) )
+///     .rule( WhenHeader::equals( caller, "ingest" ), Box::new( ingest ) )
+///     .member( Box::new( shared ) )
+///     .compose();
+/// ```
+pub struct PolicyBuilder {
+    composition: Composition,
+    members: Vec<Box<dyn BorderControl>>,
+}
+
+impl PolicyBuilder {
+    pub fn all_of() -> Self { PolicyBuilder::new( Composition::AllOf ) }
+
+    pub fn any_of() -> Self { PolicyBuilder::new( Composition::AnyOf ) }
+
+    pub fn first_match() -> Self { PolicyBuilder::new( Composition::FirstMatch ) }
+
+    fn new( composition: Composition ) -> Self {
+        PolicyBuilder { composition, members: Vec::new(), }
+    }
+
+    pub fn member( mut self, border: Box<dyn BorderControl> ) -> Self {
+        self.members.push( border );
+        self
+    }
+
+    pub fn rule<G: RequestGuard + 'static>( self, guard: G, border: Box<dyn BorderControl> ) -> Self {
+        self.member( Box::new( Guarded::new( guard, border ) ) )
+    }
+}
+
+impl PolicyBuilder {
+    /// The composed policy, e.g. to be a member of another.
+    pub fn compose( self ) -> Box<dyn BorderControl> {
+        match self.composition {
+            Composition::AllOf => Box::new( AllOf( self.members ) ),
+            Composition::AnyOf => Box::new( AnyOf( self.members ) ),
+            Composition::FirstMatch => Box::new( FirstMatch( self.members ) ),
+        }
+    }
+}
+
+impl BorderControlBuilder for PolicyBuilder {
+    fn build( self ) -> Box<dyn AsyncBorderControl> {
+        Box::new( self.compose() )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_http::http::{HeaderName, HeaderValue};
+    use actix_web::test::TestRequest;
+    use url::Url;
+    use crate::border::host_control::Destination;
+    use crate::border::visa::TimeoutClass;
+    use super::*;
+
+    /// Grants every request to one destination, with its obligations.
+    struct Grant( &'static str, Destination );
+
+    impl BorderControl for Grant {
+        fn request_visa( &self, _req: &ServiceRequest ) -> Result<Visa, DenialReason> {
+            Ok( self.1.visa( self.0 ) )
+        }
+    }
+
+    fn grant( rule: &'static str ) -> Box<dyn BorderControl> {
+        Box::new( Grant( rule, Url::parse( &format!( "https://{}.example.com", rule ) ).unwrap().into() ) )
+    }
+
+    fn grant_with( rule: &'static str, dest: impl FnOnce( Destination ) -> Destination ) -> Box<dyn BorderControl> {
+        let base: Destination = Url::parse( &format!( "https://{}.example.com", rule ) ).unwrap().into();
+        Box::new( Grant( rule, dest( base ) ) )
+    }
+
+    fn refuse( rule: &str ) -> Box<dyn BorderControl> {
+        Box::new( Refuse::new( rule ) )
+    }
+
+    fn unmatched() -> Box<dyn BorderControl> {
+        Box::new( Guarded::new( WhenMethod::new( &[ Method::DELETE ] ), grant( "never" ) ) )
+    }
+
+    fn decide( border: Box<dyn BorderControl> ) -> Result<String, DenialReason> {
+        border.request_visa( &TestRequest::default().to_srv_request() ).map( |visa| visa.rule )
+    }
+
+    fn denied( rule: &str ) -> Result<String, DenialReason> {
+        Err( DenialReason::PolicyDenied { rule: rule.to_string() } )
+    }
+
+    #[test]
+    fn any_of_grants_the_first_visa() {
+        let border = PolicyBuilder::any_of().member( refuse( "a" ) ).member( grant( "b" ) ).member( grant( "c" ) ).compose();
+        assert_eq!( decide( border ), Ok( "b".to_string() ) );
+    }
+
+    #[test]
+    fn any_of_reports_the_first_denial_of_a_matching_rule() {
+        let border = PolicyBuilder::any_of().member( unmatched() ).member( refuse( "a" ) ).member( refuse( "b" ) ).compose();
+        assert_eq!( decide( border ), denied( "a" ) );
+
+        let border = PolicyBuilder::any_of().member( unmatched() ).member( unmatched() ).compose();
+        assert_eq!( decide( border ), Err( DenialReason::NoMatchingRule ) );
+    }
+
+    #[test]
+    fn all_of_merges_obligations_into_the_first_visa() {
+        let border = PolicyBuilder::all_of()
+            .member( grant_with( "a", |d| d.with_header( HeaderName::from_static( "x-a" ), HeaderValue::from_static( "1" ) ) ) )
+            .member( grant_with( "b", |d| {
+                d.with_header( HeaderName::from_static( "x-b" ), HeaderValue::from_static( "2" ) )
+                    .with_timeout( TimeoutClass::Long )
+                    .with_quota( "b" )
+            } ) )
+            .compose();
+
+        let visa = border.request_visa( &TestRequest::default().to_srv_request() ).unwrap();
+        assert_eq!( visa.rule, "a" );
+        assert_eq!( visa.destination.to_string(), "a.example.com:443" );
+        let headers = visa.obligations.add_headers.iter().map( |( name, _ )| name.as_str() ).collect::<Vec<_>>();
+        assert_eq!( headers, vec![ "x-a", "x-b" ] );
+        assert_eq!( visa.obligations.timeout, TimeoutClass::Long );
+        assert_eq!( visa.obligations.quota.as_deref(), Some( "b" ) );
+    }
+
+    #[test]
+    fn all_of_refuses_when_any_member_refuses() {
+        let border = PolicyBuilder::all_of().member( grant( "a" ) ).member( refuse( "b" ) ).member( refuse( "c" ) ).compose();
+        assert_eq!( decide( border ), denied( "b" ) );
+        assert_eq!( decide( PolicyBuilder::all_of().compose() ), Err( DenialReason::NoMatchingRule ) );
+    }
+
+    #[test]
+    fn first_match_decides_by_the_first_matching_rule() {
+        let border = PolicyBuilder::first_match().member( unmatched() ).member( refuse( "a" ) ).member( grant( "b" ) ).compose();
+        assert_eq!( decide( border ), denied( "a" ) );
+
+        let border = PolicyBuilder::first_match().member( unmatched() ).compose();
+        assert_eq!( decide( border ), Err( DenialReason::NoMatchingRule ) );
+    }
+
+    #[test]
+    fn guards_check_methods_headers_and_their_inverse() {
+        let req = TestRequest::with_header( "x-caller", "ingest-7" ).method( Method::POST ).to_srv_request();
+        let caller = || HeaderName::from_static( "x-caller" );
+
+        assert!( WhenMethod::new( &[ Method::GET, Method::POST ] ).check( &req ) );
+        assert!( Not::new( WhenMethod::new( &[ Method::GET ] ) ).check( &req ) );
+        assert!( WhenHeader::present( caller() ).check( &req ) );
+        assert!( WhenHeader::matches( caller(), Regex::new( "^ingest-[0-9]+$" ).unwrap() ).check( &req ) );
+        assert!( !WhenHeader::equals( caller(), "ingest" ).check( &req ) );
+        assert!( !WhenHeader::present( HeaderName::from_static( "x-other" ) ).check( &req ) );
+    }
+}
@@ -0,0 +1,167 @@
+use std::fmt;
+use std::time::Duration;
+use actix_web::{HttpResponse, ResponseError};
+use actix_http::http::{header, StatusCode};
+use serde_json::json;
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// Why `BorderControl` refused to grant a visa for a request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DenialReason {
+    UnknownDestination( String ),
+    NoDefaultDestination,
+    UnresolvableDestination( String ),
+    ForbiddenAddress { destination: String, address: String },
+    ClosedBorder,
+    NoMatchingRule,
+    PolicyDenied { rule: String },
+    PolicyUnavailable,
+    MethodNotAllowed { method: String, destination: String },
+    PathNotAllowed { path: String, destination: String },
+    InvalidPath( String ),
+    RateLimited { retry_after: Duration },
+    QuotaExceeded { quota: String },
+    CallerNotAllowed { caller: String, destination: String },
+    CallerNotEntitled { caller: String, destination: String, attribute: String },
+    ProxyAuthenticationRequired { challenges: Vec<String> },
+    SourceNotAllowed { source: String, destination: Option<String> },
+    PortNotAllowed { port: u16, destination: String },
+}
+
+impl DenialReason {
+    /// Machine-readable code used in problem responses and the `reason` metric label.
+    pub fn code( &self ) -> &'static str {
+        match self {
+            DenialReason::UnknownDestination(_) => "unknown_destination",
+            DenialReason::NoDefaultDestination => "no_default_destination",
+            DenialReason::UnresolvableDestination(_) => "unresolvable_destination",
+            DenialReason::ForbiddenAddress { .. } => "forbidden_address",
+            DenialReason::ClosedBorder => "closed_border",
+            DenialReason::NoMatchingRule => "no_matching_rule",
+            DenialReason::PolicyDenied { .. } => "policy_denied",
+            DenialReason::PolicyUnavailable => "policy_unavailable",
+            DenialReason::MethodNotAllowed { .. } => "method_not_allowed",
+            DenialReason::PathNotAllowed { .. } => "path_not_allowed",
+            DenialReason::InvalidPath(_) => "invalid_path",
+            DenialReason::RateLimited { .. } => "rate_limited",
+            DenialReason::QuotaExceeded { .. } => "quota_exceeded",
+            DenialReason::CallerNotAllowed { .. } => "caller_not_allowed",
+            DenialReason::CallerNotEntitled { .. } => "caller_not_entitled",
+            DenialReason::ProxyAuthenticationRequired { .. } => "proxy_authentication_required",
+            DenialReason::SourceNotAllowed { .. } => "source_not_allowed",
+            DenialReason::PortNotAllowed { .. } => "port_not_allowed",
+        }
+    }
+
+    /// The named destination the denial concerns, when one was identified.
+    pub fn destination( &self ) -> Option<&str> {
+        match self {
+            DenialReason::MethodNotAllowed { destination, .. } => Some( destination ),
+            DenialReason::PathNotAllowed { destination, .. } => Some( destination ),
+            DenialReason::CallerNotAllowed { destination, .. } => Some( destination ),
+            DenialReason::CallerNotEntitled { destination, .. } => Some( destination ),
+            DenialReason::SourceNotAllowed { destination, .. } => destination.as_deref(),
+            DenialReason::PortNotAllowed { destination, .. } => Some( destination ),
+            _ => None,
+        }
+    }
+
+    pub fn status( &self ) -> StatusCode {
+        match self {
+            DenialReason::UnknownDestination(_) => StatusCode::NOT_FOUND,
+            DenialReason::NoDefaultDestination => StatusCode::NOT_FOUND,
+            DenialReason::UnresolvableDestination(_) => StatusCode::BAD_GATEWAY,
+            DenialReason::ForbiddenAddress { .. } => StatusCode::FORBIDDEN,
+            DenialReason::ClosedBorder => StatusCode::FORBIDDEN,
+            DenialReason::NoMatchingRule => StatusCode::FORBIDDEN,
+            DenialReason::PolicyDenied { .. } => StatusCode::FORBIDDEN,
+            DenialReason::PolicyUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            DenialReason::MethodNotAllowed { .. } => StatusCode::METHOD_NOT_ALLOWED,
+            DenialReason::PathNotAllowed { .. } => StatusCode::FORBIDDEN,
+            DenialReason::InvalidPath(_) => StatusCode::BAD_REQUEST,
+            DenialReason::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            DenialReason::QuotaExceeded { .. } => StatusCode::TOO_MANY_REQUESTS,
+            DenialReason::CallerNotAllowed { .. } => StatusCode::FORBIDDEN,
+            DenialReason::CallerNotEntitled { .. } => StatusCode::FORBIDDEN,
+            DenialReason::ProxyAuthenticationRequired { .. } => StatusCode::PROXY_AUTHENTICATION_REQUIRED,
+            DenialReason::SourceNotAllowed { .. } => StatusCode::FORBIDDEN,
+            DenialReason::PortNotAllowed { .. } => StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+impl fmt::Display for DenialReason {
+    fn fmt( &self, f: &mut fmt::Formatter ) -> fmt::Result {
+        match self {
+            DenialReason::UnknownDestination(d) => write!( f, "no egress destination identified for {}", d ),
+            DenialReason::NoDefaultDestination => write!( f, "no default egress destination for request" ),
+            DenialReason::UnresolvableDestination(d) => write!( f, "egress destination {} could not be resolved", d ),
+            DenialReason::ForbiddenAddress { destination, address } => {
+                write!( f, "egress destination {} resolves to forbidden address {}", destination, address )
+            },
+            DenialReason::ClosedBorder => write!( f, "closed egress proxy. no destinations allowed" ),
+            DenialReason::NoMatchingRule => write!( f, "no egress policy rule matched the request" ),
+            DenialReason::PolicyDenied { rule } => write!( f, "egress denied by policy rule {}", rule ),
+            DenialReason::PolicyUnavailable => write!( f, "egress policy decision unavailable" ),
+            DenialReason::MethodNotAllowed { method, destination } => {
+                write!( f, "method {} not allowed to egress destination {}", method, destination )
+            },
+            DenialReason::PathNotAllowed { path, destination } => {
+                write!( f, "path {} not allowed to egress destination {}", path, destination )
+            },
+            DenialReason::InvalidPath(path) => write!( f, "path {} has dot segments or encoded separators", path ),
+            DenialReason::RateLimited { retry_after } => {
+                write!( f, "egress rate limit exceeded. retry after {}s", retry_after.as_secs() )
+            },
+            DenialReason::QuotaExceeded { quota } => write!( f, "egress quota {} exceeded", quota ),
+            DenialReason::CallerNotAllowed { caller, destination } => {
+                write!( f, "caller {} not allowed to egress destination {}", caller, destination )
+            },
+            DenialReason::CallerNotEntitled { caller, destination, attribute } => {
+                write!( f, "caller {} lacks the {} required by egress destination {}", caller, attribute, destination )
+            },
+            DenialReason::ProxyAuthenticationRequired { .. } => write!( f, "valid proxy credentials required" ),
+            DenialReason::SourceNotAllowed { source, destination: None } => {
+                write!( f, "egress not allowed from source address {}", source )
+            },
+            DenialReason::SourceNotAllowed { source, destination: Some(destination) } => {
+                write!( f, "source address {} not allowed to egress destination {}", source, destination )
+            },
+            DenialReason::PortNotAllowed { port, destination } => {
+                write!( f, "port {} not allowed to egress destination {}", port, destination )
+            },
+        }
+    }
+}
+
+impl ResponseError for DenialReason {
+    fn error_response( &self ) -> HttpResponse {
+        let mut resp = HttpResponse::build( self.status() );
+
+        match self {
+            DenialReason::RateLimited { retry_after } => {
+                resp.header( header::RETRY_AFTER, retry_after.as_secs().to_string() );
+            },
+            DenialReason::ProxyAuthenticationRequired { challenges } => {
+                for challenge in challenges.iter() {
+                    resp.header( header::PROXY_AUTHENTICATE, challenge.as_str() );
+                }
+            },
+            _ => {},
+        }
+
+        resp.content_type( PROBLEM_JSON )
+            .body(
+                json!( {
+                    "type": format!( "urn:egress-proxy:denial:{}", self.code() ),
+                    "title": self.status().canonical_reason().unwrap_or( "Denied" ),
+                    "status": self.status().as_u16(),
+                    "detail": self.to_string(),
+                    "code": self.code(),
+                } ).to_string()
+            )
+    }
+
+    fn render_response( &self ) -> HttpResponse { self.error_response() }
+}
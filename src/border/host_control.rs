@@ -1,30 +1,206 @@
 //use std::marker::PhantomData;
 //use futures::future::{ ok, FutureResult };
-use std::collections::HashMap;
-use log::info;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use ipnet::IpNet;
+use log::{error, info};
 use url::{Host, HostAndPort, ParseError, Url};
 use super::BorderControl;
 use actix_web::{Error, HttpRequest};
 use actix_web::dev::ServiceRequest;
-use crate::border::{BorderControlBuilder, ClosedBorder};
-use actix_http::error::{ErrorForbidden, ErrorNotImplemented, ErrorNotFound};
-use actix_http::http::HeaderValue;
+use crate::border::{AsyncBorderControl, BorderControlBuilder, ClosedBorder};
+use crate::border::address::{AddressPolicy, ResolvedAddressBorder};
+use crate::border::caller::{CallerBorder, CallerIdentity};
+use crate::border::denial::DenialReason;
+use crate::border::source::{self, ClientAddress, SourceBorder, SourcePolicy};
+use crate::border::matcher::DestinationMatcher;
+use crate::border::route::{check_routes, decoded_path, RouteCheck, RouteRule};
+use crate::border::selector::{DestinationSelector, Selection};
+use crate::border::visa::{Obligations, TimeoutClass, Visa};
+use actix_http::http::{header, HeaderName, HeaderValue};
+use actix_http::http::uri::Authority;
+use crate::config::PROTOCOL;
 
-static DEFAULT: &str = "__default__";
-type DestinationMap = HashMap<String, HostAndPort>;
+pub static DEFAULT: &str = "__default__";
+type DestinationMap = HashMap<String, Destination>;
 
-#[derive(Clone)]
-pub struct Destination( HostAndPort );
+/// An egress destination a policy may grant, along with the obligations attached to requests
+/// sent to it.
+#[derive(Clone, Debug)]
+pub struct Destination {
+    scheme: String,
+    host: HostAndPort,
+    base_path: String,
+    obligations: Obligations,
+    routes: Vec<RouteRule>,
+    tls: Option<String>,
+    required_attributes: BTreeMap<String, Vec<String>>,
+    allowed_sources: Vec<IpNet>,
+}
+
+impl Destination {
+    pub fn host_and_port( &self ) -> &HostAndPort { &self.host }
+
+    pub fn with_host( mut self, host: Host<String> ) -> Self {
+        self.host.host = host;
+        self
+    }
+
+    pub fn with_scheme( mut self, scheme: &str ) -> Self {
+        self.scheme = scheme.to_string();
+        self
+    }
+
+    pub fn with_base_path( mut self, base_path: &str ) -> Self {
+        self.base_path = base_path.trim_end_matches( '/' ).to_string();
+        self
+    }
+
+    pub fn with_header( mut self, name: HeaderName, value: HeaderValue ) -> Self {
+        self.obligations.add_headers.push( (name, value) );
+        self
+    }
+
+    pub fn strip_header( mut self, name: HeaderName ) -> Self {
+        self.obligations.strip_headers.push( name );
+        self
+    }
+
+    pub fn with_timeout( mut self, timeout: TimeoutClass ) -> Self {
+        self.obligations.timeout = timeout;
+        self
+    }
+
+    pub fn with_rate_limit_bucket( mut self, bucket: &str ) -> Self {
+        self.obligations.rate_limit_bucket = Some( bucket.to_string() );
+        self
+    }
+
+    pub fn with_quota( mut self, quota: &str ) -> Self {
+        self.obligations.quota = Some( quota.to_string() );
+        self
+    }
+
+    /// Connects to this destination with the named TLS profile rather than the default.
+    pub fn with_tls_profile( mut self, profile: &str ) -> Self {
+        self.tls = Some( profile.to_string() );
+        self
+    }
+
+    /// Restricts requests to this destination to those matching one of its routes. A
+    /// destination without routes admits every method and path.
+    pub fn with_route( mut self, route: RouteRule ) -> Self {
+        self.routes.push( route );
+        self
+    }
+
+    /// Admits only callers with the named attribute set to one of `values`, e.g. a token scope.
+    pub fn with_required_attribute( mut self, name: &str, values: &[String] ) -> Self {
+        self.required_attributes.entry( name.to_string() ).or_default().extend( values.iter().cloned() );
+        self
+    }
+
+    /// Admits only callers whose client address falls in one of the allowed ranges.
+    pub fn with_allowed_source( mut self, net: IpNet ) -> Self {
+        self.allowed_sources.push( net );
+        self
+    }
+
+    /// Checks the request's source and caller against this destination's allowed sources and
+    /// required attributes, then the request against its routes, before issuing its visa.
+    pub fn admit( &self, rule: &str, req: &ServiceRequest ) -> Result<Visa, DenialReason> {
+        self.admit_path( rule, req, req.path() )
+    }
+
+    /// Admits the request as `admit` does, checking `path` against the routes in place of the
+    /// request's own path, e.g. the path it is forwarded with. The path is forwarded as it is,
+    /// so paths the upstream URL would resolve to another path are refused.
+    pub fn admit_path( &self, rule: &str, req: &ServiceRequest, path: &str ) -> Result<Visa, DenialReason> {
+        self.check_source( rule, req )?;
+        self.check_caller( rule, req )?;
+
+        let decoded = decoded_path( path ).ok_or_else( || DenialReason::InvalidPath( path.to_string() ) )?;
+        match check_routes( &self.routes, req.method(), &decoded ) {
+            RouteCheck::Allowed => Ok( self.visa( rule ) ),
+
+            RouteCheck::PathNotAllowed => Err(
+                DenialReason::PathNotAllowed { path: path.to_string(), destination: rule.to_string() }
+            ),
+
+            RouteCheck::MethodNotAllowed => Err(
+                DenialReason::MethodNotAllowed { method: req.method().to_string(), destination: rule.to_string() }
+            ),
+        }
+    }
+
+    fn check_source( &self, rule: &str, req: &ServiceRequest ) -> Result<(), DenialReason> {
+        if self.allowed_sources.is_empty() {
+            return Ok( () );
+        }
+
+        match ClientAddress::of( req ) {
+            Some(ref addr) if source::admits( &self.allowed_sources, addr ) => Ok( () ),
+            client => Err( DenialReason::SourceNotAllowed {
+                source: client.map( |a| a.to_string() ).unwrap_or_else( || "unknown".to_string() ),
+                destination: Some( rule.to_string() ),
+            } ),
+        }
+    }
+
+    fn check_caller( &self, rule: &str, req: &ServiceRequest ) -> Result<(), DenialReason> {
+        if self.required_attributes.is_empty() {
+            return Ok( () );
+        }
+
+        let identity = CallerIdentity::of( req );
+        let missing = self.required_attributes.iter().find( |( name, values )| {
+            !identity.as_ref().is_some_and( |id| id.attribute( name ).iter().any( |v| values.contains( v ) ) )
+        } );
+
+        match missing {
+            None => Ok( () ),
+            Some(( attribute, _ )) => Err( DenialReason::CallerNotEntitled {
+                caller: identity.map( |id| id.name().to_string() ).unwrap_or_else( || "anonymous".to_string() ),
+                destination: rule.to_string(),
+                attribute: attribute.clone(),
+            } ),
+        }
+    }
+
+    /// Issues a visa to this destination under the named rule.
+    pub fn visa( &self, rule: &str ) -> Visa {
+        Visa {
+            rule: rule.to_string(),
+            scheme: self.scheme.clone(),
+            destination: self.host.clone(),
+            base_path: self.base_path.clone(),
+            obligations: self.obligations.clone(),
+            resolved: None,
+            tls: self.tls.clone(),
+            path_prefix: String::new(),
+            selector_parameter: None,
+        }
+    }
+}
 
 impl From<HostAndPort> for Destination {
     fn from( hp: HostAndPort ) -> Self {
-        Destination( hp )
+        Destination {
+            scheme: PROTOCOL.to_string(),
+            host: hp,
+            base_path: String::new(),
+            obligations: Obligations::default(),
+            routes: Vec::new(),
+            tls: None,
+            required_attributes: BTreeMap::new(),
+            allowed_sources: Vec::new(),
+        }
     }
 }
 
 impl From<Url> for Destination {
     fn from( url: Url ) -> Self {
-        ( url.host().unwrap().to_owned(), url.port().unwrap_or( 80 ) ).into()
+        let dest: Destination = ( url.host().unwrap().to_owned(), url.port_or_known_default().unwrap_or( 80 ) ).into();
+        dest.with_scheme( url.scheme() ).with_base_path( url.path() )
     }
 }
 
@@ -34,13 +210,27 @@ impl From<(Host<String>, u16)> for Destination {
     }
 }
 
+/// Builds the border policy for a set of destinations. Granted destinations are checked against
+/// an `AddressPolicy` after DNS resolution, which by default denies internal address ranges.
 pub struct HostControlBuilder {
     destinations: DestinationMap,
+    address_policy: AddressPolicy,
+    source_policy: SourcePolicy,
+    callers: HashMap<String, HashSet<String>>,
+    selectors: Vec<DestinationSelector>,
+    resolve: bool,
 }
 
 impl Default for HostControlBuilder {
     fn default() -> Self {
-        HostControlBuilder { destinations: HashMap::new(), }
+        HostControlBuilder {
+            destinations: DestinationMap::new(),
+            address_policy: AddressPolicy::default(),
+            source_policy: SourcePolicy::default(),
+            callers: HashMap::new(),
+            selectors: Vec::new(),
+            resolve: true,
+        }
     }
 }
 
@@ -50,12 +240,45 @@ impl HostControlBuilder {
     }
 
     pub fn with_default_destination<D: Into<Destination>>( mut self, dest: D ) -> Self {
-        self.destinations.insert( DEFAULT.to_string(), dest.into().0 );
+        self.destinations.insert( DEFAULT.to_string(), dest.into() );
         self
     }
 
     pub fn with_named_destination<D: Into<Destination>>( mut self, name: &str, dest: D ) -> Self {
-        self.destinations.insert( name.to_string(), dest.into().0 );
+        self.destinations.insert( name.to_string(), dest.into() );
+        self
+    }
+
+    pub fn with_address_policy( mut self, policy: AddressPolicy ) -> Self {
+        self.address_policy = policy;
+        self
+    }
+
+    /// Checks where callers connect from before any destination is considered.
+    pub fn with_source_policy( mut self, policy: SourcePolicy ) -> Self {
+        self.source_policy = policy;
+        self
+    }
+
+    /// Allows the named caller (or `ANY_CALLER`) the named destinations, `DEFAULT` for the
+    /// default destination. Once any caller is listed, callers may only use the destinations
+    /// listed for them.
+    pub fn with_caller_destinations( mut self, caller: &str, destinations: &[String] ) -> Self {
+        self.callers.entry( caller.to_string() ).or_default().extend( destinations.iter().cloned() );
+        self
+    }
+
+    /// Adds a way for requests to name their destination, tried in the order added; without
+    /// any, requests name it with the `X-DESTINATION` header.
+    pub fn with_destination_selector( mut self, selector: DestinationSelector ) -> Self {
+        self.selectors.push( selector );
+        self
+    }
+
+    /// Skips resolving granted destinations and checking them against the address policy, e.g.
+    /// to evaluate a policy offline.
+    pub fn without_address_resolution( mut self ) -> Self {
+        self.resolve = false;
         self
     }
 
@@ -67,15 +290,35 @@ impl HostControlBuilder {
 }
 
 impl BorderControlBuilder for HostControlBuilder {
-    fn build( self ) -> Box<dyn BorderControl> {
-        if self.is_closed() == true {
-            Box::new( ClosedBorder::new() )
-        } else if self.has_only_default() {
+    fn build( self ) -> Box<dyn AsyncBorderControl> {
+        if self.is_closed() {
+            return Box::new( ClosedBorder::new() );
+        }
+
+        let border: Box<dyn BorderControl> = if self.has_only_default() {
             Box::new(
                 SingleHostBorder::new( self.destinations.get(DEFAULT).unwrap().clone() )
             )
         } else {
-            Box::new( ManyHostsBorder::new( self.destinations ) )
+            Box::new( ManyHostsBorder::new( self.destinations, self.selectors ) )
+        };
+
+        let border: Box<dyn BorderControl> = if self.callers.is_empty() {
+            border
+        } else {
+            Box::new( CallerBorder::new( border, self.callers ) )
+        };
+
+        let border: Box<dyn BorderControl> = if self.source_policy.is_empty() {
+            border
+        } else {
+            Box::new( SourceBorder::new( border, self.source_policy ) )
+        };
+
+        if self.resolve {
+            Box::new( ResolvedAddressBorder::new( border, self.address_policy ) )
+        } else {
+            Box::new( border )
         }
     }
 }
@@ -97,66 +340,231 @@ impl SingleHostBorder {
 }
 
 impl BorderControl for SingleHostBorder {
-    fn request_visa(&self, req: &ServiceRequest ) -> Result<&HostAndPort, Error> {
-        Ok( &self.destination.0 )
+    fn request_visa(&self, req: &ServiceRequest ) -> Result<Visa, DenialReason> {
+        self.destination.admit( DEFAULT, req )
     }
 }
 
+/// Selects among named destinations by the first of its selectors yielding a key, the
+/// `X-DESTINATION` header unless configured otherwise, else by the host of an absolute-form
+/// request URI or the `Host` header, as a standard forward proxy would. Only a first path
+/// segment naming no destination is passed over; other unknown keys are refused.
+/// Destination names may be exact hosts, `*.example.com` suffix wildcards or `~`-prefixed
+/// regular expressions; a request matched by a wildcard or regex is forwarded to the host it
+/// named, under that destination's scheme, port and obligations.
 #[derive(Clone)]
 struct ManyHostsBorder {
-    destinations: DestinationMap,
-    has_default: bool,
+    destinations: DestinationMatcher<(String, Destination)>,
+    default: Option<Destination>,
+    selectors: Vec<DestinationSelector>,
 }
 
-static HDR_X_DESTINATION: &str = "X-DESTINATION";
+static HDR_X_DESTINATION: &str = "x-destination";
 
 impl ManyHostsBorder {
-    fn new( destinations: DestinationMap ) -> Self {
-        let has_default = destinations.contains_key( DEFAULT );
-        ManyHostsBorder { destinations, has_default, }
+    fn new( mut destinations: DestinationMap, mut selectors: Vec<DestinationSelector> ) -> Self {
+        let default = destinations.remove( DEFAULT );
+
+        let mut matcher = DestinationMatcher::new();
+        for ( name, dest ) in destinations {
+            if let Err(err) = matcher.insert( &name, (name.clone(), dest) ) {
+                error!( "ignoring egress destination {} with invalid pattern: {}", name, err );
+            }
+        }
+
+        if selectors.is_empty() {
+            selectors.push( DestinationSelector::Header( HeaderName::from_static( HDR_X_DESTINATION ) ) );
+        }
+
+        ManyHostsBorder { destinations: matcher, default, selectors, }
     }
 
-    fn destination_for( &self, key: &str ) -> Result<&HostAndPort, Error> {
-        self.destinations
-            .get( key )
-            .ok_or_else( || {
-                ErrorNotFound( format!( "no egress destination identified for {}", key ) )
-            } )
+    fn destination_for( &self, selection: &Selection, req: &ServiceRequest ) -> Result<Visa, DenialReason> {
+        let key = selection.key.as_str();
+        let unknown = || DenialReason::UnknownDestination( key.to_string() );
+        let path = req.path().get( selection.path_prefix.len().. ).unwrap_or( "" );
+
+        let visa = match self.destinations.find( key ) {
+            Some( ((name, dest), true) ) => dest.admit_path( name, req, path ),
+
+            Some( ((name, dest), false) ) => {
+                Host::parse( key )
+                    .map_err( |_| unknown() )
+                    .and_then( |host| dest.clone().with_host( host ).admit_path( name, req, path ) )
+            },
+
+            None => Err( unknown() ),
+        };
+
+        visa.map( |visa| Visa {
+            path_prefix: selection.path_prefix.clone(),
+            selector_parameter: selection.query_parameter.clone(),
+            ..visa
+        } )
     }
+}
 
-    fn identify_destination( &self, req: &ServiceRequest ) -> Result<&HostAndPort, Error> {
-        match req.headers().get( "X-DESTINATION" ) {
-            Some(d) => self.destination_for( d.to_str().unwrap() ),
+impl BorderControl for ManyHostsBorder {
+    fn request_visa(&self, req: &ServiceRequest ) -> Result<Visa, DenialReason> {
+        // a first path segment naming no destination may just be part of the path, so it leaves
+        // the request to the next selector and then to the host it names; any other key is
+        // meant as a destination and refused if it names none
+        let mut unknown = None;
+        for selector in self.selectors.iter() {
+            let selection = match selector.select( req ) {
+                Some(selection) => selection,
+                None => continue,
+            };
+            if *selector == DestinationSelector::PathSegment && self.destinations.find( &selection.key ).is_none() {
+                unknown = unknown.or( Some( selection.key ) );
+                continue;
+            }
+            return self.destination_for( &selection, req );
+        }
 
-            None => {
-                self.destination_for( DEFAULT )
-                    .map_err( |_| {
-                        ErrorNotFound( format!("no default egress destination for request {:?}", req.uri() ) )
-                    } )
+        // absolute-form, as sent by clients using the egress proxy as their HTTP proxy
+        if let Some(authority) = req.uri().authority_part() {
+            let visa = self.destination_for( &Selection::named( authority.host() ), req )?;
+            return admit_port( authority.port_u16(), visa );
+        }
+
+        // a Host naming no destination is the proxy's own, leaving the request to the default
+        if let Some(authority) = host_header( req ) {
+            if ( self.default.is_none() && unknown.is_none() ) || self.destinations.find( authority.host() ).is_some() {
+                let visa = self.destination_for( &Selection::named( authority.host() ), req )?;
+                return admit_port( authority.port_u16(), visa );
             }
+        }
 
-//            Some(d) => Ok( d.to_str().unwrap() ),
-//
-//            None if self.has_default => Ok( DEFAULT ),
-//
-//            None => Err(
-//                ErrorNotFound(format!("no egress destination found for request {:?}", req.uri()))
-//            ),
+        match self.default {
+            Some(ref default) => default.admit( DEFAULT, req ),
+            None => Err( unknown.map( DenialReason::UnknownDestination ).unwrap_or( DenialReason::NoDefaultDestination ) ),
         }
     }
 }
 
-impl BorderControl for ManyHostsBorder {
-    fn request_visa(&self, req: &ServiceRequest ) -> Result<&HostAndPort, Error> {
-        match req.headers().get( "X-DESTINATION" ) {
-            Some(d) => self.destination_for(d.to_str().unwrap()),
-
-            None => {
-                self.destination_for(DEFAULT)
-                    .map_err(|_| {
-                        ErrorNotFound(format!("no default egress destination for request {:?}", req.uri()))
-                    } )
-            }
+fn host_header( req: &ServiceRequest ) -> Option<Authority> {
+    req.headers().get( header::HOST )?.to_str().ok()?.parse::<Authority>().ok()
+}
+
+/// Refuses a visa for a request naming a port other than its destination's.
+fn admit_port( port: Option<u16>, visa: Visa ) -> Result<Visa, DenialReason> {
+    match port {
+        Some(port) if port != visa.destination.port => Err( DenialReason::PortNotAllowed { port, destination: visa.rule } ),
+        _ => Ok( visa ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use super::*;
+
+    fn destination( url: &str ) -> Destination {
+        Url::parse( url ).unwrap().into()
+    }
+
+    fn border( default: Option<&str>, selectors: Vec<DestinationSelector> ) -> ManyHostsBorder {
+        let mut destinations = DestinationMap::new();
+        destinations.insert( "vendor.example.com".to_string(), destination( "http://vendor.example.com:8081" ) );
+        destinations.insert( "*.partner.com".to_string(), destination( "https://partner.com" ) );
+        if let Some(default) = default {
+            destinations.insert( DEFAULT.to_string(), destination( default ) );
+        }
+        ManyHostsBorder::new( destinations, selectors )
+    }
+
+    fn decide( border: &ManyHostsBorder, req: TestRequest ) -> Result<String, DenialReason> {
+        border.request_visa( &req.to_srv_request() ).map( |visa| format!( "{} {}", visa.rule, visa.destination ) )
+    }
+
+    fn code( decision: Result<String, DenialReason> ) -> &'static str {
+        decision.unwrap_err().code()
+    }
+
+    #[test]
+    fn selects_by_absolute_form_uris() {
+        let border = border( Some( "https://default.example.com" ), vec![] );
+        let absolute = |uri: &str| TestRequest::with_uri( uri );
+
+        assert_eq!( decide( &border, absolute( "http://vendor.example.com:8081/v1" ) ).unwrap(), "vendor.example.com vendor.example.com:8081" );
+        assert_eq!( decide( &border, absolute( "http://vendor.example.com/v1" ) ).unwrap(), "vendor.example.com vendor.example.com:8081" );
+        assert_eq!( decide( &border, absolute( "https://api.partner.com/v1" ) ).unwrap(), "*.partner.com api.partner.com:443" );
+        // the default is only for requests naming no host
+        assert_eq!( code( decide( &border, absolute( "http://other.example.com/" ) ) ), "unknown_destination" );
+    }
+
+    #[test]
+    fn refuses_mismatched_ports() {
+        let border = border( Some( "https://default.example.com" ), vec![] );
+        assert_eq!( code( decide( &border, TestRequest::with_uri( "http://vendor.example.com:9999/" ) ) ), "port_not_allowed" );
+        assert_eq!( code( decide( &border, TestRequest::with_header( "Host", "vendor.example.com:9999" ) ) ), "port_not_allowed" );
+        assert_eq!( code( decide( &border, TestRequest::with_header( "Host", "api.partner.com:8443" ) ) ), "port_not_allowed" );
+        assert!( decide( &border, TestRequest::with_header( "Host", "vendor.example.com:8081" ) ).is_ok() );
+        assert!( decide( &border, TestRequest::with_header( "Host", "vendor.example.com" ) ).is_ok() );
+    }
+
+    #[test]
+    fn falls_back_to_the_default_for_the_proxys_own_host() {
+        let border = border( Some( "https://default.example.com" ), vec![] );
+        assert_eq!( decide( &border, TestRequest::with_header( "Host", "egress.internal:8010" ) ).unwrap(), "__default__ default.example.com:443" );
+        assert_eq!( decide( &border, TestRequest::default() ).unwrap(), "__default__ default.example.com:443" );
+        assert_eq!( decide( &border, TestRequest::with_header( "Host", "vendor.example.com" ) ).unwrap(), "vendor.example.com vendor.example.com:8081" );
+
+        let border = self::border( None, vec![] );
+        assert_eq!( code( decide( &border, TestRequest::with_header( "Host", "egress.internal:8010" ) ) ), "unknown_destination" );
+        assert_eq!( code( decide( &border, TestRequest::default() ) ), "no_default_destination" );
+    }
+
+    fn selectors() -> Vec<DestinationSelector> {
+        vec![
+            DestinationSelector::Header( HeaderName::from_static( "x-egress-target" ) ),
+            DestinationSelector::PathSegment,
+            DestinationSelector::QueryParameter( "egress".to_string() ),
+        ]
+    }
+
+    #[test]
+    fn selects_by_the_first_selector_yielding_a_key() {
+        let border = border( Some( "https://default.example.com" ), selectors() );
+        let req = || TestRequest::with_uri( "/vendor.example.com/v1?egress=api.partner.com" );
+
+        assert_eq!( decide( &border, req().header( "X-Egress-Target", "api.partner.com" ) ).unwrap(), "*.partner.com api.partner.com:443" );
+        assert_eq!( decide( &border, req() ).unwrap(), "vendor.example.com vendor.example.com:8081" );
+        assert_eq!( decide( &border, TestRequest::with_uri( "/v1?egress=api.partner.com" ) ).unwrap(), "*.partner.com api.partner.com:443" );
+
+        let visa = border.request_visa( &req().to_srv_request() ).unwrap();
+        assert_eq!( visa.path_prefix, "/vendor.example.com" );
+    }
+
+    #[test]
+    fn refuses_unknown_keys_other_than_path_segments() {
+        let border = border( Some( "https://default.example.com" ), selectors() );
+
+        match decide( &border, TestRequest::with_header( "X-Egress-Target", "other.example.com" ).uri( "/vendor.example.com/v1" ) ) {
+            Err(DenialReason::UnknownDestination(key)) => assert_eq!( key, "other.example.com" ),
+            other => panic!( "not refused: {:?}", other ),
+        }
+        assert_eq!( code( decide( &border, TestRequest::with_uri( "/v1?egress=other.example.com" ) ) ), "unknown_destination" );
+
+        let border = self::border( Some( "https://default.example.com" ), vec![ DestinationSelector::Subdomain( "egress.internal".to_string() ) ] );
+        assert_eq!( code( decide( &border, TestRequest::with_header( "Host", "other.egress.internal" ) ) ), "unknown_destination" );
+        assert_eq!( decide( &border, TestRequest::with_header( "Host", "egress.internal" ) ).unwrap(), "__default__ default.example.com:443" );
+    }
+
+    #[test]
+    fn passes_over_path_segments_naming_no_destination() {
+        let border = border( Some( "https://default.example.com" ), selectors() );
+        assert_eq!( decide( &border, TestRequest::with_uri( "/v1/events" ) ).unwrap(), "__default__ default.example.com:443" );
+        assert_eq!(
+            decide( &border, TestRequest::with_uri( "/v1/events" ).header( "Host", "vendor.example.com" ) ).unwrap(),
+            "vendor.example.com vendor.example.com:8081"
+        );
+
+        let border = self::border( None, vec![ DestinationSelector::PathSegment ] );
+        match decide( &border, TestRequest::with_uri( "/v1/events" ) ) {
+            Err(DenialReason::UnknownDestination(key)) => assert_eq!( key, "v1" ),
+            other => panic!( "not refused: {:?}", other ),
         }
     }
 }
@@ -0,0 +1,213 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::fs;
+use std::io::Result;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use log::{error, info};
+use url::Host;
+use actix_http::http::Method;
+use super::host_control::{Destination, HostControlBuilder, DEFAULT};
+use super::matcher::REGEX_PREFIX;
+use super::visa::Visa;
+
+const DEFAULT_WRITE_INTERVAL: Duration = Duration::from_secs( 10 );
+
+/// A policy admitting requests to any host named by `X-DESTINATION`, for learning the
+/// destinations of a service. Internal addresses stay denied.
+pub fn permissive_border() -> HostControlBuilder {
+    let any = format!( "{}.*", REGEX_PREFIX );
+    HostControlBuilder::new()
+        .with_named_destination( &any, ( Host::Domain( any.clone() ), 80 ) )
+}
+
+#[derive(Default)]
+struct LearnedDestination {
+    scheme: String,
+    port: u16,
+    routes: BTreeMap<String, BTreeSet<String>>,
+}
+
+#[derive(Default)]
+struct Observations {
+    destinations: BTreeMap<String, LearnedDestination>,
+    default_destination: Option<String>,
+    changed: bool,
+}
+
+/// Records the destinations, methods and path templates of granted requests and renders them
+/// as a config file listing each destination with its observed routes, ready for review.
+#[derive(Clone, Default)]
+pub struct Learner( Arc<Mutex<Observations>> );
+
+impl Learner {
+    pub fn new() -> Self { Learner::default() }
+
+    pub fn observe( &self, visa: &Visa, method: &Method, path: &str ) {
+        let host = visa.destination.host.to_string();
+        let template = path_template( path );
+
+        let mut observations = self.0.lock().unwrap();
+        if visa.rule == DEFAULT && observations.default_destination.is_none() {
+            observations.default_destination = Some( host.clone() );
+            observations.changed = true;
+        }
+
+        let destination = observations.destinations
+            .entry( host )
+            .or_insert_with( || {
+                LearnedDestination { scheme: visa.scheme.clone(), port: visa.destination.port, routes: BTreeMap::new(), }
+            } );
+
+        if destination.routes.entry( template ).or_default().insert( method.to_string() ) {
+            observations.changed = true;
+        }
+    }
+
+    /// The observed traffic as a config file of destinations and routes.
+    pub fn to_config( &self ) -> String {
+        let observations = self.0.lock().unwrap();
+        let mut config = String::from( "# learned from observed egress traffic; review before enforcing\n" );
+
+        if let Some(ref name) = observations.default_destination {
+            writeln!( config, "default_destination = \"{}\"", name ).unwrap();
+        }
+
+        config.push_str( "destinations {\n" );
+        for ( host, destination ) in observations.destinations.iter() {
+            writeln!( config, "  \"{}\" {{", host ).unwrap();
+            writeln!( config, "    scheme = {}", destination.scheme ).unwrap();
+            writeln!( config, "    port = {}", destination.port ).unwrap();
+            config.push_str( "    routes = [\n" );
+            for ( path, methods ) in destination.routes.iter() {
+                let methods = methods.iter().cloned().collect::<Vec<String>>().join( ", " );
+                writeln!( config, "      {{ methods = [ {} ], path = \"{}\" }}", methods, path ).unwrap();
+            }
+            config.push_str( "    ]\n  }\n" );
+        }
+        config.push_str( "}\n" );
+
+        config
+    }
+
+    /// Rewrites the config file at `path` whenever new traffic has been observed.
+    pub fn spawn_writer<P: Into<PathBuf>>( self, path: P ) -> Result<JoinHandle<()>> {
+        let path = path.into();
+
+        thread::Builder::new()
+            .name( "policy-learner".to_string() )
+            .spawn( move || {
+                loop {
+                    thread::sleep( DEFAULT_WRITE_INTERVAL );
+                    if !std::mem::replace( &mut self.0.lock().unwrap().changed, false ) {
+                        continue;
+                    }
+
+                    match fs::write( &path, self.to_config() ) {
+                        Ok(_) => info!( "wrote learned egress policy to {}", path.display() ),
+                        Err(e) => error!( "failed to write learned egress policy to {}: {}", path.display(), e ),
+                    }
+                }
+            } )
+    }
+}
+
+/// Generalizes a request path into a route pattern by replacing segments that look like
+/// identifiers (numbers, UUIDs, hex digests and other long tokens containing digits) with `*`.
+pub fn path_template( path: &str ) -> String {
+    let segments = path.split( '/' )
+        .filter( |s| !s.is_empty() )
+        .map( |s| if is_identifier( s ) { "*" } else { s } )
+        .collect::<Vec<&str>>();
+
+    format!( "/{}", segments.join( "/" ) )
+}
+
+fn is_identifier( segment: &str ) -> bool {
+    let has_digit = segment.chars().any( |c| c.is_ascii_digit() );
+    let is_hex = segment.chars().all( |c| c.is_ascii_hexdigit() || c == '-' );
+
+    segment.chars().all( |c| c.is_ascii_digit() ) ||
+        ( has_digit && is_hex && 8 <= segment.len() ) ||
+        ( has_digit && 20 <= segment.len() )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use actix_rt::System;
+    use url::Url;
+    use crate::border::{AsyncBorderControl, BorderControlBuilder};
+    use crate::border::simulation::SimulatedRequest;
+    use crate::config::settings::Settings;
+    use super::*;
+
+    #[test]
+    fn replaces_identifiers_with_wildcards() {
+        assert_eq!( path_template( "/users/12345/orders" ), "/users/*/orders" );
+        assert_eq!( path_template( "/items/0b6f7e4a-3c1d-4e8f-9a2b-5d6c7e8f9a0b" ), "/items/*" );
+        assert_eq!( path_template( "/blobs/9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08" ), "/blobs/*" );
+        assert_eq!( path_template( "/sessions/AbCdEf0123GhIjKl4567mNoP" ), "/sessions/*" );
+        assert_eq!( path_template( "//users//42/" ), "/users/*" );
+        assert_eq!( path_template( "/" ), "/" );
+    }
+
+    #[test]
+    fn keeps_words_and_versions() {
+        assert_eq!( path_template( "/v1/users/me" ), "/v1/users/me" );
+        assert_eq!( path_template( "/api/v2beta3/status" ), "/api/v2beta3/status" );
+        assert_eq!( path_template( "/feed/deadbeef" ), "/feed/deadbeef" );
+        assert_eq!( path_template( "/oauth2/authorize" ), "/oauth2/authorize" );
+    }
+
+    #[test]
+    fn identifies_ids_but_not_short_tokens() {
+        assert!( is_identifier( "7" ) );
+        assert!( is_identifier( "a1b2c3d4" ) );
+        assert!( is_identifier( "0b6f7e4a-3c1d-4e8f-9a2b-5d6c7e8f9a0b" ) );
+        assert!( !is_identifier( "a1b2c3" ) );
+        assert!( !is_identifier( "v1" ) );
+        assert!( !is_identifier( "abcdefabcdef" ) );
+        assert!( !is_identifier( "a-very-long-path-segment-without-digits" ) );
+    }
+
+    fn request( method: &str, url: &str ) -> SimulatedRequest {
+        SimulatedRequest {
+            method: method.to_string(),
+            url: url.to_string(),
+            headers: vec![ ( "X-DESTINATION".to_string(), "api.example.com".to_string() ) ].into_iter().collect(),
+            caller: None,
+            identity: vec![],
+            attributes: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn learned_config_admits_the_observed_requests() {
+        let learner = Learner::new();
+        let destination: Destination = Url::parse( "https://api.example.com" ).unwrap().into();
+        let visa = destination.visa( "api.example.com" );
+        learner.observe( &visa, &Method::GET, "/v1/users/42" );
+        learner.observe( &visa, &Method::POST, "/v1/users/42/orders" );
+
+        let path = std::env::temp_dir().join( format!( "egress-proxy-learned-{}.conf", std::process::id() ) );
+        fs::write( &path, learner.to_config() ).unwrap();
+        let settings = Settings::load( &path );
+        fs::remove_file( &path ).unwrap();
+
+        let border: Rc<dyn AsyncBorderControl> = Rc::from(
+            settings.unwrap().border_builder().unwrap().without_address_resolution().build()
+        );
+        let mut system = System::new( "learning-test" );
+        let mut decide = |method, url| system.block_on( request( method, url ).decide( border.clone() ) ).unwrap().decision;
+
+        let visa = decide( "GET", "/v1/users/7" ).unwrap();
+        assert_eq!( visa.destination.to_string(), "api.example.com:443" );
+        assert_eq!( visa.scheme, "https" );
+        assert!( decide( "POST", "/v1/users/7/orders" ).is_ok() );
+        assert_eq!( decide( "DELETE", "/v1/users/7" ).unwrap_err().code(), "method_not_allowed" );
+        assert_eq!( decide( "GET", "/v1/admin" ).unwrap_err().code(), "path_not_allowed" );
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use regex::Regex;
+
+/// Prefix marking a destination key as a regular expression, e.g. `~^api-[0-9]+\.vendor\.com$`.
+pub const REGEX_PREFIX: &str = "~";
+/// Prefix marking a destination key as a suffix wildcard, e.g. `*.vendor.com`.
+pub const WILDCARD_PREFIX: &str = "*.";
+
+/// A pattern selecting destinations by host name.
+#[derive(Clone, Debug)]
+pub enum HostPattern {
+    Exact( String ),
+    Suffix( String ),
+    Regex( Regex ),
+}
+
+impl HostPattern {
+    pub fn parse( pattern: &str ) -> Result<HostPattern, regex::Error> {
+        if let Some(re) = pattern.strip_prefix( REGEX_PREFIX ) {
+            Regex::new( re ).map( HostPattern::Regex )
+        } else if let Some(suffix) = pattern.strip_prefix( WILDCARD_PREFIX ) {
+            Ok( HostPattern::Suffix( format!( ".{}", suffix.to_lowercase() ) ) )
+        } else {
+            Ok( HostPattern::Exact( pattern.to_lowercase() ) )
+        }
+    }
+}
+
+/// Matches a host against exact, suffix wildcard and regex patterns with deterministic
+/// precedence: an exact match wins, then the longest matching suffix, then the first matching
+/// regex in lexical order of its pattern.
+#[derive(Clone, Debug)]
+pub struct DestinationMatcher<T> {
+    exact: HashMap<String, T>,
+    suffixes: Vec<(String, T)>,
+    regexes: Vec<(String, Regex, T)>,
+}
+
+impl<T> Default for DestinationMatcher<T> {
+    fn default() -> Self {
+        DestinationMatcher { exact: HashMap::new(), suffixes: Vec::new(), regexes: Vec::new(), }
+    }
+}
+
+impl<T> DestinationMatcher<T> {
+    pub fn new() -> Self { DestinationMatcher::default() }
+
+    pub fn insert( &mut self, pattern: &str, value: T ) -> Result<(), regex::Error> {
+        match HostPattern::parse( pattern )? {
+            HostPattern::Exact(h) => { self.exact.insert( h, value ); },
+
+            HostPattern::Suffix(s) => {
+                self.suffixes.push( (s, value) );
+                self.suffixes.sort_by( |a, b| b.0.len().cmp( &a.0.len() ).then_with( || a.0.cmp( &b.0 ) ) );
+            },
+
+            HostPattern::Regex(re) => {
+                self.regexes.push( (pattern.to_string(), re, value) );
+                self.regexes.sort_by( |a, b| a.0.cmp( &b.0 ) );
+            },
+        }
+
+        Ok( () )
+    }
+
+    /// Finds the most specific value for the host, along with whether it matched exactly.
+    pub fn find( &self, host: &str ) -> Option<(&T, bool)> {
+        let host = host.to_lowercase();
+
+        if let Some(v) = self.exact.get( &host ) {
+            return Some( (v, true) );
+        }
+
+        self.suffixes
+            .iter()
+            .find( |(s, _)| host.ends_with( s.as_str() ) && host.len() > s.len() )
+            .map( |(_, v)| (v, false) )
+            .or_else( || {
+                self.regexes
+                    .iter()
+                    .find( |(_, re, _)| re.is_match( &host ) )
+                    .map( |(_, _, v)| (v, false) )
+            } )
+    }
+
+    pub fn is_empty( &self ) -> bool {
+        self.exact.is_empty() && self.suffixes.is_empty() && self.regexes.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher( patterns: &[&str] ) -> DestinationMatcher<String> {
+        let mut matcher = DestinationMatcher::new();
+        for pattern in patterns {
+            matcher.insert( pattern, pattern.to_string() ).unwrap();
+        }
+        matcher
+    }
+
+    fn found<'a>( matcher: &'a DestinationMatcher<String>, host: &str ) -> Option<( &'a str, bool )> {
+        matcher.find( host ).map( |( v, exact )| ( v.as_str(), exact ) )
+    }
+
+    #[test]
+    fn matches_exact_hosts_ignoring_case() {
+        let matcher = matcher( &[ "api.example.com" ] );
+        assert_eq!( found( &matcher, "API.Example.com" ), Some( ( "api.example.com", true ) ) );
+        assert_eq!( found( &matcher, "example.com" ), None );
+    }
+
+    #[test]
+    fn matches_suffixes_only_below_the_domain() {
+        let matcher = matcher( &[ "*.example.com" ] );
+        assert_eq!( found( &matcher, "api.example.com" ), Some( ( "*.example.com", false ) ) );
+        assert_eq!( found( &matcher, "a.b.example.com" ), Some( ( "*.example.com", false ) ) );
+        assert_eq!( found( &matcher, "example.com" ), None );
+        assert_eq!( found( &matcher, "example.com.evil" ), None );
+        assert_eq!( found( &matcher, "evilexample.com" ), None );
+    }
+
+    #[test]
+    fn prefers_exact_then_longest_suffix_then_regex() {
+        let matcher = matcher( &[ "~^api\\..*$", "*.example.com", "*.eu.example.com", "api.eu.example.com" ] );
+        assert_eq!( found( &matcher, "api.eu.example.com" ), Some( ( "api.eu.example.com", true ) ) );
+        assert_eq!( found( &matcher, "web.eu.example.com" ), Some( ( "*.eu.example.com", false ) ) );
+        assert_eq!( found( &matcher, "api.example.com" ), Some( ( "*.example.com", false ) ) );
+        assert_eq!( found( &matcher, "api.example.org" ), Some( ( "~^api\\..*$", false ) ) );
+    }
+
+    #[test]
+    fn tries_regexes_in_lexical_order() {
+        let matcher = matcher( &[ "~^b.*$", "~^a.*$", "~^.*$" ] );
+        assert_eq!( found( &matcher, "bravo" ), Some( ( "~^.*$", false ) ) );
+
+        let matcher = self::matcher( &[ "~^b.*$", "~^a.*$" ] );
+        assert_eq!( found( &matcher, "bravo" ), Some( ( "~^b.*$", false ) ) );
+        assert_eq!( found( &matcher, "alpha" ), Some( ( "~^a.*$", false ) ) );
+    }
+
+    #[test]
+    fn refuses_invalid_regexes() {
+        assert!( DestinationMatcher::new().insert( "~^api-[", () ).is_err() );
+    }
+}
@@ -1,16 +1,29 @@
 use futures::{ Future, IntoFuture };
-use actix_web::Error;
+use futures::future::result;
 use actix_web::dev::ServiceRequest;
-use url::HostAndPort;
-use actix_http::error::ErrorForbidden;
 
+pub mod address;
+pub mod caller;
+pub mod combinators;
+pub mod denial;
 pub mod host_control;
+pub mod learning;
+pub mod matcher;
+pub mod policy;
+pub mod route;
+pub mod selector;
+pub mod simulation;
+pub mod source;
+pub mod visa;
 
-pub trait BorderControl {
-    /// Requests challenged by BorderControl.
+use self::denial::DenialReason;
+use self::visa::Visa;
+
+pub trait BorderControl: Send + Sync {
+    // Requests challenged by BorderControl.
 //    type Request = HttpRequest;
 
-    /// Errors produced by the BorderControl challenge
+    // Errors produced by the BorderControl challenge
 //    type Error;
 
 //    type Future: Future<Item = bool, Error = Self::Error>;
@@ -18,11 +31,54 @@ pub trait BorderControl {
     /// Process the request, possibly considering past requests, to make a determination
     /// whether to allow it to pass.
 //    fn allow( &mut self, req: &HttpRequest ) -> Self::Future;
-    fn request_visa(&self, req: &ServiceRequest ) -> Result<&HostAndPort, Error>;
+    fn request_visa(&self, req: &ServiceRequest ) -> Result<Visa, DenialReason>;
+}
+
+impl BorderControl for Box<dyn BorderControl> {
+    fn request_visa(&self, req: &ServiceRequest ) -> Result<Visa, DenialReason> {
+        self.as_ref().request_visa( req )
+    }
+}
+
+/// The eventual decision of an `AsyncBorderControl`.
+pub type VisaFuture = Box<dyn Future<Item = Visa, Error = DenialReason>>;
+
+/// A border policy whose decision may require I/O, such as consulting an entitlement store or
+/// waiting on a rate-limiter permit. The returned future must not borrow the request; extract
+/// what the decision needs before returning. Every `BorderControl` is usable as an
+/// `AsyncBorderControl` that decides immediately.
+pub trait AsyncBorderControl: Send + Sync {
+    fn apply_for_visa( &self, req: &ServiceRequest ) -> VisaFuture;
+
+    /// The decision made without the checks that need I/O, if the policy can make one; a
+    /// fail-open filter grants by it when the full decision takes too long.
+    fn decide_without_io( &self, _req: &ServiceRequest ) -> Option<Result<Visa, DenialReason>> {
+        None
+    }
+}
+
+impl<B: BorderControl + ?Sized> AsyncBorderControl for B {
+    fn apply_for_visa( &self, req: &ServiceRequest ) -> VisaFuture {
+        Box::new( result( self.request_visa( req ) ) )
+    }
+
+    fn decide_without_io( &self, req: &ServiceRequest ) -> Option<Result<Visa, DenialReason>> {
+        Some( self.request_visa( req ) )
+    }
+}
+
+impl AsyncBorderControl for Box<dyn AsyncBorderControl> {
+    fn apply_for_visa( &self, req: &ServiceRequest ) -> VisaFuture {
+        self.as_ref().apply_for_visa( req )
+    }
+
+    fn decide_without_io( &self, req: &ServiceRequest ) -> Option<Result<Visa, DenialReason>> {
+        self.as_ref().decide_without_io( req )
+    }
 }
 
 pub trait BorderControlBuilder {
-    fn build( self ) -> Box<dyn BorderControl>;
+    fn build( self ) -> Box<dyn AsyncBorderControl>;
 }
 
 
@@ -34,7 +90,7 @@ impl ClosedBorder {
 }
 
 impl BorderControl for ClosedBorder {
-    fn request_visa(&self, req: &ServiceRequest ) -> Result<&HostAndPort, Error> {
-        Err( ErrorForbidden("closed egress proxy. no destinations allowed" ) )
+    fn request_visa(&self, req: &ServiceRequest ) -> Result<Visa, DenialReason> {
+        Err( DenialReason::ClosedBorder )
     }
 }
@@ -0,0 +1,82 @@
+use std::sync::{Arc, RwLock};
+use lazy_static::*;
+use log::info;
+use prometheus::IntGauge;
+use actix_web::dev::ServiceRequest;
+use super::{AsyncBorderControl, VisaFuture};
+use super::denial::DenialReason;
+use super::visa::Visa;
+
+lazy_static! {
+    pub static ref POLICY_VERSION: IntGauge = register_int_gauge!(
+        opts!(
+            "egress_border_policy_version",
+            "Version of the border policy currently enforced by the egress proxy."
+        ).const_labels( crate::metrics::const_labels() )
+    )
+    .unwrap();
+
+    pub static ref SHADOW_POLICY_VERSION: IntGauge = register_int_gauge!(
+        opts!(
+            "egress_border_shadow_policy_version",
+            "Version of the candidate border policy evaluated in shadow mode."
+        ).const_labels( crate::metrics::const_labels() )
+    )
+    .unwrap();
+}
+
+struct ActivePolicy {
+    version: u64,
+    border: Arc<dyn AsyncBorderControl>,
+    kind: &'static str,
+    version_gauge: &'static IntGauge,
+}
+
+/// A border policy shared by every worker that can be atomically replaced while the proxy runs.
+/// Requests in flight keep the policy they started with; new requests see the replacement.
+#[derive(Clone)]
+pub struct ReloadableBorder( Arc<RwLock<ActivePolicy>> );
+
+impl ReloadableBorder {
+    pub fn new( border: Box<dyn AsyncBorderControl> ) -> Self {
+        ReloadableBorder::with_gauge( border, "border", &POLICY_VERSION )
+    }
+
+    /// A candidate policy evaluated in shadow mode, whose version is exported separately.
+    pub fn shadow( border: Box<dyn AsyncBorderControl> ) -> Self {
+        ReloadableBorder::with_gauge( border, "shadow border", &SHADOW_POLICY_VERSION )
+    }
+
+    fn with_gauge( border: Box<dyn AsyncBorderControl>, kind: &'static str, version_gauge: &'static IntGauge ) -> Self {
+        version_gauge.set( 1 );
+        ReloadableBorder(
+            Arc::new( RwLock::new( ActivePolicy { version: 1, border: border.into(), kind, version_gauge, } ) )
+        )
+    }
+
+    pub fn version( &self ) -> u64 { self.0.read().unwrap().version }
+
+    /// Swaps in a new border policy, returning its version.
+    pub fn replace( &self, border: Box<dyn AsyncBorderControl> ) -> u64 {
+        let mut active = self.0.write().unwrap();
+        active.version += 1;
+        active.border = border.into();
+        active.version_gauge.set( active.version as i64 );
+        info!( "{} policy version {} activated", active.kind, active.version );
+        active.version
+    }
+
+    fn current( &self ) -> Arc<dyn AsyncBorderControl> {
+        self.0.read().unwrap().border.clone()
+    }
+}
+
+impl AsyncBorderControl for ReloadableBorder {
+    fn apply_for_visa( &self, req: &ServiceRequest ) -> VisaFuture {
+        self.current().apply_for_visa( req )
+    }
+
+    fn decide_without_io( &self, req: &ServiceRequest ) -> Option<Result<Visa, DenialReason>> {
+        self.current().decide_without_io( req )
+    }
+}
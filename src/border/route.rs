@@ -0,0 +1,164 @@
+use std::fmt;
+use actix_http::http::Method;
+use url::percent_encoding::percent_decode;
+
+/// A request path pattern over `/`-separated segments: `*` matches any single segment and a
+/// trailing `**` matches any remaining segments, including none. `/v1/status/**` matches
+/// `/v1/status` and everything below it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PathPattern {
+    pattern: String,
+    segments: Vec<String>,
+}
+
+impl PathPattern {
+    pub fn new( pattern: &str ) -> Self {
+        PathPattern { pattern: pattern.to_string(), segments: segments( pattern ), }
+    }
+
+    pub fn matches( &self, path: &str ) -> bool {
+        let path = segments( path );
+        let mut path = path.iter();
+
+        for ( i, seg ) in self.segments.iter().enumerate() {
+            if seg == "**" && i == self.segments.len() - 1 {
+                return true;
+            }
+
+            match path.next() {
+                Some(p) if seg == "*" || seg == p => continue,
+                _ => return false,
+            }
+        }
+
+        path.next().is_none()
+    }
+}
+
+impl fmt::Display for PathPattern {
+    fn fmt( &self, f: &mut fmt::Formatter ) -> fmt::Result {
+        write!( f, "{}", self.pattern )
+    }
+}
+
+fn segments( path: &str ) -> Vec<String> {
+    path.split( '/' )
+        .filter( |s| !s.is_empty() )
+        .map( |s| s.to_string() )
+        .collect()
+}
+
+/// The request path percent-decoded, as routes are matched against it. Paths with `.` or `..`
+/// segments, however encoded, or with segments decoding to a `/` or `\`, have none: the upstream
+/// URL would resolve them to another path than the one checked.
+pub fn decoded_path( path: &str ) -> Option<String> {
+    let mut decoded = Vec::new();
+    for segment in path.split( '/' ) {
+        let segment = percent_decode( segment.as_bytes() ).decode_utf8_lossy();
+        if segment == "." || segment == ".." || segment.contains( '/' ) || segment.contains( '\\' ) {
+            return None;
+        }
+        decoded.push( segment );
+    }
+
+    Some( decoded.join( "/" ) )
+}
+
+/// Admits requests whose path matches the pattern using one of the methods, or any method if
+/// none are listed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RouteRule {
+    methods: Vec<Method>,
+    path: PathPattern,
+}
+
+impl RouteRule {
+    pub fn new( methods: &[Method], path: &str ) -> Self {
+        RouteRule { methods: methods.to_vec(), path: PathPattern::new( path ), }
+    }
+
+    pub fn matches_path( &self, path: &str ) -> bool { self.path.matches( path ) }
+
+    pub fn allows_method( &self, method: &Method ) -> bool {
+        self.methods.is_empty() || self.methods.contains( method )
+    }
+}
+
+/// The outcome of checking a request against a destination's routes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RouteCheck {
+    Allowed,
+    PathNotAllowed,
+    MethodNotAllowed,
+}
+
+/// Checks a request against route rules; a destination without rules admits every request.
+pub fn check_routes( routes: &[RouteRule], method: &Method, path: &str ) -> RouteCheck {
+    if routes.is_empty() {
+        return RouteCheck::Allowed;
+    }
+
+    let mut path_matched = false;
+    for route in routes.iter().filter( |r| r.matches_path( path ) ) {
+        path_matched = true;
+        if route.allows_method( method ) {
+            return RouteCheck::Allowed;
+        }
+    }
+
+    if path_matched { RouteCheck::MethodNotAllowed } else { RouteCheck::PathNotAllowed }
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+    use super::*;
+
+    #[test]
+    fn decodes_segments() {
+        assert_eq!( decoded_path( "/v1/%73tatus/a%20b" ), Some( "/v1/status/a b".to_string() ) );
+        assert_eq!( decoded_path( "/v1/status/a..b/.well-known" ), Some( "/v1/status/a..b/.well-known".to_string() ) );
+    }
+
+    #[test]
+    fn refuses_dot_segments() {
+        for path in &[
+            "/v1/status/../admin",
+            "/v1/status/%2e%2e/admin",
+            "/v1/status/%2E%2e/admin",
+            "/v1/status/.%2E/admin",
+            "/v1/status/%2e./admin",
+            "/v1/status/./admin",
+            "/v1/status/%2e/admin",
+            "/v1/status/..",
+        ] {
+            assert_eq!( decoded_path( path ), None, "{}", path );
+        }
+    }
+
+    #[test]
+    fn refuses_encoded_separators() {
+        for path in &[ "/v1/status%2F..%2Fadmin", "/v1/status%2f..%2fadmin", "/v1/status%5C..%5Cadmin", "/v1/status\\..\\admin" ] {
+            assert_eq!( decoded_path( path ), None, "{}", path );
+        }
+    }
+
+    #[test]
+    fn forwards_the_path_checked() {
+        let base = Url::parse( "http://upstream.example" ).unwrap();
+        for path in &[ "/v1/status/a..b", "/v1/%73tatus/x", "/v1/status/.well-known", "/v1/status/%2e%2e.x" ] {
+            assert!( decoded_path( path ).is_some(), "{}", path );
+            let mut url = base.clone();
+            url.set_path( path );
+            assert_eq!( url.path(), *path );
+        }
+    }
+
+    #[test]
+    fn traversal_cannot_reach_other_routes() {
+        let routes = vec![ RouteRule::new( &[Method::GET], "/v1/status/**" ) ];
+        assert_eq!( check_routes( &routes, &Method::GET, "/v1/status/x" ), RouteCheck::Allowed );
+        assert_eq!( check_routes( &routes, &Method::GET, &decoded_path( "/v1/%73tatus/x" ).unwrap() ), RouteCheck::Allowed );
+        assert_eq!( check_routes( &routes, &Method::GET, "/v1/admin" ), RouteCheck::PathNotAllowed );
+    }
+}
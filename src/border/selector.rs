@@ -0,0 +1,73 @@
+use actix_http::http::{header, HeaderName};
+use actix_http::http::uri::Authority;
+use actix_web::dev::ServiceRequest;
+use url::form_urlencoded;
+
+/// How a request names its egress destination to `ManyHostsBorder`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DestinationSelector {
+    /// The value of a request header, e.g. `X-DESTINATION`.
+    Header( HeaderName ),
+    /// The first segment of the request path, e.g. `vendor` in `/vendor/v1/events`; the
+    /// segment is stripped before the request is forwarded.
+    PathSegment,
+    /// The value of a query parameter, which is removed before the request is forwarded.
+    QueryParameter( String ),
+    /// The subdomain of the proxy's own host name in the `Host` header, e.g. `vendor` in
+    /// `vendor.egress.internal` for the proxy `egress.internal`.
+    Subdomain( String ),
+}
+
+/// A destination named by a request, and the parts of the request that named it, which are
+/// not forwarded.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Selection {
+    pub key: String,
+    pub path_prefix: String,
+    pub query_parameter: Option<String>,
+}
+
+impl Selection {
+    pub fn named( key: &str ) -> Self {
+        Selection { key: key.to_string(), ..Selection::default() }
+    }
+}
+
+impl DestinationSelector {
+    /// The destination the request names by this selector, if it names one. The path, query
+    /// and host of an absolute-form request are its destination's own, so only a header
+    /// selects for those.
+    pub fn select( &self, req: &ServiceRequest ) -> Option<Selection> {
+        let absolute = req.uri().authority_part().is_some();
+
+        match *self {
+            DestinationSelector::Header(ref name) => {
+                let value = req.headers().get( name )?;
+                let key = value.to_str().map( str::to_string ).unwrap_or_else( |_| format!( "{:?}", value ) );
+                Some( Selection { key, ..Selection::default() } )
+            },
+
+            _ if absolute => None,
+
+            DestinationSelector::PathSegment => {
+                let path = req.path();
+                let rest = path.trim_start_matches( '/' );
+                let segment = rest.split( '/' ).next().filter( |s| !s.is_empty() )?;
+                let prefix = &path[..path.len() - rest.len() + segment.len()];
+                Some( Selection { key: segment.to_string(), path_prefix: prefix.to_string(), query_parameter: None, } )
+            },
+
+            DestinationSelector::QueryParameter(ref name) => {
+                let ( _, key ) = form_urlencoded::parse( req.query_string().as_bytes() ).find( |( n, _ )| n == name )?;
+                Some( Selection { key: key.into_owned(), path_prefix: String::new(), query_parameter: Some( name.clone() ), } )
+            },
+
+            DestinationSelector::Subdomain(ref domain) => {
+                let host = req.headers().get( header::HOST )?.to_str().ok()?.parse::<Authority>().ok()?;
+                let host = host.host().to_lowercase();
+                let key = host.strip_suffix( domain.as_str() )?.strip_suffix( '.' ).filter( |k| !k.is_empty() )?;
+                Some( Selection::named( key ) )
+            },
+        }
+    }
+}
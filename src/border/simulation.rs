@@ -0,0 +1,205 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::{Error, Result};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::rc::Rc;
+use std::str::FromStr;
+use actix_http::{HttpMessage, Request};
+use actix_http::http::{HeaderName, HeaderValue, Method, Uri};
+use actix_server_config::ServerConfig;
+use actix_service::{service_fn, IntoNewService, NewService, Service};
+use actix_web::{App, HttpResponse};
+use actix_web::dev::ServiceRequest;
+use futures::{Future, future::{err, Either}};
+use serde_derive::Deserialize;
+use crate::config::settings::invalid;
+use super::AsyncBorderControl;
+use super::caller::CallerIdentity;
+use super::denial::DenialReason;
+use super::visa::Visa;
+
+/// A described request to put to a border policy without forwarding it, e.g.
+/// `{ "method": "POST", "url": "/v1/events", "headers": { "X-DESTINATION": "vendor" }, "caller": "10.1.2.3" }`
+#[derive(Clone, Debug, Deserialize)]
+pub struct SimulatedRequest {
+    #[serde(default = "default_method")]
+    pub method: String,
+    pub url: String,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    pub caller: Option<IpAddr>,
+    /// Names of the caller's identity, e.g. from its client certificate.
+    #[serde(default)]
+    pub identity: Vec<String>,
+    /// Attributes of the caller's identity, e.g. `{ "scopes": [ "egress:vendor" ] }`.
+    #[serde(default)]
+    pub attributes: BTreeMap<String, Vec<String>>,
+}
+
+fn default_method() -> String { "GET".to_string() }
+
+/// A border policy's decision for a simulated request, with the parts of the request it was
+/// made on.
+#[derive(Clone, Debug)]
+pub struct Simulation {
+    pub decision: std::result::Result<Visa, DenialReason>,
+    pub path: String,
+    pub query: String,
+    pub caller: Option<CallerIdentity>,
+}
+
+impl SimulatedRequest {
+    /// Builds the request as the proxy would receive it from the caller.
+    pub fn to_request( &self ) -> Result<Request> {
+        let method = Method::from_bytes( self.method.to_uppercase().as_bytes() )
+            .map_err( |e| invalid( format!( "invalid method {}: {}", self.method, e ) ) )?;
+        let uri = Uri::from_str( &self.url )
+            .map_err( |e| invalid( format!( "invalid url {}: {}", self.url, e ) ) )?;
+
+        let mut req = Request::new();
+        let head = req.head_mut();
+        head.method = method;
+        head.uri = uri;
+        head.peer_addr = self.caller.map( |ip| SocketAddr::new( ip, 0 ) );
+        for ( name, value ) in self.headers.iter() {
+            match ( HeaderName::from_bytes( name.as_bytes() ), HeaderValue::from_str( value ) ) {
+                ( Ok(name), Ok(value) ) => head.headers.append( name, value ),
+                _ => return Err( invalid( format!( "invalid header {}: {}", name, value ) ) ),
+            }
+        }
+
+        if let Some(mut identity) = CallerIdentity::from_names( self.identity.clone() ) {
+            for ( name, values ) in self.attributes.iter() {
+                identity = identity.with_attribute( name, values.clone() );
+            }
+            req.extensions_mut().insert( identity );
+        }
+        Ok( req )
+    }
+
+    /// Puts the request to the border policy as a proxy worker would, on a service of its own;
+    /// must be run on an actix system.
+    pub fn decide( &self, border: Rc<dyn AsyncBorderControl> ) -> impl Future<Item = Simulation, Error = Error> {
+        let req = match self.to_request() {
+            Ok(req) => req,
+            Err(e) => return Either::A( err( e ) ),
+        };
+
+        let simulation = Rc::new( RefCell::new( None ) );
+        let outcome = simulation.clone();
+        let app = App::new().default_service( service_fn( move |req: ServiceRequest| {
+            let outcome = outcome.clone();
+            border.apply_for_visa( &req ).then( move |decision| {
+                *outcome.borrow_mut() = Some( Simulation {
+                    decision,
+                    path: req.path().to_string(),
+                    query: req.query_string().to_string(),
+                    caller: CallerIdentity::of( &req ),
+                } );
+                Ok( req.into_response( HttpResponse::Ok().finish() ) )
+            } )
+        } ) );
+
+        let config = ServerConfig::new( SocketAddr::new( IpAddr::V4( Ipv4Addr::LOCALHOST ), 0 ) );
+        let decided = app.into_new_service()
+            .new_service( &config )
+            .map_err( |_| Error::other( "failed to start the simulation service" ) )
+            .and_then( |mut service| service.call( req ).map_err( |e| Error::other( e.to_string() ) ) )
+            .and_then( move |_| {
+                simulation.borrow_mut()
+                    .take()
+                    .ok_or_else( || Error::other( "the simulated request was not decided" ) )
+            } );
+
+        Either::B( decided )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_rt::System;
+    use url::Url;
+    use crate::border::BorderControlBuilder;
+    use crate::border::host_control::HostControlBuilder;
+    use super::*;
+
+    fn request( method: &str, destination: &str ) -> SimulatedRequest {
+        SimulatedRequest {
+            method: method.to_string(),
+            url: "/v1/events?page=2".to_string(),
+            headers: vec![ ( "X-DESTINATION".to_string(), destination.to_string() ) ].into_iter().collect(),
+            caller: Some( "10.1.2.3".parse().unwrap() ),
+            identity: vec![],
+            attributes: BTreeMap::new(),
+        }
+    }
+
+    fn border() -> Rc<dyn AsyncBorderControl> {
+        Rc::from(
+            HostControlBuilder::new()
+                .with_named_destination( "vendor", Url::parse( "https://vendor.example.com" ).unwrap() )
+                .without_address_resolution()
+                .build()
+        )
+    }
+
+    #[test]
+    fn builds_the_request_as_received() {
+        let req = request( "post", "vendor" ).to_request().unwrap();
+        assert_eq!( req.method(), Method::POST );
+        assert_eq!( req.uri().path(), "/v1/events" );
+        assert_eq!( req.headers().get( "x-destination" ).unwrap(), "vendor" );
+        assert_eq!( req.peer_addr(), Some( "10.1.2.3:0".parse().unwrap() ) );
+        assert!( req.extensions().get::<CallerIdentity>().is_none() );
+    }
+
+    #[test]
+    fn injects_the_identity_with_its_attributes() {
+        let mut sim = request( "GET", "vendor" );
+        sim.identity = vec![ "billing".to_string(), "billing.internal".to_string() ];
+        sim.attributes.insert( "scopes".to_string(), vec![ "egress:vendor".to_string() ] );
+
+        let req = sim.to_request().unwrap();
+        let extensions = req.extensions();
+        let identity = extensions.get::<CallerIdentity>().unwrap();
+        assert_eq!( identity.names(), &[ "billing".to_string(), "billing.internal".to_string() ] );
+        assert_eq!( identity.attribute( "scopes" ), &[ "egress:vendor".to_string() ] );
+    }
+
+    #[test]
+    fn refuses_invalid_methods_and_headers() {
+        assert!( request( "GE T", "vendor" ).to_request().is_err() );
+
+        let mut sim = request( "GET", "vendor" );
+        sim.headers.insert( "bad header".to_string(), "value".to_string() );
+        assert!( sim.to_request().is_err() );
+
+        let mut sim = request( "GET", "vendor" );
+        sim.headers.insert( "X-Trace".to_string(), "line\nbreak".to_string() );
+        assert!( sim.to_request().is_err() );
+
+        let mut sim = request( "GET", "vendor" );
+        sim.url = "http://[bad".to_string();
+        assert!( sim.to_request().is_err() );
+    }
+
+    #[test]
+    fn decides_on_an_actix_system() {
+        let mut system = System::new( "simulation-test" );
+
+        let mut sim = request( "GET", "vendor" );
+        sim.identity = vec![ "billing".to_string() ];
+        let simulation = system.block_on( sim.decide( border() ) ).unwrap();
+        let visa = simulation.decision.unwrap();
+        assert_eq!( visa.rule, "vendor" );
+        assert_eq!( visa.destination.to_string(), "vendor.example.com:443" );
+        assert_eq!( simulation.path, "/v1/events" );
+        assert_eq!( simulation.query, "page=2" );
+        assert_eq!( simulation.caller.unwrap().name(), "billing" );
+
+        let simulation = system.block_on( request( "GET", "other" ).decide( border() ) ).unwrap();
+        assert_eq!( simulation.decision.unwrap_err().code(), "unknown_destination" );
+
+        assert!( system.block_on( request( "GE T", "vendor" ).decide( border() ) ).is_err() );
+    }
+}
@@ -0,0 +1,185 @@
+use std::net::IpAddr;
+use ipnet::IpNet;
+use actix_web::HttpMessage;
+use actix_web::dev::ServiceRequest;
+use super::BorderControl;
+use super::address::unmapped;
+use super::denial::DenialReason;
+use super::visa::Visa;
+
+static HDR_X_FORWARDED_FOR: &str = "X-Forwarded-For";
+
+/// The address a request originated from: the peer address of its connection, or the client a
+/// trusted load balancer forwarded it for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClientAddress( pub IpAddr );
+
+impl ClientAddress {
+    /// The client address established for a request, or else its peer address.
+    pub fn of( req: &ServiceRequest ) -> Option<IpAddr> {
+        req.extensions()
+            .get::<ClientAddress>()
+            .map( |a| a.0 )
+            .or_else( || req.head().peer_addr.map( |a| a.ip() ) )
+    }
+}
+
+/// CIDR ranges callers must connect from, and the load balancers trusted to report the client
+/// they forward for in `X-Forwarded-For` (or with the PROXY protocol). An empty allow list
+/// admits every caller.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SourcePolicy {
+    allow: Vec<IpNet>,
+    trusted_proxies: Vec<IpNet>,
+}
+
+impl SourcePolicy {
+    pub fn new() -> Self {
+        SourcePolicy::default()
+    }
+
+    pub fn allow( mut self, net: IpNet ) -> Self {
+        self.allow.push( net );
+        self
+    }
+
+    pub fn trust_proxy( mut self, net: IpNet ) -> Self {
+        self.trusted_proxies.push( net );
+        self
+    }
+
+    pub fn is_empty( &self ) -> bool {
+        self.allow.is_empty() && self.trusted_proxies.is_empty()
+    }
+
+    pub fn admits( &self, addr: &IpAddr ) -> bool {
+        admits( &self.allow, addr )
+    }
+
+    pub fn is_trusted_proxy( &self, addr: &IpAddr ) -> bool {
+        let addr = unmapped( addr );
+        self.trusted_proxies.iter().any( |net| net.contains( &addr ) )
+    }
+
+    /// The client a request came from. `X-Forwarded-For` is read from the right, skipping the
+    /// hops added by trusted proxies, and only when the peer itself is a trusted proxy.
+    pub fn client_address( &self, req: &ServiceRequest ) -> Option<IpAddr> {
+        let mut client = req.head().peer_addr.map( |a| a.ip() )?;
+
+        let forwarded = req.headers()
+            .get_all( HDR_X_FORWARDED_FOR )
+            .filter_map( |v| v.to_str().ok() )
+            .flat_map( |v| v.split( ',' ) )
+            .map( str::trim )
+            .collect::<Vec<&str>>();
+
+        for hop in forwarded.iter().rev() {
+            if !self.is_trusted_proxy( &client ) {
+                break;
+            }
+            match hop.parse::<IpAddr>() {
+                Ok(addr) => client = addr,
+                Err(_) => break,
+            }
+        }
+
+        Some( client )
+    }
+}
+
+/// Whether `addr` falls in one of `nets`; no ranges admit every address.
+pub fn admits( nets: &[IpNet], addr: &IpAddr ) -> bool {
+    let addr = unmapped( addr );
+    nets.is_empty() || nets.iter().any( |net| net.contains( &addr ) )
+}
+
+/// Establishes the client address of a request and refuses callers outside the source policy
+/// before the wrapped border considers the request. Destinations limiting their own sources
+/// check the same client address.
+pub struct SourceBorder {
+    border: Box<dyn BorderControl>,
+    policy: SourcePolicy,
+}
+
+impl SourceBorder {
+    pub fn new( border: Box<dyn BorderControl>, policy: SourcePolicy ) -> Self {
+        SourceBorder { border, policy, }
+    }
+}
+
+impl BorderControl for SourceBorder {
+    fn request_visa( &self, req: &ServiceRequest ) -> Result<Visa, DenialReason> {
+        let client = self.policy.client_address( req );
+        if let Some(client) = client {
+            req.extensions_mut().insert( ClientAddress( client ) );
+        }
+
+        match client {
+            Some(ref addr) if self.policy.admits( addr ) => self.border.request_visa( req ),
+            None if self.policy.allow.is_empty() => self.border.request_visa( req ),
+            _ => Err( DenialReason::SourceNotAllowed {
+                source: client.map( |a| a.to_string() ).unwrap_or_else( || "unknown".to_string() ),
+                destination: None,
+            } ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use super::*;
+
+    fn policy() -> SourcePolicy {
+        SourcePolicy::new().trust_proxy( "10.0.0.0/24".parse().unwrap() )
+    }
+
+    fn client( peer: &str, forwarded: &[&str] ) -> Option<IpAddr> {
+        let mut req = TestRequest::default();
+        for hops in forwarded {
+            req = req.header( HDR_X_FORWARDED_FOR, *hops );
+        }
+        let mut req = req.to_srv_request();
+        req.head_mut().peer_addr = Some( peer.parse().unwrap() );
+        policy().client_address( &req )
+    }
+
+    fn ip( addr: &str ) -> Option<IpAddr> {
+        Some( addr.parse().unwrap() )
+    }
+
+    #[test]
+    fn ignores_forwarded_for_from_untrusted_peers() {
+        assert_eq!( client( "192.168.1.5:4000", &[ "10.1.2.3" ] ), ip( "192.168.1.5" ) );
+    }
+
+    #[test]
+    fn takes_the_client_a_trusted_proxy_forwarded_for() {
+        assert_eq!( client( "10.0.0.10:4000", &[ "10.1.2.3" ] ), ip( "10.1.2.3" ) );
+        assert_eq!( client( "10.0.0.10:4000", &[] ), ip( "10.0.0.10" ) );
+    }
+
+    #[test]
+    fn skips_trusted_hops_from_the_right() {
+        assert_eq!( client( "10.0.0.10:4000", &[ "10.1.2.3, 10.0.0.11" ] ), ip( "10.1.2.3" ) );
+        assert_eq!( client( "10.0.0.10:4000", &[ "10.1.2.3", "10.0.0.11" ] ), ip( "10.1.2.3" ) );
+    }
+
+    #[test]
+    fn stops_at_the_first_untrusted_hop() {
+        // a caller prepending its own hops cannot pass for another client
+        assert_eq!( client( "10.0.0.10:4000", &[ "10.9.9.9, 172.16.0.4, 10.0.0.11" ] ), ip( "172.16.0.4" ) );
+        assert_eq!( client( "10.0.0.10:4000", &[ "10.0.0.12, 172.16.0.4" ] ), ip( "172.16.0.4" ) );
+    }
+
+    #[test]
+    fn stops_at_unparsable_hops() {
+        assert_eq!( client( "10.0.0.10:4000", &[ "10.1.2.3, unknown" ] ), ip( "10.0.0.10" ) );
+        assert_eq!( client( "10.0.0.10:4000", &[ "10.1.2.3:51234" ] ), ip( "10.0.0.10" ) );
+    }
+
+    #[test]
+    fn trusts_mapped_proxy_addresses() {
+        assert_eq!( client( "[::ffff:10.0.0.10]:4000", &[ "10.1.2.3" ] ), ip( "10.1.2.3" ) );
+    }
+}
@@ -0,0 +1,187 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use url::{form_urlencoded, HostAndPort, Url};
+use actix_http::http::{HeaderName, HeaderValue};
+use serde_derive::Deserialize;
+
+/// Class of upstream timeout a visa obliges the proxy to apply to the egress request.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeoutClass {
+    Short,
+    #[default]
+    Standard,
+    Long,
+}
+
+impl TimeoutClass {
+    pub fn duration( self ) -> Duration {
+        match self {
+            TimeoutClass::Short => Duration::from_secs( 5 ),
+            TimeoutClass::Standard => Duration::from_secs( 30 ),
+            TimeoutClass::Long => Duration::from_secs( 120 ),
+        }
+    }
+}
+
+/// Conditions attached to a granted visa that the proxy must honour when forwarding.
+#[derive(Clone, Debug, Default)]
+pub struct Obligations {
+    pub add_headers: Vec<(HeaderName, HeaderValue)>,
+    pub strip_headers: Vec<HeaderName>,
+    pub timeout: TimeoutClass,
+    pub rate_limit_bucket: Option<String>,
+    pub quota: Option<String>,
+}
+
+impl Obligations {
+    pub fn is_empty( &self ) -> bool {
+        self.add_headers.is_empty() &&
+            self.strip_headers.is_empty() &&
+            self.timeout == TimeoutClass::default() &&
+            self.rate_limit_bucket.is_none() &&
+            self.quota.is_none()
+    }
+
+    /// Adds another policy's obligations to these. Header changes accumulate; a timeout class,
+    /// rate-limit bucket and quota already set here take precedence.
+    pub fn merge( &mut self, other: &Obligations ) {
+        self.add_headers.extend( other.add_headers.iter().cloned() );
+        self.strip_headers.extend( other.strip_headers.iter().cloned() );
+
+        if self.timeout == TimeoutClass::default() {
+            self.timeout = other.timeout;
+        }
+
+        if self.rate_limit_bucket.is_none() {
+            self.rate_limit_bucket = other.rate_limit_bucket.clone();
+        }
+
+        if self.quota.is_none() {
+            self.quota = other.quota.clone();
+        }
+    }
+}
+
+/// The grant issued by `BorderControl` for a request: where it may go and under which obligations.
+#[derive(Clone, Debug)]
+pub struct Visa {
+    pub rule: String,
+    pub scheme: String,
+    pub destination: HostAndPort,
+    pub base_path: String,
+    pub obligations: Obligations,
+    /// The checked address the destination resolved to; when set the proxy connects there.
+    pub resolved: Option<SocketAddr>,
+    /// Name of the TLS profile for an `https` destination with its own TLS settings.
+    pub tls: Option<String>,
+    /// Leading part of the request path that selected the destination, not forwarded.
+    pub path_prefix: String,
+    /// Query parameter that selected the destination, not forwarded.
+    pub selector_parameter: Option<String>,
+}
+
+impl Visa {
+    /// The upstream URL for a request path and query under this visa. A visa pinned to a
+    /// resolved address is still addressed by host name, which TLS needs to verify the
+    /// destination; the proxy connects to the pinned address.
+    pub fn url_for( &self, path: &str, query: Option<&str> ) -> Url {
+        let mut url = Url::parse( &format!( "{}://{}", self.scheme, self.destination ) ).unwrap();
+        url.set_path( &format!( "{}{}", self.base_path, self.forwarded_path( path ) ) );
+        url.set_query( self.forwarded_query( query ).as_deref() );
+        url
+    }
+
+    /// The request path without the prefix that selected the destination.
+    pub fn forwarded_path<'a>( &self, path: &'a str ) -> &'a str {
+        path.get( self.path_prefix.len().. ).filter( |_| path.starts_with( &self.path_prefix ) ).unwrap_or( path )
+    }
+
+    fn forwarded_query( &self, query: Option<&str> ) -> Option<String> {
+        let ( query, parameter ) = match ( query, self.selector_parameter.as_ref() ) {
+            ( Some(query), Some(parameter) ) => ( query, parameter ),
+            ( query, None ) => return query.map( str::to_string ),
+            ( None, _ ) => return None,
+        };
+
+        let pairs = query.split( '&' )
+            .filter( |pair| form_urlencoded::parse( pair.as_bytes() ).next().is_none_or( |( name, _ )| name != *parameter ) )
+            .collect::<Vec<&str>>();
+
+        if pairs.is_empty() { None } else { Some( pairs.join( "&" ) ) }
+    }
+
+    /// The destination host, with its port unless that is the scheme's default.
+    pub fn authority( &self ) -> String {
+        let default_port = match self.scheme.as_str() {
+            "https" => 443,
+            _ => 80,
+        };
+
+        if self.destination.port == default_port {
+            self.destination.host.to_string()
+        } else {
+            self.destination.to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header( name: &'static str, value: &'static str ) -> ( HeaderName, HeaderValue ) {
+        ( HeaderName::from_static( name ), HeaderValue::from_static( value ) )
+    }
+
+    #[test]
+    fn merges_header_changes() {
+        let mut first = Obligations { add_headers: vec![ header( "x-team", "a" ) ], ..Obligations::default() };
+        let second = Obligations {
+            add_headers: vec![ header( "x-source", "b" ) ],
+            strip_headers: vec![ HeaderName::from_static( "cookie" ) ],
+            ..Obligations::default()
+        };
+
+        first.merge( &second );
+        assert_eq!( first.add_headers, vec![ header( "x-team", "a" ), header( "x-source", "b" ) ] );
+        assert_eq!( first.strip_headers, vec![ HeaderName::from_static( "cookie" ) ] );
+    }
+
+    #[test]
+    fn keeps_settings_already_made() {
+        let mut first = Obligations {
+            timeout: TimeoutClass::Short,
+            rate_limit_bucket: Some( "first".to_string() ),
+            ..Obligations::default()
+        };
+        let second = Obligations {
+            timeout: TimeoutClass::Long,
+            rate_limit_bucket: Some( "second".to_string() ),
+            quota: Some( "second".to_string() ),
+            ..Obligations::default()
+        };
+
+        first.merge( &second );
+        assert_eq!( first.timeout, TimeoutClass::Short );
+        assert_eq!( first.rate_limit_bucket.as_deref(), Some( "first" ) );
+        assert_eq!( first.quota.as_deref(), Some( "second" ) );
+    }
+
+    #[test]
+    fn takes_a_timeout_over_the_standard_one() {
+        let mut first = Obligations::default();
+        first.merge( &Obligations { timeout: TimeoutClass::Long, ..Obligations::default() } );
+        assert_eq!( first.timeout, TimeoutClass::Long );
+    }
+
+    #[test]
+    fn merging_nothing_changes_nothing() {
+        let mut obligations = Obligations::default();
+        obligations.merge( &Obligations::default() );
+        assert!( obligations.is_empty() );
+
+        obligations.merge( &Obligations { quota: Some( "q".to_string() ), ..Obligations::default() } );
+        assert!( !obligations.is_empty() );
+    }
+}
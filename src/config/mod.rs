@@ -0,0 +1,404 @@
+use log::{info, error};
+use clap::{value_t, AppSettings, Arg, ArgMatches, SubCommand};
+use url::{Host, Url};
+use std::io::{Result, ErrorKind::NotFound};
+use std::net::{IpAddr, SocketAddr, TcpListener, ToSocketAddrs};
+use std::path::PathBuf;
+use std::str::FromStr;
+use listenfd::ListenFd;
+use ipnet::IpNet;
+use crate::auth::Authenticator;
+use crate::border::address::parse_net;
+use crate::border::host_control::HostControlBuilder;
+use crate::border::learning::permissive_border;
+use crate::border::source::SourcePolicy;
+use crate::limits::quota::QuotaLedger;
+use crate::limits::rate::RateLimiter;
+use crate::listener::tls::ServerTls;
+use crate::upstream::TlsProfiles;
+
+pub mod policy_test;
+pub mod reload;
+pub mod settings;
+
+use self::policy_test::PolicyTest;
+use self::settings::{PolicySettings, Settings};
+
+pub const PROTOCOL: &str = "http";
+
+const CONFIG_FILE: &str = "config_file";
+const LISTEN_HOST: &str = "listen_host";
+const LISTEN_PORT: &str = "listen_port";
+const FORWARD_HOST: &str = "forward_host";
+const FORWARD_PORT: &str = "forward_port";
+const ALLOW_CIDR: &str = "allow_cidr";
+const SHADOW_CONFIG_FILE: &str = "shadow_config_file";
+const LEARN_FILE: &str = "learn_file";
+const POLICY: &str = "policy";
+const POLICY_TEST: &str = "test";
+const CASES_FILE: &str = "cases_file";
+const RESOLVE: &str = "resolve";
+const ADMIN_LISTEN: &str = "admin_listen";
+
+
+#[derive(Clone, PartialEq, Eq, Debug, Hash)]
+pub struct Config {
+    pub listen_socket_addresses: Vec<SocketAddr>,
+    /// Where the admin endpoints are served, if anywhere.
+    pub admin_socket_address: Option<SocketAddr>,
+    pub forward_url: Option<Url>,
+    pub allow_cidrs: Vec<IpNet>,
+    pub config_file: Option<PathBuf>,
+    pub settings: Option<Settings>,
+    pub shadow_config_file: Option<PathBuf>,
+    pub shadow_settings: Option<Settings>,
+    pub learn_file: Option<PathBuf>,
+    /// Set when run as `policy test` instead of as a proxy.
+    pub policy_test: Option<PolicyTest>,
+}
+
+impl Config {
+    pub fn from_args() -> Config {
+        let matches = arg_matches();
+        if let Some(test) = matches.subcommand_matches( POLICY ).and_then( |m| m.subcommand_matches( POLICY_TEST ) ) {
+            return Config::for_policy_test( test );
+        }
+
+        let lhost = matches.value_of( LISTEN_HOST );
+        let lport = Self::match_listen_port( &matches );
+
+        let socket = match (lhost, lport) {
+            (Some(h), Some(p)) => {
+                let ip = IpAddr::from_str(h ).unwrap();
+                Some( SocketAddr::new(ip, p ) )
+            },
+            _ => None
+        };
+
+        let config_file = matches.value_of( CONFIG_FILE ).map( PathBuf::from );
+        let settings = config_file.as_ref().map( load_or_exit );
+
+        let shadow_config_file = matches.value_of( SHADOW_CONFIG_FILE ).map( PathBuf::from );
+        let shadow_settings = shadow_config_file.as_ref().map( load_or_exit );
+
+        let furl = matches.value_of( FORWARD_HOST ).map( |fhost| {
+            let fport = value_t!( matches, FORWARD_PORT, u16 ).unwrap_or_else(|e| e.exit() );
+            Url::parse(
+                &format!(
+                    "{}://{}",
+                    PROTOCOL,
+                    (fhost, fport)
+                        .to_socket_addrs()
+                        .unwrap()
+                        .next()
+                        .unwrap()
+                )
+            ).unwrap()
+        } );
+
+        let allow_cidrs = matches.values_of( ALLOW_CIDR )
+            .map( |vs| {
+                vs.map( |v| parse_net( v ).unwrap_or_else( |e| {
+                    error!( "failed to parse ALLOW CIDR value {}: {}", v, e );
+                    eprintln!( "invalid --allow-cidr {}: {}", v, e );
+                    std::process::exit( 1 );
+                } ) ).collect()
+            } )
+            .unwrap_or_default();
+
+        let listen_socket_addresses = match ( socket, settings.as_ref() ) {
+            ( Some(s), _ ) => vec![ s ],
+            ( None, Some(settings) ) => {
+                settings.listeners.iter().map( |l| l.socket_address().unwrap() ).collect()
+            },
+            ( None, None ) => vec![],
+        };
+
+        let admin_socket_address = match matches.value_of( ADMIN_LISTEN ) {
+            Some(addr) => Some( SocketAddr::from_str( addr ).unwrap_or_else( |e| {
+                error!( "failed to parse ADMIN LISTEN value {}: {}", addr, e );
+                eprintln!( "invalid --admin-listen {}: {}", addr, e );
+                std::process::exit( 1 );
+            } ) ),
+            None => settings.as_ref()
+                .and_then( |s| s.admin_listener.as_ref() )
+                .map( |l| l.socket_address().unwrap() ),
+        };
+
+        Config {
+            listen_socket_addresses,
+            admin_socket_address,
+            forward_url: furl,
+            allow_cidrs,
+            config_file,
+            settings,
+            shadow_config_file,
+            shadow_settings,
+            learn_file: matches.value_of( LEARN_FILE ).map( PathBuf::from ),
+            policy_test: None,
+        }
+    }
+
+    fn for_policy_test( m: &ArgMatches ) -> Config {
+        let test = PolicyTest {
+            config_file: PathBuf::from( m.value_of( CONFIG_FILE ).unwrap() ),
+            cases_file: PathBuf::from( m.value_of( CASES_FILE ).unwrap() ),
+            resolve: m.is_present( RESOLVE ),
+        };
+
+        Config {
+            listen_socket_addresses: vec![],
+            admin_socket_address: None,
+            forward_url: None,
+            allow_cidrs: vec![],
+            config_file: Some( test.config_file.clone() ),
+            settings: None,
+            shadow_config_file: None,
+            shadow_settings: None,
+            learn_file: None,
+            policy_test: Some( test ),
+        }
+    }
+
+    fn match_listen_port( m: &ArgMatches ) -> Option<u16> {
+        if m.is_present( LISTEN_PORT ) {
+            let p = value_t!( m, LISTEN_PORT, u16 ).unwrap_or_else( |e| {
+                error!( "failed to parse LISTEN PORT value: {}", e );
+                e.exit();
+            } );
+
+            Some( p )
+        } else {
+            None
+        }
+    }
+
+    /// Logging filter configured in the settings file, if any.
+    pub fn log_filter( &self ) -> Option<&str> {
+        self.settings
+            .as_ref()
+            .and_then( |s| s.logging.filter.as_ref() )
+            .map( |f| f.as_str() )
+    }
+
+    /// Assembles the border policy from the settings file or, without one, the single forward
+    /// destination given on the command line, whose address is admitted by the address policy
+    /// even if internal. Ranges allowed with `--allow-cidr` are added to either policy.
+    pub fn border_builder( &self ) -> Result<HostControlBuilder> {
+        let builder = match ( self.settings.as_ref(), self.forward_url.as_ref() ) {
+            ( Some(settings), _ ) => settings.border_builder()?,
+            ( None, Some(url) ) => HostControlBuilder::new().with_default_destination( url.clone() ),
+            ( None, None ) if self.learn_file.is_some() => permissive_border(),
+            ( None, None ) => HostControlBuilder::new(),
+        };
+
+        self.with_allowed_cidrs( builder, self.settings.as_ref() )
+    }
+
+    /// Assembles the candidate border policy evaluated in shadow mode, if one is configured.
+    pub fn shadow_border_builder( &self ) -> Result<Option<HostControlBuilder>> {
+        match self.shadow_settings {
+            Some(ref settings) => Ok( Some( self.with_allowed_cidrs( settings.border_builder()?, Some( settings ) )? ) ),
+            None => Ok( None ),
+        }
+    }
+
+    fn with_allowed_cidrs( &self, builder: HostControlBuilder, settings: Option<&Settings> ) -> Result<HostControlBuilder> {
+        let mut address_policy = self.allow_cidrs.iter().fold(
+            settings.map( |s| s.address_policy.to_policy() ).transpose()?.unwrap_or_default(),
+            |policy, net| policy.allow( *net )
+        );
+
+        // the address given with --fhost is the operator's own choice, even if it is internal
+        let forward_ip = match self.forward_url.as_ref().and_then( Url::host ) {
+            Some(Host::Ipv4(ip)) if settings.is_none() => Some( IpAddr::V4( ip ) ),
+            Some(Host::Ipv6(ip)) if settings.is_none() => Some( IpAddr::V6( ip ) ),
+            _ => None,
+        };
+        if let Some(ip) = forward_ip {
+            address_policy = address_policy.allow( IpNet::from( ip ) );
+        }
+
+        Ok( builder.with_address_policy( address_policy ) )
+    }
+
+    /// How the border policy decides, as configured or by default.
+    pub fn policy_settings( &self ) -> PolicySettings {
+        self.settings.as_ref().map( |s| s.policy.clone() ).unwrap_or_default()
+    }
+
+    pub fn rate_limiter( &self ) -> Result<RateLimiter> {
+        self.settings.as_ref()
+            .map( |s| s.rate_limiter() )
+            .unwrap_or_else( || Ok( RateLimiter::new() ) )
+    }
+
+    pub fn quota_ledger( &self ) -> Result<Option<QuotaLedger>> {
+        self.settings.as_ref()
+            .map( |s| s.quota_ledger() )
+            .unwrap_or( Ok( None ) )
+    }
+
+    pub fn tls_profiles( &self ) -> Result<TlsProfiles> {
+        self.settings.as_ref()
+            .map( |s| s.tls_profiles() )
+            .unwrap_or_else( TlsProfiles::new )
+    }
+
+    pub fn authenticator( &self ) -> Result<Option<Authenticator>> {
+        self.settings.as_ref()
+            .map( |s| s.authenticator() )
+            .unwrap_or( Ok( None ) )
+    }
+
+    /// The proxies trusted to send a PROXY protocol header to the configured listener on `addr`,
+    /// if it expects one.
+    pub fn proxy_protocol( &self, addr: &SocketAddr ) -> Result<Option<SourcePolicy>> {
+        let settings = match self.settings.as_ref() {
+            Some(settings) => settings,
+            None => return Ok( None ),
+        };
+
+        let expected = settings.listeners
+            .iter()
+            .any( |l| l.proxy_protocol && l.socket_address().ok().as_ref() == Some( addr ) );
+
+        if expected { settings.source_addresses.to_policy().map( Some ) } else { Ok( None ) }
+    }
+
+    /// TLS settings of the configured listener on `addr`, if it terminates TLS.
+    pub fn listener_tls( &self, addr: &SocketAddr ) -> Option<ServerTls> {
+        self.settings.as_ref()?
+            .listeners
+            .iter()
+            .find( |l| l.socket_address().ok().as_ref() == Some( addr ) )
+            .and_then( |l| l.tls.as_ref() )
+            .map( |tls| tls.to_server_tls() )
+    }
+
+    pub fn tcp_listeners( &self ) -> Result<Vec<TcpListener>> {
+        if self.listen_socket_addresses.is_empty() {
+            info!( "listen socket address not specified, seeking system listener...");
+            let mut listenfd = ListenFd::from_env();
+            return listenfd.take_tcp_listener( 0 )
+                .and_then( |l| l.ok_or_else( || NotFound.into() ) )
+                .map( |l| {
+                    info!( "listening on {}", l.local_addr().unwrap() );
+                    vec![ l ]
+                } );
+        }
+
+        self.listen_socket_addresses
+            .iter()
+            .map( |sa| {
+                TcpListener::bind( sa ).inspect( |l| {
+                    info!( "listening on {}", l.local_addr().unwrap() );
+                } )
+            } )
+            .collect()
+    }
+}
+
+fn load_or_exit( path: &PathBuf ) -> Settings {
+    Settings::load( path ).unwrap_or_else( |e| {
+        error!( "failed to load config file: {}", e );
+        eprintln!( "{}", e );
+        std::process::exit( 1 );
+    } )
+}
+
+fn arg_matches<'a>() -> ArgMatches<'a> {
+    clap::App::new( "HTTP Egress Proxy" )
+        .setting( AppSettings::SubcommandsNegateReqs )
+        .arg(
+            Arg::with_name( CONFIG_FILE )
+                .takes_value( true )
+                .value_name( "CONFIG FILE" )
+                .short( "c" )
+                .long( "config" )
+                .required( false ),
+        )
+        .arg(
+            Arg::with_name( LISTEN_HOST )
+                .takes_value( true )
+                .value_name( "LISTEN HOST" )
+                .short( "H" )
+                .long( "lhost" )
+                .required( false ),
+        )
+        .arg(
+            Arg::with_name( LISTEN_PORT )
+                .takes_value( true )
+                .value_name( "LISTEN PORT" )
+                .short( "P" )
+                .long( "lport" )
+                .required( false ),
+        )
+        .arg(
+            Arg::with_name( ADMIN_LISTEN )
+                .takes_value( true )
+                .value_name( "ADMIN LISTEN ADDRESS" )
+                .long( "admin-listen" )
+                .help( "host:port serving the admin endpoints (quotas, learned config, policy simulation)" )
+                .required( false ),
+        )
+        .arg(
+            Arg::with_name( FORWARD_HOST )
+                .takes_value( true )
+                .value_name( "FORWARD HOST" )
+                .short( "h" )
+                .long( "fhost" )
+                .required_unless_one( &[ CONFIG_FILE, LEARN_FILE ] ),
+        )
+        .arg(
+            Arg::with_name( FORWARD_PORT )
+                .takes_value( true )
+                .value_name( "FORWARD PORT" )
+                .short( "p" )
+                .long( "fport" )
+                .required_unless_one( &[ CONFIG_FILE, LEARN_FILE ] ),
+        )
+        .arg(
+            Arg::with_name( ALLOW_CIDR )
+                .takes_value( true )
+                .multiple( true )
+                .number_of_values( 1 )
+                .value_name( "ALLOW CIDR" )
+                .long( "allow-cidr" )
+                .help( "address range exempt from the default deny of internal addresses" )
+                .required( false ),
+        )
+        .arg(
+            Arg::with_name( SHADOW_CONFIG_FILE )
+                .takes_value( true )
+                .value_name( "SHADOW CONFIG FILE" )
+                .long( "shadow-config" )
+                .help( "candidate border policy evaluated alongside the enforced one without blocking" )
+                .required( false ),
+        )
+        .arg(
+            Arg::with_name( LEARN_FILE )
+                .takes_value( true )
+                .value_name( "LEARNED CONFIG FILE" )
+                .long( "learn" )
+                .help( "record observed destinations and routes to a config file; without a policy every destination is allowed" )
+                .required( false ),
+        )
+        .subcommand(
+            SubCommand::with_name( POLICY )
+                .about( "border policy tools" )
+                .setting( AppSettings::SubcommandRequiredElseHelp )
+                .subcommand(
+                    SubCommand::with_name( POLICY_TEST )
+                        .about( "checks the policy in a config file against a table of request cases" )
+                        .arg( Arg::with_name( CONFIG_FILE ).value_name( "CONFIG FILE" ).required( true ) )
+                        .arg( Arg::with_name( CASES_FILE ).value_name( "CASES FILE" ).required( true ) )
+                        .arg(
+                            Arg::with_name( RESOLVE )
+                                .long( "resolve" )
+                                .help( "resolve destinations and check them against the address policy" )
+                        )
+                )
+        )
+        .get_matches()
+}
@@ -0,0 +1,250 @@
+use std::fmt;
+use std::io::Result;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use serde_derive::Deserialize;
+use actix_rt::System;
+use crate::border::{AsyncBorderControl, BorderControlBuilder};
+use crate::border::denial::DenialReason;
+use crate::border::simulation::SimulatedRequest;
+use crate::border::visa::Visa;
+use super::settings::{invalid, Settings};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Decision {
+    Allow,
+    Deny,
+}
+
+/// A request fixture and the decision expected for it, e.g.
+/// `{ name = "no writes", request { method = POST, url = "/v1/status" }, expect = deny, reason = method_not_allowed }`.
+/// Only the expectations given are checked.
+#[derive(Clone, Debug, Deserialize)]
+pub struct PolicyCase {
+    pub name: String,
+    pub request: SimulatedRequest,
+    pub expect: Decision,
+    /// Rule expected to grant the visa.
+    pub rule: Option<String>,
+    /// Expected `host:port` of the granted destination.
+    pub destination: Option<String>,
+    /// Expected denial code, e.g. `path_not_allowed`.
+    pub reason: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct PolicyCases {
+    cases: Vec<PolicyCase>,
+}
+
+/// The decision a policy made for a case.
+struct Outcome( std::result::Result<Visa, DenialReason> );
+
+impl Outcome {
+    fn satisfies( &self, case: &PolicyCase ) -> bool {
+        match ( &self.0, case.expect ) {
+            ( Ok(visa), Decision::Allow ) => {
+                case.rule.as_ref().is_none_or( |r| *r == visa.rule ) &&
+                    case.destination.as_ref().is_none_or( |d| *d == visa.destination.to_string() )
+            },
+            ( Err(reason), Decision::Deny ) => case.reason.as_ref().is_none_or( |r| r == reason.code() ),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt( &self, f: &mut fmt::Formatter ) -> fmt::Result {
+        match self.0 {
+            Ok(ref visa) => write!( f, "allow rule={} destination={}", visa.rule, visa.destination ),
+            Err(ref reason) => write!( f, "deny reason={} ({})", reason.code(), reason ),
+        }
+    }
+}
+
+struct Expected<'a>( &'a PolicyCase );
+
+impl<'a> fmt::Display for Expected<'a> {
+    fn fmt( &self, f: &mut fmt::Formatter ) -> fmt::Result {
+        match self.0.expect {
+            Decision::Allow => write!( f, "allow" )?,
+            Decision::Deny => write!( f, "deny" )?,
+        }
+
+        let expectations = [ ( "rule", &self.0.rule ), ( "destination", &self.0.destination ), ( "reason", &self.0.reason ) ];
+        for ( name, value ) in expectations.iter() {
+            if let Some(value) = value {
+                write!( f, " {}={}", name, value )?;
+            }
+        }
+        Ok( () )
+    }
+}
+
+/// Runs a table of request fixtures against the policy a config file describes, as
+/// `egress-proxy policy test <config> <cases>`. Destinations are not resolved unless asked,
+/// so the address policy is only checked with `--resolve`.
+#[derive(Clone, PartialEq, Eq, Debug, Hash)]
+pub struct PolicyTest {
+    pub config_file: PathBuf,
+    pub cases_file: PathBuf,
+    pub resolve: bool,
+}
+
+impl PolicyTest {
+    /// Prints a report of every case, returning whether all passed.
+    pub fn run( &self ) -> Result<bool> {
+        let settings = Settings::load( &self.config_file )?;
+        let mut builder = settings.border_builder()?;
+        if !self.resolve {
+            builder = builder.without_address_resolution();
+        }
+        let border: Rc<dyn AsyncBorderControl> = Rc::from( builder.build() );
+
+        let cases = load_cases( &self.cases_file )?;
+        let mut failed = 0;
+
+        // destinations are resolved on the system's resolver, as by a proxy worker
+        let mut system = System::new( "egress-proxy-policy-test" );
+        for case in cases.iter() {
+            // a case whose request cannot be built fails without stopping the others
+            let outcome = match system.block_on( case.request.decide( border.clone() ) ) {
+                Ok(simulation) => Outcome( simulation.decision ),
+                Err(e) => {
+                    failed += 1;
+                    println!( "FAIL  {}", case.name );
+                    println!( "      - {}", Expected( case ) );
+                    println!( "      + invalid request: {}", e );
+                    continue;
+                },
+            };
+
+            if outcome.satisfies( case ) {
+                println!( "ok    {}", case.name );
+            } else {
+                failed += 1;
+                println!( "FAIL  {}", case.name );
+                println!( "      - {}", Expected( case ) );
+                println!( "      + {}", outcome );
+            }
+        }
+
+        println!( "\n{} passed, {} failed", cases.len() - failed, failed );
+        Ok( failed == 0 )
+    }
+}
+
+fn load_cases( path: &Path ) -> Result<Vec<PolicyCase>> {
+    std::fs::metadata( path )
+        .map_err( |e| invalid( format!( "failed to load policy cases {}: {}", path.display(), e ) ) )?;
+
+    hocon::HoconLoader::new()
+        .load_file( path )
+        .and_then( |loader| loader.resolve::<PolicyCases>() )
+        .map( |cases| cases.cases )
+        .map_err( |e| invalid( format!( "failed to load policy cases {}: {:?}", path.display(), e ) ) )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use url::Url;
+    use crate::border::host_control::Destination;
+    use super::*;
+
+    fn case( expect: Decision ) -> PolicyCase {
+        PolicyCase {
+            name: "case".to_string(),
+            request: SimulatedRequest {
+                method: "GET".to_string(),
+                url: "/".to_string(),
+                headers: BTreeMap::new(),
+                caller: None,
+                identity: vec![],
+                attributes: BTreeMap::new(),
+            },
+            expect,
+            rule: None,
+            destination: None,
+            reason: None,
+        }
+    }
+
+    fn allowed() -> Outcome {
+        let destination: Destination = Url::parse( "https://vendor.example.com" ).unwrap().into();
+        Outcome( Ok( destination.visa( "vendor" ) ) )
+    }
+
+    fn denied() -> Outcome {
+        Outcome( Err( DenialReason::NoMatchingRule ) )
+    }
+
+    #[test]
+    fn satisfies_the_expected_decision() {
+        assert!( allowed().satisfies( &case( Decision::Allow ) ) );
+        assert!( denied().satisfies( &case( Decision::Deny ) ) );
+        assert!( !allowed().satisfies( &case( Decision::Deny ) ) );
+        assert!( !denied().satisfies( &case( Decision::Allow ) ) );
+    }
+
+    #[test]
+    fn checks_the_expected_rule_and_destination() {
+        let mut expected = case( Decision::Allow );
+        expected.rule = Some( "vendor".to_string() );
+        expected.destination = Some( "vendor.example.com:443".to_string() );
+        assert!( allowed().satisfies( &expected ) );
+
+        let mut other_rule = expected.clone();
+        other_rule.rule = Some( "other".to_string() );
+        assert!( !allowed().satisfies( &other_rule ) );
+
+        let mut other_destination = expected.clone();
+        other_destination.destination = Some( "vendor.example.com:80".to_string() );
+        assert!( !allowed().satisfies( &other_destination ) );
+    }
+
+    #[test]
+    fn checks_the_expected_reason() {
+        let mut expected = case( Decision::Deny );
+        expected.reason = Some( "no_matching_rule".to_string() );
+        assert!( denied().satisfies( &expected ) );
+
+        expected.reason = Some( "path_not_allowed".to_string() );
+        assert!( !denied().satisfies( &expected ) );
+    }
+
+    fn run( name: &str, cases: &str ) -> bool {
+        let dir = std::env::temp_dir().join( format!( "egress-proxy-policy-test-{}-{}", std::process::id(), name ) );
+        std::fs::create_dir_all( &dir ).unwrap();
+        let config_file = dir.join( "egress.conf" );
+        let cases_file = dir.join( "cases.conf" );
+        std::fs::write( &config_file, "destinations {\n  \"vendor.example.com\" {\n    scheme = https\n  }\n}\n" ).unwrap();
+        std::fs::write( &cases_file, cases ).unwrap();
+
+        let passed = PolicyTest { config_file, cases_file, resolve: false }.run().unwrap();
+        std::fs::remove_dir_all( &dir ).unwrap();
+        passed
+    }
+
+    const CASES: &str = r#"
+cases = [
+  { name = "vendor", request { url = "/v1", headers { "X-DESTINATION" = "vendor.example.com" } }, expect = allow, destination = "vendor.example.com:443" }
+  { name = "unknown", request { url = "/v1", headers { "X-DESTINATION" = "other.example.com" } }, expect = deny, reason = unknown_destination }
+"#;
+
+    #[test]
+    fn passes_when_every_case_is_satisfied() {
+        assert!( run( "passing", &format!( "{}]\n", CASES ) ) );
+    }
+
+    #[test]
+    fn fails_on_unsatisfied_cases() {
+        assert!( !run( "unsatisfied", &format!( "{}  {{ name = \"wrong\", request {{ url = \"/\" }}, expect = allow, rule = other }}\n]\n", CASES ) ) );
+    }
+
+    #[test]
+    fn fails_on_invalid_requests() {
+        assert!( !run( "invalid", &format!( "{}  {{ name = \"invalid\", request {{ method = \"GE T\", url = \"/\" }}, expect = deny }}\n]\n", CASES ) ) );
+    }
+}
@@ -0,0 +1,261 @@
+use std::fs;
+use std::io::Result;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+use lazy_static::*;
+use log::{error, info};
+use prometheus::IntCounter;
+use crate::auth::jwt::JwtValidator;
+use crate::border::BorderControlBuilder;
+use crate::border::policy::ReloadableBorder;
+use crate::listener::tls::ReloadableAcceptor;
+use crate::upstream::TlsProfiles;
+use super::settings::{invalid, Settings};
+
+lazy_static! {
+    pub static ref RELOAD_FAILURES_TOTAL: IntCounter = register_int_counter!(
+        opts!(
+            "egress_border_policy_reload_failures_total",
+            "Total number of border policy reloads rejected as invalid."
+        ).const_labels( crate::metrics::const_labels() )
+    )
+    .unwrap();
+
+    pub static ref JWKS_RELOAD_FAILURES_TOTAL: IntCounter = register_int_counter!(
+        opts!(
+            "egress_auth_jwks_reload_failures_total",
+            "Total number of JWKS reloads rejected as invalid."
+        ).const_labels( crate::metrics::const_labels() )
+    )
+    .unwrap();
+
+    pub static ref CERTIFICATE_RELOAD_FAILURES_TOTAL: IntCounter = register_int_counter!(
+        opts!(
+            "egress_listener_certificate_reload_failures_total",
+            "Total number of listener certificate reloads rejected as invalid."
+        ).const_labels( crate::metrics::const_labels() )
+    )
+    .unwrap();
+}
+
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs( 2 );
+
+/// Reloads the border policy from the config file on SIGHUP or when the file changes. The new
+/// policy is validated before it is swapped in; an invalid file leaves the active policy in force.
+/// Only the border policy is reloaded; listener and logging changes still require a restart.
+pub struct PolicyWatcher {
+    path: PathBuf,
+    border: ReloadableBorder,
+    interval: Duration,
+    tls_profiles: Option<TlsProfiles>,
+}
+
+impl PolicyWatcher {
+    pub fn new<P: Into<PathBuf>>( path: P, border: ReloadableBorder ) -> Self {
+        PolicyWatcher { path: path.into(), border, interval: DEFAULT_POLL_INTERVAL, tls_profiles: None, }
+    }
+
+    pub fn with_interval( mut self, interval: Duration ) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Rejects policies naming TLS profiles other than the ones requests are forwarded with,
+    /// as profiles are only loaded at startup.
+    pub fn with_tls_profiles( mut self, profiles: TlsProfiles ) -> Self {
+        self.tls_profiles = Some( profiles );
+        self
+    }
+
+    pub fn spawn( self ) -> Result<JoinHandle<()>> {
+        let hangup = Arc::new( AtomicBool::new( false ) );
+        signal_hook::flag::register( signal_hook::SIGHUP, hangup.clone() )?;
+
+        thread::Builder::new()
+            .name( "policy-watcher".to_string() )
+            .spawn( move || {
+                let mut last_modified = self.modified();
+
+                loop {
+                    thread::sleep( self.interval );
+
+                    let modified = self.modified();
+                    let signaled = hangup.swap( false, Ordering::SeqCst );
+                    if signaled || modified != last_modified {
+                        last_modified = modified;
+                        let trigger = if signaled { "SIGHUP" } else { "file change" };
+                        info!( "reloading border policy from {} on {}", self.path.display(), trigger );
+                        let _ = self.reload();
+                    }
+                }
+            } )
+    }
+
+    /// Loads, validates and activates the policy in the config file, returning the new version.
+    pub fn reload( &self ) -> Result<u64> {
+        Settings::load( &self.path )
+            .and_then( |settings| self.check_tls_profiles( &settings ).map( |_| settings ) )
+            .and_then( |settings| settings.border_builder() )
+            .map( |builder| self.border.replace( builder.build() ) )
+            .inspect_err( |e| {
+                RELOAD_FAILURES_TOTAL.inc();
+                error!(
+                    "rejected border policy from {}, keeping version {}: {}",
+                    self.path.display(), self.border.version(), e
+                );
+            } )
+    }
+
+    fn check_tls_profiles( &self, settings: &Settings ) -> Result<()> {
+        let profiles = match self.tls_profiles {
+            Some(ref profiles) => profiles,
+            None => return Ok( () ),
+        };
+
+        let unloaded = settings.destinations
+            .iter()
+            .find( |( name, dest )| dest.tls.is_some() && !profiles.contains( name ) );
+        match unloaded {
+            Some(( name, _ )) => Err( invalid( format!( "destination {} has TLS settings not loaded at startup; restart to apply them", name ) ) ),
+            None => Ok( () ),
+        }
+    }
+
+    fn modified( &self ) -> Option<SystemTime> {
+        fs::metadata( &self.path ).and_then( |m| m.modified() ).ok()
+    }
+}
+
+/// Reloads a TLS listener's certificate, key and client CA bundle when any of the files
+/// changes, so certificates can be rotated without a restart. Invalid files are rejected and
+/// the listener keeps serving the certificate it has.
+pub struct CertificateWatcher {
+    acceptor: ReloadableAcceptor,
+    interval: Duration,
+}
+
+impl CertificateWatcher {
+    pub fn new( acceptor: ReloadableAcceptor ) -> Self {
+        CertificateWatcher { acceptor, interval: DEFAULT_POLL_INTERVAL, }
+    }
+
+    pub fn with_interval( mut self, interval: Duration ) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn spawn( self ) -> Result<JoinHandle<()>> {
+        thread::Builder::new()
+            .name( "certificate-watcher".to_string() )
+            .spawn( move || {
+                let mut last_modified = self.modified();
+
+                loop {
+                    thread::sleep( self.interval );
+
+                    let modified = self.modified();
+                    if modified != last_modified {
+                        last_modified = modified;
+                        self.reload();
+                    }
+                }
+            } )
+    }
+
+    fn reload( &self ) {
+        match self.acceptor.reload() {
+            Ok(_) => info!( "reloaded listener certificate from {:?}", self.acceptor.files() ),
+            Err(e) => {
+                CERTIFICATE_RELOAD_FAILURES_TOTAL.inc();
+                error!( "rejected listener certificate, keeping the current one: {}", e );
+            },
+        }
+    }
+
+    fn modified( &self ) -> Vec<Option<SystemTime>> {
+        self.acceptor.files()
+            .iter()
+            .map( |path| fs::metadata( path ).and_then( |m| m.modified() ).ok() )
+            .collect()
+    }
+}
+
+/// Reloads the JWKS that caller tokens are validated against when the file changes, so the
+/// issuer's signing keys can be rotated without a restart. An invalid file is rejected and the
+/// current keys stay in use.
+pub struct KeySetWatcher {
+    validator: JwtValidator,
+    interval: Duration,
+}
+
+impl KeySetWatcher {
+    pub fn new( validator: JwtValidator ) -> Self {
+        KeySetWatcher { validator, interval: DEFAULT_POLL_INTERVAL, }
+    }
+
+    pub fn with_interval( mut self, interval: Duration ) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn spawn( self ) -> Result<JoinHandle<()>> {
+        thread::Builder::new()
+            .name( "jwks-watcher".to_string() )
+            .spawn( move || {
+                let mut last_modified = self.modified();
+
+                loop {
+                    thread::sleep( self.interval );
+
+                    let modified = self.modified();
+                    if modified != last_modified {
+                        last_modified = modified;
+                        self.reload();
+                    }
+                }
+            } )
+    }
+
+    fn reload( &self ) {
+        match self.validator.reload() {
+            Ok(_) => info!( "reloaded JWKS from {}", self.validator.path().display() ),
+            Err(e) => {
+                JWKS_RELOAD_FAILURES_TOTAL.inc();
+                error!( "rejected JWKS, keeping the current keys: {}", e );
+            },
+        }
+    }
+
+    fn modified( &self ) -> Option<SystemTime> {
+        fs::metadata( self.validator.path() ).and_then( |m| m.modified() ).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "destinations {\n  vendor { host = \"vendor.example.com\", scheme = https }\n}\n";
+    const CONFIG_WITH_TLS: &str = "destinations {\n  vendor { host = \"vendor.example.com\", scheme = https, tls { server_name = \"api.vendor.com\" } }\n}\n";
+
+    #[test]
+    fn rejects_policies_naming_tls_profiles_not_loaded() {
+        let path = std::env::temp_dir().join( format!( "egress-proxy-reload-{}.conf", std::process::id() ) );
+        let border = ReloadableBorder::new( crate::border::host_control::HostControlBuilder::new().build() );
+        let watcher = PolicyWatcher::new( path.clone(), border.clone() ).with_tls_profiles( TlsProfiles::new().unwrap() );
+
+        fs::write( &path, CONFIG ).unwrap();
+        assert_eq!( watcher.reload().unwrap(), 2 );
+
+        fs::write( &path, CONFIG_WITH_TLS ).unwrap();
+        assert!( watcher.reload().is_err() );
+        assert_eq!( border.version(), 2 );
+
+        // the shadow policy never forwards, so it may name any profile
+        assert!( PolicyWatcher::new( path.clone(), border.clone() ).reload().is_ok() );
+        fs::remove_file( &path ).unwrap();
+    }
+}
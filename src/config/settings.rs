@@ -0,0 +1,804 @@
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind::InvalidData, Result};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use log::warn;
+use serde_derive::Deserialize;
+use url::Host;
+use actix_http::http::{header, HeaderName, HeaderValue, Method};
+use ipnet::IpNet;
+use crate::auth::{Authenticator, DEFAULT_REALM};
+use crate::auth::basic::Credentials;
+use crate::auth::bearer::BearerTokens;
+use crate::auth::jwt::{JwtValidator, DEFAULT_LEEWAY};
+use crate::border::BorderControlBuilder;
+use crate::border::address::{parse_net, AddressPolicy};
+use crate::border::host_control::{Destination, HostControlBuilder, DEFAULT};
+use crate::border::matcher::HostPattern;
+use crate::border::route::RouteRule;
+use crate::border::selector::DestinationSelector;
+use crate::border::source::SourcePolicy;
+use crate::border::visa::TimeoutClass;
+use crate::limits::LimitScope;
+use crate::limits::quota::{Quota, QuotaLedger, QuotaLimit, QuotaPeriod};
+use crate::limits::rate::{Exhaustion, RateLimit, RateLimiter};
+use crate::listener::tls::{ClientAuth, ServerTls};
+use crate::middleware::proxy_filter::{FailMode, DEFAULT_DECISION_TIMEOUT};
+use crate::upstream::TlsProfiles;
+use crate::upstream::tls::{TlsProfile, TlsProfileBuilder, TlsVersion};
+use super::PROTOCOL;
+
+/// Declarative proxy configuration loaded from a HOCON (or JSON) file via `--config`.
+#[derive(Clone, PartialEq, Eq, Debug, Hash, Deserialize)]
+pub struct Settings {
+    #[serde(default)]
+    pub listeners: Vec<ListenerSettings>,
+
+    /// Serves the admin endpoints apart from proxied requests, e.g. on a loopback address.
+    pub admin_listener: Option<AdminListenerSettings>,
+
+    #[serde(default)]
+    pub destinations: BTreeMap<String, DestinationSettings>,
+
+    pub default_destination: Option<String>,
+
+    /// Ways requests name their destination, tried in order; `X-DESTINATION` without any.
+    #[serde(default)]
+    pub destination_selectors: Vec<SelectorSettings>,
+
+    #[serde(default)]
+    pub policy: PolicySettings,
+
+    /// Destinations each caller identity may use, by identity name, with `"*"` for any other
+    /// caller. Without any, every caller may use every destination.
+    #[serde(default)]
+    pub callers: BTreeMap<String, CallerSettings>,
+
+    /// Authenticates callers by their `Proxy-Authorization` header.
+    pub authentication: Option<AuthenticationSettings>,
+
+    #[serde(default)]
+    pub address_policy: AddressPolicySettings,
+
+    /// CIDR ranges callers may connect from, and the load balancers trusted to forward for them.
+    #[serde(default)]
+    pub source_addresses: SourceAddressSettings,
+
+    /// Token-bucket limits by bucket name. Destinations draw on the bucket named by their
+    /// `rate_limit_bucket`, or else on the bucket named after the destination.
+    #[serde(default)]
+    pub rate_limits: BTreeMap<String, RateLimitSettings>,
+
+    /// Request and byte quotas by name. Destinations draw on the quota named by their `quota`,
+    /// or else on the quota named after the destination.
+    #[serde(default)]
+    pub quotas: BTreeMap<String, QuotaSettings>,
+
+    /// Directory of the store persisting quota usage; usage is lost on exit without one.
+    pub quota_store: Option<PathBuf>,
+
+    #[serde(default)]
+    pub metrics: MetricsSettings,
+
+    #[serde(default)]
+    pub logging: LoggingSettings,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Hash, Deserialize)]
+pub struct ListenerSettings {
+    pub host: String,
+    pub port: u16,
+    /// Terminates TLS on the listener when set.
+    pub tls: Option<ListenerTlsSettings>,
+    /// Expect a PROXY protocol (v1 or v2) header on every connection, sent by one of the
+    /// `source_addresses.trusted_proxies`.
+    #[serde(default)]
+    pub proxy_protocol: bool,
+}
+
+impl ListenerSettings {
+    pub fn socket_address( &self ) -> Result<SocketAddr> {
+        socket_address( &self.host, self.port )
+    }
+}
+
+/// e.g. `{ host = "127.0.0.1", port = 9000 }`
+#[derive(Clone, PartialEq, Eq, Debug, Hash, Deserialize)]
+pub struct AdminListenerSettings {
+    pub host: String,
+    pub port: u16,
+}
+
+impl AdminListenerSettings {
+    pub fn socket_address( &self ) -> Result<SocketAddr> {
+        socket_address( &self.host, self.port )
+    }
+}
+
+fn socket_address( host: &str, port: u16 ) -> Result<SocketAddr> {
+    IpAddr::from_str( host )
+        .map( |ip| SocketAddr::new( ip, port ) )
+        .map_err( |e| invalid( format!( "invalid listener host {}: {}", host, e ) ) )
+}
+
+/// e.g. `{ cert = "/etc/egress-proxy/proxy.pem", key = "/etc/egress-proxy/proxy-key.pem", client_ca = "/etc/egress-proxy/clients-ca.pem" }`
+#[derive(Clone, PartialEq, Eq, Debug, Hash, Deserialize)]
+pub struct ListenerTlsSettings {
+    /// PEM certificate chain and private key served to callers.
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// PEM certificates client certificates are verified against; without them callers are
+    /// not asked for a certificate.
+    pub client_ca: Option<PathBuf>,
+    #[serde(default)]
+    pub client_auth: ClientAuth,
+}
+
+impl ListenerTlsSettings {
+    pub fn to_server_tls( &self ) -> ServerTls {
+        let tls = ServerTls::new( &self.cert, &self.key );
+        match self.client_ca {
+            Some(ref ca) => tls.with_client_ca( ca, self.client_auth ),
+            None => tls,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Hash, Deserialize)]
+pub struct DestinationSettings {
+    #[serde(default = "default_scheme")]
+    pub scheme: String,
+    /// Upstream host; defaults to the destination name. Requests matched by a wildcard or regex
+    /// destination are forwarded to the host they named.
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub base_path: String,
+    #[serde(default)]
+    pub add_headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub strip_headers: Vec<String>,
+    #[serde(default)]
+    pub timeout: TimeoutClass,
+    pub rate_limit_bucket: Option<String>,
+    pub quota: Option<String>,
+    /// Method and path rules; without any, every request to the destination is allowed.
+    #[serde(default)]
+    pub routes: Vec<RouteSettings>,
+    /// TLS options for an `https` destination; others use the system roots.
+    pub tls: Option<TlsSettings>,
+    /// Caller attributes, e.g. token `scopes`, a caller must have one of the listed values of.
+    #[serde(default)]
+    pub required_attributes: BTreeMap<String, Vec<String>>,
+    /// CIDR ranges callers must connect from to use this destination.
+    #[serde(default)]
+    pub allowed_sources: Vec<String>,
+}
+
+/// e.g. `{ ca_bundle = "/etc/egress/vendor-ca.pem", server_name = "api.vendor.com", min_version = "1.3" }`
+#[derive(Clone, PartialEq, Eq, Debug, Hash, Deserialize)]
+pub struct TlsSettings {
+    /// PEM certificates trusted instead of the system roots.
+    pub ca_bundle: Option<PathBuf>,
+    /// Name sent as SNI and verified against the certificate in place of the host.
+    pub server_name: Option<String>,
+    #[serde(default)]
+    pub min_version: TlsVersion,
+    /// PEM certificate chain and PKCS #8 PEM key presented to the destination.
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+}
+
+impl TlsSettings {
+    pub fn to_profile( &self, name: &str ) -> Result<TlsProfile> {
+        let context = |e: Error| invalid( format!( "invalid TLS settings for destination {}: {}", name, e ) );
+
+        let mut builder = TlsProfileBuilder::new().with_min_version( self.min_version );
+
+        if let Some(ref path) = self.ca_bundle {
+            builder = builder.with_ca_bundle( &read_file( path )? ).map_err( context )?;
+        }
+
+        if let Some(ref server_name) = self.server_name {
+            builder = builder.with_server_name( server_name );
+        }
+
+        builder = match ( self.client_cert.as_ref(), self.client_key.as_ref() ) {
+            ( Some(cert), Some(key) ) => {
+                builder.with_client_identity( &read_file( cert )?, &read_file( key )? ).map_err( context )?
+            },
+            ( None, None ) => builder,
+            _ => return Err( invalid( format!( "destination {} needs both client_cert and client_key", name ) ) ),
+        };
+
+        builder.build().map_err( context )
+    }
+}
+
+/// e.g. `{ methods = [GET, HEAD], path = "/v1/status/**" }`
+#[derive(Clone, PartialEq, Eq, Debug, Hash, Deserialize)]
+pub struct RouteSettings {
+    #[serde(default)]
+    pub methods: Vec<String>,
+    pub path: String,
+}
+
+impl RouteSettings {
+    pub fn to_route( &self ) -> Result<RouteRule> {
+        let methods = self.methods
+            .iter()
+            .map( |m| {
+                Method::from_bytes( m.to_uppercase().as_bytes() )
+                    .map_err( |e| invalid( format!( "invalid route method {}: {}", m, e ) ) )
+            } )
+            .collect::<Result<Vec<Method>>>()?;
+
+        Ok( RouteRule::new( &methods, &self.path ) )
+    }
+}
+
+fn default_scheme() -> String { PROTOCOL.to_string() }
+
+impl DestinationSettings {
+    pub fn to_destination( &self, name: &str ) -> Result<Destination> {
+        let pattern = HostPattern::parse( name )
+            .map_err( |e| invalid( format!( "invalid destination pattern {}: {}", name, e ) ) )?;
+
+        let host = match ( self.host.as_ref(), pattern ) {
+            ( Some(h), _ ) => {
+                Host::parse( h ).map_err( |e| invalid( format!( "invalid destination host {}: {}", h, e ) ) )?
+            },
+            // a pattern's own host is replaced by the host each request names
+            ( None, HostPattern::Suffix(_) ) | ( None, HostPattern::Regex(_) ) => Host::Domain( name.to_string() ),
+            ( None, HostPattern::Exact(h) ) => {
+                Host::parse( &h ).map_err( |e| invalid( format!( "invalid destination host {}: {}", h, e ) ) )?
+            },
+        };
+        if self.scheme != "http" && self.scheme != "https" {
+            return Err( invalid( format!( "destination {} has unsupported scheme {}", name, self.scheme ) ) );
+        }
+        let port = self.port.unwrap_or_else( || if self.scheme == "https" { 443 } else { 80 } );
+
+        let mut dest = Destination::from( (host, port) )
+            .with_scheme( &self.scheme )
+            .with_base_path( &self.base_path )
+            .with_timeout( self.timeout );
+
+        for ( name, value ) in self.add_headers.iter() {
+            dest = dest.with_header( header_name( name )?, header_value( value )? );
+        }
+
+        for name in self.strip_headers.iter() {
+            dest = dest.strip_header( header_name( name )? );
+        }
+
+        if let Some(ref bucket) = self.rate_limit_bucket {
+            dest = dest.with_rate_limit_bucket( bucket );
+        }
+
+        if let Some(ref quota) = self.quota {
+            dest = dest.with_quota( quota );
+        }
+
+        for route in self.routes.iter() {
+            dest = dest.with_route( route.to_route()? );
+        }
+
+        if self.tls.is_some() {
+            dest = dest.with_tls_profile( name );
+        }
+
+        for ( attribute, values ) in self.required_attributes.iter() {
+            dest = dest.with_required_attribute( attribute, values );
+        }
+
+        for net in self.allowed_sources.iter() {
+            dest = dest.with_allowed_source( cidr( net )? );
+        }
+
+        Ok( dest )
+    }
+}
+
+/// e.g. `{ destinations = [ vendor, "*.payments.com" ] }`
+#[derive(Clone, PartialEq, Eq, Debug, Hash, Deserialize)]
+pub struct CallerSettings {
+    #[serde(default)]
+    pub destinations: Vec<String>,
+}
+
+/// e.g. `{ realm = egress, credentials_file = "/etc/egress-proxy/credentials", tokens_file = "/etc/egress-proxy/tokens" }`
+#[derive(Clone, PartialEq, Eq, Debug, Hash, Deserialize)]
+pub struct AuthenticationSettings {
+    #[serde(default = "default_realm")]
+    pub realm: String,
+    /// `user:hash` lines of PBKDF2-SHA256 password hashes, checked for Basic credentials.
+    pub credentials_file: Option<PathBuf>,
+    /// `name:sha256` lines of token digests, checked for Bearer tokens.
+    pub tokens_file: Option<PathBuf>,
+    /// Signed JWTs, checked against the issuer's JWKS.
+    pub jwt: Option<JwtSettings>,
+    /// Refuse callers that neither send credentials nor present a client certificate.
+    #[serde(default = "default_required")]
+    pub required: bool,
+}
+
+/// e.g. `{ jwks_file = "/etc/egress-proxy/jwks.json", issuer = "https://auth.example.com", audience = egress-proxy }`
+#[derive(Clone, PartialEq, Eq, Debug, Hash, Deserialize)]
+pub struct JwtSettings {
+    pub jwks_file: PathBuf,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    /// Clock skew tolerated on `exp` and `nbf`, in seconds.
+    #[serde(default = "default_leeway_secs")]
+    pub leeway_secs: u64,
+    /// Request header carrying the token as `Bearer <jwt>`; it is not forwarded. Values that are
+    /// not a Bearer JWT, e.g. credentials meant for the destination, are forwarded untouched.
+    #[serde(default = "default_jwt_header")]
+    pub header: String,
+}
+
+impl JwtSettings {
+    pub fn to_validator( &self ) -> Result<JwtValidator> {
+        let mut validator = JwtValidator::load( &self.jwks_file )
+            .map_err( |e| load_error( &self.jwks_file, e ) )?
+            .with_leeway( Duration::from_secs( self.leeway_secs ) );
+
+        match self.issuer {
+            Some(ref issuer) => validator = validator.with_issuer( issuer ),
+            None => warn!( "JWTs from {} are accepted from any issuer; set jwt.issuer", self.jwks_file.display() ),
+        }
+
+        match self.audience {
+            Some(ref audience) => validator = validator.with_audience( audience ),
+            None => warn!( "JWTs from {} are accepted for any audience; set jwt.audience", self.jwks_file.display() ),
+        }
+
+        Ok( validator )
+    }
+}
+
+fn default_leeway_secs() -> u64 { DEFAULT_LEEWAY.as_secs() }
+
+fn default_jwt_header() -> String { header::AUTHORIZATION.as_str().to_string() }
+
+fn default_realm() -> String { DEFAULT_REALM.to_string() }
+
+fn default_required() -> bool { true }
+
+impl AuthenticationSettings {
+    pub fn to_authenticator( &self ) -> Result<Authenticator> {
+        if self.credentials_file.is_none() && self.tokens_file.is_none() && self.jwt.is_none() {
+            return Err( invalid( "authentication needs a credentials_file, a tokens_file or jwt settings" ) );
+        }
+
+        let mut authenticator = Authenticator::new().with_realm( &self.realm );
+
+        if let Some(ref path) = self.credentials_file {
+            authenticator = authenticator.with_credentials( Credentials::load( path ).map_err( |e| load_error( path, e ) )? );
+        }
+
+        if let Some(ref path) = self.tokens_file {
+            authenticator = authenticator.with_bearer_tokens( BearerTokens::load( path ).map_err( |e| load_error( path, e ) )? );
+        }
+
+        if let Some(ref jwt) = self.jwt {
+            authenticator = authenticator.with_jwt( header_name( &jwt.header )?, jwt.to_validator()? );
+        }
+
+        Ok( if self.required { authenticator } else { authenticator.optional() } )
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Hash, Default, Deserialize)]
+pub struct PolicySettings {
+    /// A closed policy denies every request regardless of the destinations configured.
+    #[serde(default)]
+    pub closed: bool,
+    /// How requests are decided when the policy cannot decide within `decision_timeout_ms`:
+    /// `closed` refuses them, `open` decides by policies needing no I/O and refuses the rest.
+    #[serde(default)]
+    pub fail_mode: FailModeSettings,
+    /// Longest the policy may take to decide, e.g. while resolving the destination; defaults to
+    /// 5 seconds.
+    pub decision_timeout_ms: Option<u64>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailModeSettings {
+    #[default]
+    Closed,
+    Open,
+}
+
+impl PolicySettings {
+    pub fn fail_mode( &self ) -> FailMode {
+        match self.fail_mode {
+            FailModeSettings::Closed => FailMode::Closed,
+            FailModeSettings::Open => FailMode::Open,
+        }
+    }
+
+    pub fn decision_timeout( &self ) -> Result<Duration> {
+        match self.decision_timeout_ms {
+            Some(0) => Err( invalid( "policy.decision_timeout_ms must be positive" ) ),
+            Some(ms) => Ok( Duration::from_millis( ms ) ),
+            None => Ok( DEFAULT_DECISION_TIMEOUT ),
+        }
+    }
+}
+
+/// CIDR ranges checked against the addresses destinations resolve to.
+#[derive(Clone, PartialEq, Eq, Debug, Hash, Deserialize)]
+pub struct AddressPolicySettings {
+    /// Deny loopback, private, link-local and other internal ranges.
+    #[serde(default = "default_deny_internal")]
+    pub deny_internal: bool,
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+fn default_deny_internal() -> bool { true }
+
+impl Default for AddressPolicySettings {
+    fn default() -> Self {
+        AddressPolicySettings { deny_internal: true, allow: Vec::new(), deny: Vec::new(), }
+    }
+}
+
+impl AddressPolicySettings {
+    pub fn to_policy( &self ) -> Result<AddressPolicy> {
+        let mut policy = if self.deny_internal { AddressPolicy::default() } else { AddressPolicy::open() };
+
+        for net in self.allow.iter() {
+            policy = policy.allow( cidr( net )? );
+        }
+
+        for net in self.deny.iter() {
+            policy = policy.deny( cidr( net )? );
+        }
+
+        Ok( policy )
+    }
+}
+
+/// e.g. `{ allow = [ "10.20.0.0/16" ], trusted_proxies = [ "10.0.0.10", "10.0.0.11" ] }`
+#[derive(Clone, PartialEq, Eq, Debug, Hash, Default, Deserialize)]
+pub struct SourceAddressSettings {
+    /// Without any ranges every caller is admitted.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Load balancers whose `X-Forwarded-For` and PROXY protocol addresses are believed.
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+}
+
+impl SourceAddressSettings {
+    pub fn to_policy( &self ) -> Result<SourcePolicy> {
+        let mut policy = SourcePolicy::new();
+
+        for net in self.allow.iter() {
+            policy = policy.allow( cidr( net )? );
+        }
+
+        for net in self.trusted_proxies.iter() {
+            policy = policy.trust_proxy( cidr( net )? );
+        }
+
+        Ok( policy )
+    }
+}
+
+/// One of `{ header = "X-Egress-Target" }`, `{ path_segment = true }`,
+/// `{ query_parameter = egress }` or `{ subdomain_of = "egress.internal" }`.
+#[derive(Clone, PartialEq, Eq, Debug, Hash, Deserialize)]
+pub struct SelectorSettings {
+    pub header: Option<String>,
+    #[serde(default)]
+    pub path_segment: bool,
+    pub query_parameter: Option<String>,
+    /// The proxy's own host name, under which subdomains name destinations.
+    pub subdomain_of: Option<String>,
+}
+
+impl SelectorSettings {
+    pub fn to_selector( &self ) -> Result<DestinationSelector> {
+        let mut selectors = Vec::new();
+        if let Some(ref name) = self.header {
+            selectors.push( DestinationSelector::Header( header_name( name )? ) );
+        }
+        if self.path_segment {
+            selectors.push( DestinationSelector::PathSegment );
+        }
+        if let Some(ref name) = self.query_parameter {
+            selectors.push( DestinationSelector::QueryParameter( name.clone() ) );
+        }
+        if let Some(ref domain) = self.subdomain_of {
+            selectors.push( DestinationSelector::Subdomain( domain.trim_start_matches( '.' ).to_lowercase() ) );
+        }
+
+        match selectors.len() {
+            1 => Ok( selectors.remove( 0 ) ),
+            _ => Err( invalid( "a destination selector sets exactly one of header, path_segment, query_parameter or subdomain_of" ) ),
+        }
+    }
+}
+
+/// e.g. `{ requests = 100, period_secs = 60, burst = 20, per = caller, on_exhausted = queue, max_wait_ms = 500 }`
+#[derive(Clone, PartialEq, Eq, Debug, Hash, Deserialize)]
+pub struct RateLimitSettings {
+    /// Sustained rate of `requests` per `period_secs` seconds.
+    pub requests: u32,
+    #[serde(default = "default_period_secs")]
+    pub period_secs: u64,
+    /// Requests allowed at once on a full bucket; defaults to `requests`.
+    pub burst: Option<u32>,
+    #[serde(default)]
+    pub per: LimitScope,
+    #[serde(default)]
+    pub on_exhausted: ExhaustionSettings,
+    /// Longest a request is queued for a token when exhausted buckets queue requests.
+    #[serde(default)]
+    pub max_wait_ms: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExhaustionSettings {
+    #[default]
+    Reject,
+    Queue,
+}
+
+fn default_period_secs() -> u64 { 1 }
+
+impl RateLimitSettings {
+    pub fn to_rate_limit( &self, name: &str ) -> Result<RateLimit> {
+        let burst = self.burst.unwrap_or( self.requests );
+        if self.requests == 0 || self.period_secs == 0 || burst == 0 {
+            return Err( invalid( format!( "rate limit {} must allow at least one request per period", name ) ) );
+        }
+
+        let on_exhausted = match self.on_exhausted {
+            ExhaustionSettings::Reject => Exhaustion::Reject,
+            ExhaustionSettings::Queue => Exhaustion::Queue { max_wait: Duration::from_millis( self.max_wait_ms ) },
+        };
+
+        Ok(
+            RateLimit::new( f64::from( self.requests ) / self.period_secs as f64, burst )
+                .per( self.per )
+                .on_exhausted( on_exhausted )
+        )
+    }
+}
+
+/// e.g. `{ per = caller, daily { requests = 1000 }, monthly { bytes_out = 1000000000 } }`
+#[derive(Clone, PartialEq, Eq, Debug, Hash, Deserialize)]
+pub struct QuotaSettings {
+    #[serde(default)]
+    pub per: LimitScope,
+    pub daily: Option<QuotaLimitSettings>,
+    pub monthly: Option<QuotaLimitSettings>,
+}
+
+/// Allowances for one period; bytes out are sent to the destination, bytes in received from it.
+#[derive(Clone, PartialEq, Eq, Debug, Hash, Deserialize)]
+pub struct QuotaLimitSettings {
+    pub requests: Option<u64>,
+    pub bytes_in: Option<u64>,
+    pub bytes_out: Option<u64>,
+}
+
+impl QuotaLimitSettings {
+    fn to_limit( &self, period: QuotaPeriod ) -> QuotaLimit {
+        QuotaLimit { period, requests: self.requests, bytes_in: self.bytes_in, bytes_out: self.bytes_out, }
+    }
+}
+
+impl QuotaSettings {
+    pub fn to_quota( &self, name: &str ) -> Result<Quota> {
+        let limits = [ ( QuotaPeriod::Daily, &self.daily ), ( QuotaPeriod::Monthly, &self.monthly ) ];
+
+        let quota = limits.iter()
+            .filter_map( |( period, limit )| limit.as_ref().map( |l| l.to_limit( *period ) ) )
+            .fold( Quota::new( self.per ), |quota, limit| quota.with_limit( limit ) );
+
+        if quota.limits.is_empty() {
+            return Err( invalid( format!( "quota {} has neither a daily nor a monthly allowance", name ) ) );
+        }
+
+        Ok( quota )
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Hash, Default, Deserialize)]
+pub struct MetricsSettings {
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Hash, Default, Deserialize)]
+pub struct LoggingSettings {
+    pub filter: Option<String>,
+}
+
+impl Settings {
+    pub fn load<P: AsRef<Path>>( path: P ) -> Result<Settings> {
+        let path = path.as_ref();
+        hocon::HoconLoader::new()
+            .load_file( path )
+            .and_then( |loader| loader.resolve::<Settings>() )
+            .map_err( |e| invalid( format!( "failed to load config file {}: {:?}", path.display(), e ) ) )
+            .and_then( |settings| settings.validate().map( |_| settings ) )
+    }
+
+    fn validate( &self ) -> Result<()> {
+        if let Some(ref name) = self.default_destination {
+            if !self.destinations.contains_key( name ) {
+                return Err( invalid( format!( "default destination {} is not a configured destination", name ) ) );
+            }
+        }
+
+        for listener in self.listeners.iter() {
+            let addr = listener.socket_address()?;
+            if listener.proxy_protocol && self.source_addresses.trusted_proxies.is_empty() {
+                return Err( invalid( format!( "listener {} expects the PROXY protocol but no source_addresses.trusted_proxies are configured", addr ) ) );
+            }
+            if let Some(ref tls) = listener.tls {
+                tls.to_server_tls().acceptor()?;
+            }
+        }
+
+        if let Some(ref admin) = self.admin_listener {
+            let addr = admin.socket_address()?;
+            if self.listeners.iter().any( |l| l.socket_address().ok() == Some( addr ) ) {
+                return Err( invalid( format!( "admin listener {} is also a proxy listener", addr ) ) );
+            }
+        }
+
+        for ( name, dest ) in self.destinations.iter() {
+            dest.to_destination( name )?;
+        }
+
+        for selector in self.destination_selectors.iter() {
+            selector.to_selector()?;
+        }
+
+        for ( caller, settings ) in self.callers.iter() {
+            if let Some(unknown) = settings.destinations.iter().find( |d| !self.destinations.contains_key( *d ) ) {
+                return Err( invalid( format!( "caller {} names unknown destination {}", caller, unknown ) ) );
+            }
+        }
+
+        self.policy.decision_timeout()?;
+        self.address_policy.to_policy()?;
+        self.source_addresses.to_policy()?;
+        self.rate_limiter()?;
+
+        for ( name, quota ) in self.quotas.iter() {
+            quota.to_quota( name )?;
+        }
+
+        self.tls_profiles()?;
+        self.authenticator()?;
+        Ok( () )
+    }
+
+    /// Builds the authenticator described by these settings, if authentication is configured.
+    pub fn authenticator( &self ) -> Result<Option<Authenticator>> {
+        self.authentication.as_ref().map( |a| a.to_authenticator() ).transpose()
+    }
+
+    /// Builds the TLS profiles of destinations with TLS settings.
+    pub fn tls_profiles( &self ) -> Result<TlsProfiles> {
+        let mut profiles = TlsProfiles::new()?;
+        for ( name, dest ) in self.destinations.iter() {
+            if let Some(ref tls) = dest.tls {
+                profiles = profiles.with_profile( name, tls.to_profile( name )? );
+            }
+        }
+
+        Ok( profiles )
+    }
+
+    /// Builds the rate limiter described by these settings.
+    pub fn rate_limiter( &self ) -> Result<RateLimiter> {
+        let mut limiter = RateLimiter::new();
+        for ( name, limit ) in self.rate_limits.iter() {
+            limiter = limiter.with_limit( name, limit.to_rate_limit( name )? );
+        }
+
+        Ok( limiter )
+    }
+
+    /// Opens the quota ledger described by these settings, if any quotas are configured.
+    pub fn quota_ledger( &self ) -> Result<Option<QuotaLedger>> {
+        if self.quotas.is_empty() {
+            return Ok( None );
+        }
+
+        let mut ledger = match self.quota_store {
+            Some(ref path) => QuotaLedger::open( path )?,
+            None => {
+                warn!( "no quota_store configured; quota usage will reset on restart" );
+                QuotaLedger::temporary()?
+            },
+        };
+
+        for ( name, quota ) in self.quotas.iter() {
+            ledger = ledger.with_quota( name, quota.to_quota( name )? );
+        }
+
+        Ok( Some( ledger ) )
+    }
+
+    /// Assembles the border policy described by these settings.
+    pub fn border_builder( &self ) -> Result<HostControlBuilder> {
+        let mut builder = HostControlBuilder::new()
+            .with_address_policy( self.address_policy.to_policy()? )
+            .with_source_policy( self.source_addresses.to_policy()? );
+        for selector in self.destination_selectors.iter() {
+            builder = builder.with_destination_selector( selector.to_selector()? );
+        }
+        if self.policy.closed {
+            return Ok( builder );
+        }
+
+        for ( name, dest ) in self.destinations.iter() {
+            builder = builder.with_named_destination( name, dest.to_destination( name )? );
+        }
+
+        if let Some(ref name) = self.default_destination {
+            let settings = &self.destinations[name];
+            let mut dest = settings.to_destination( name )?;
+            // requests reaching the default share the named destination's limits
+            if settings.rate_limit_bucket.is_none() {
+                dest = dest.with_rate_limit_bucket( name );
+            }
+            if settings.quota.is_none() {
+                dest = dest.with_quota( name );
+            }
+            builder = builder.with_default_destination( dest );
+        }
+
+        for ( caller, settings ) in self.callers.iter() {
+            let mut destinations = settings.destinations.clone();
+            // requests reaching the default are allowed to callers allowed the named destination
+            if self.default_destination.as_ref().is_some_and( |d| destinations.contains( d ) ) {
+                destinations.push( DEFAULT.to_string() );
+            }
+            builder = builder.with_caller_destinations( caller, &destinations );
+        }
+
+        Ok( builder )
+    }
+}
+
+fn read_file( path: &Path ) -> Result<Vec<u8>> {
+    std::fs::read( path ).map_err( |e| load_error( path, e ) )
+}
+
+fn load_error( path: &Path, e: Error ) -> Error {
+    invalid( format!( "failed to read {}: {}", path.display(), e ) )
+}
+
+fn cidr( net: &str ) -> Result<IpNet> {
+    parse_net( net ).map_err( |e| invalid( format!( "invalid CIDR range {}: {}", net, e ) ) )
+}
+
+fn header_name( name: &str ) -> Result<HeaderName> {
+    HeaderName::from_str( name ).map_err( |e| invalid( format!( "invalid header name {}: {}", name, e ) ) )
+}
+
+fn header_value( value: &str ) -> Result<HeaderValue> {
+    HeaderValue::from_str( value ).map_err( |e| invalid( format!( "invalid header value {}: {}", value, e ) ) )
+}
+
+pub(crate) fn invalid<M: Into<String>>( message: M ) -> Error {
+    Error::new( InvalidData, message.into() )
+}
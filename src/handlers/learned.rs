@@ -0,0 +1,10 @@
+use actix_web::{web::Data, HttpResponse};
+use crate::border::learning::Learner;
+
+/// Renders the destinations and routes observed in learning mode as a config file.
+pub fn config( learner: Data<Option<Learner>> ) -> HttpResponse {
+    match learner.as_ref() {
+        Some(learner) => HttpResponse::Ok().content_type( "text/plain" ).body( learner.to_config() ),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
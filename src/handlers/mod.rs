@@ -1,2 +1,5 @@
 pub mod proxy;
-pub mod metrics;
\ No newline at end of file
+pub mod learned;
+pub mod metrics;
+pub mod quotas;
+pub mod simulate;
\ No newline at end of file
@@ -1,20 +1,25 @@
-use actix_web::{client::Client, Error, HttpRequest, HttpResponse};
+use actix_web::{Error, HttpRequest, HttpResponse};
 use actix_web::web::{Data, Payload};
+use actix_http::error::{ErrorBadGateway, ErrorInternalServerError};
 use url::Url;
-use futures::Future;
+use futures::{Future, future::{err, ok, Either}};
 use prometheus::HistogramVec;
 use log::{debug, info};
-use actix_http::http::{HeaderName, header, HeaderValue};
+use actix_http::http::{HeaderName, header, HeaderValue, Method};
 use stopwatch::Stopwatch;
 use core::borrow::{BorrowMut, Borrow};
 use std::time::Duration;
 use crate::metrics::MetricsCollection;
+use crate::border::visa::Visa;
+use crate::upstream::UpstreamClients;
+
+static HDR_PROXY_CONNECTION: &str = "Proxy-Connection";
 
 fn include_header( h: &HeaderName ) -> bool {
-    match h {
-        &header::CONNECTION => false,
-        &header::CONTENT_LENGTH => false,
-//        &header::CONTENT_ENCODING => false,
+    match *h {
+        header::CONNECTION => false,
+        header::CONTENT_LENGTH => false,
+//        header::CONTENT_ENCODING => false,
         _ => true,
     }
 }
@@ -22,16 +27,48 @@ fn include_header( h: &HeaderName ) -> bool {
 pub fn forward(
     req: HttpRequest,
     payload: Payload,
-    url: Data<Url>,
-    client: Data<Client>,
+    clients: Data<UpstreamClients>,
     metrics_collection: Data<MetricsCollection>,
 ) -> impl Future<Item = HttpResponse, Error = Error> {
-    let mut new_url = url.get_ref().clone();
-    new_url.set_path( req.uri().path() );
-    new_url.set_query( req.uri().query() );
+    let visa = req.extensions().get::<Visa>().cloned();
+    let visa = match visa {
+        Some(v) => v,
+        None => {
+            return Either::A(
+                err( ErrorInternalServerError( "no egress visa granted for request" ) )
+            );
+        }
+    };
+
+    // granted tunnels are opened by the listener once answered
+    if req.method() == Method::CONNECT {
+        return Either::A( ok( HttpResponse::Ok().finish() ) );
+    }
+
+    let new_url = visa.url_for( req.uri().path(), req.uri().query() );
 
     info!( "REQUEST: {:?}", req );
-    let forwarded_req = client.request_from( new_url.as_str(), req.head() );
+    let forwarded_req = match clients.request_from( &visa, &new_url, req.head() ) {
+        Some(forwarded_req) => forwarded_req,
+        None => return Either::A( err( ErrorBadGateway( "destination TLS profile is not loaded" ) ) ),
+    };
+    let mut forwarded_req = forwarded_req
+        .set_header( header::HOST, visa.authority() )
+        .timeout( visa.obligations.timeout.duration() );
+
+    // credentials for the proxy are never passed on to the destination, nor is the
+    // hop-by-hop header clients send their HTTP proxy
+    forwarded_req.headers_mut().remove( header::PROXY_AUTHORIZATION );
+    forwarded_req.headers_mut().remove( HDR_PROXY_CONNECTION );
+
+    for name in visa.obligations.strip_headers.iter() {
+        forwarded_req.headers_mut().remove( name );
+    }
+
+    for ( name, value ) in visa.obligations.add_headers.iter() {
+        forwarded_req = forwarded_req.set_header( name.clone(), value.clone() );
+    }
+
     let forwarded_req = if let Some(addr) = req.head().peer_addr {
         forwarded_req.header( "x-forwarded-for", format!( "{}", addr.ip() ) )
     } else {
@@ -49,7 +86,7 @@ pub fn forward(
 
     let mut request_timer = Stopwatch::start_new();
 
-    forwarded_req
+    let response = forwarded_req
         .no_decompress()
         .send_stream( payload )
         .map_err( Error::from )
@@ -69,5 +106,7 @@ pub fn forward(
                 }
 
             client_resp.streaming( res )
-        } )
+        } );
+
+    Either::B( response )
 }
\ No newline at end of file
@@ -0,0 +1,13 @@
+use actix_web::{web::Data, HttpResponse};
+use serde_json::json;
+use crate::limits::quota::QuotaLedger;
+
+/// Reports the usage of every quota in its current windows.
+pub fn usage( ledger: Data<Option<QuotaLedger>> ) -> HttpResponse {
+    let reports = ledger.as_ref()
+        .as_ref()
+        .map( |l| l.report() )
+        .unwrap_or_default();
+
+    HttpResponse::Ok().json( json!( { "quotas": reports } ) )
+}
@@ -0,0 +1,142 @@
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use actix_web::{web::{Data, Json}, Error, HttpResponse, ResponseError};
+use futures::Future;
+use serde_json::{json, Value};
+use crate::border::denial::PROBLEM_JSON;
+use crate::border::policy::ReloadableBorder;
+use crate::border::simulation::SimulatedRequest;
+use crate::border::visa::{Obligations, Visa};
+
+/// Reports the decision the active border policy makes for a described request: the matched
+/// rule, destination and obligations of a granted visa, or the reason for a denial.
+pub fn simulate( border: Data<ReloadableBorder>, sim: Json<SimulatedRequest> ) -> impl Future<Item = HttpResponse, Error = Error> {
+    let version = border.version();
+    sim.decide( Rc::new( border.get_ref().clone() ) ).then( move |simulation| {
+        let simulation = match simulation {
+            Ok(simulation) => simulation,
+            Err(e) => return Ok( bad_request( e.to_string() ) ),
+        };
+
+        let mut decision = match simulation.decision {
+            Ok(visa) => json!( {
+                "decision": "allow",
+                "rule": visa.rule,
+                "destination": describe_destination( &visa, &simulation.path, &simulation.query ),
+                "obligations": describe_obligations( &visa.obligations ),
+            } ),
+
+            Err(reason) => json!( {
+                "decision": "deny",
+                "reason": reason.code(),
+                "status": reason.status().as_u16(),
+                "detail": reason.to_string(),
+                "destination": reason.destination(),
+            } ),
+        };
+
+        let identity = simulation.caller;
+        decision["caller"] = json!( identity.as_ref().map( |id| id.to_string() ) );
+        decision["caller_attributes"] = json!( identity.as_ref().map( |id| id.attributes() ) );
+        decision["policy_version"] = json!( version );
+        Ok( HttpResponse::Ok().json( decision ) )
+    } )
+}
+
+fn describe_destination( visa: &Visa, path: &str, query: &str ) -> Value {
+    let query = if query.is_empty() { None } else { Some( query ) };
+
+    json!( {
+        "scheme": visa.scheme,
+        "host": visa.destination.host.to_string(),
+        "port": visa.destination.port,
+        "base_path": visa.base_path,
+        "resolved": visa.resolved.map( |a| a.ip().to_string() ),
+        "tls_profile": visa.tls,
+        "url": visa.url_for( path, query ).to_string(),
+    } )
+}
+
+fn describe_obligations( obligations: &Obligations ) -> Value {
+    let add_headers = obligations.add_headers
+        .iter()
+        .map( |( name, value )| ( name.to_string(), String::from_utf8_lossy( value.as_bytes() ).to_string() ) )
+        .collect::<BTreeMap<String, String>>();
+
+    json!( {
+        "add_headers": add_headers,
+        "strip_headers": obligations.strip_headers.iter().map( |h| h.to_string() ).collect::<Vec<String>>(),
+        "timeout": format!( "{:?}", obligations.timeout ).to_lowercase(),
+        "rate_limit_bucket": obligations.rate_limit_bucket,
+        "quota": obligations.quota,
+    } )
+}
+
+fn bad_request( detail: String ) -> HttpResponse {
+    HttpResponse::BadRequest()
+        .content_type( PROBLEM_JSON )
+        .body( json!( { "title": "Bad Request", "status": 400, "detail": detail } ).to_string() )
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, web, App};
+    use actix_web::http::StatusCode;
+    use url::Url;
+    use crate::border::BorderControlBuilder;
+    use crate::border::host_control::HostControlBuilder;
+    use super::*;
+
+    fn simulate_json( body: Value ) -> ( StatusCode, Value ) {
+        let border = HostControlBuilder::new()
+            .with_named_destination( "vendor", Url::parse( "https://vendor.example.com/api" ).unwrap() )
+            .without_address_resolution()
+            .build();
+        let mut app = test::init_service(
+            App::new()
+                .data( ReloadableBorder::new( border ) )
+                .route( "/", web::post().to_async( simulate ) )
+        );
+
+        let res = test::call_service( &mut app, test::TestRequest::post().set_json( &body ).to_request() );
+        let status = res.status();
+        ( status, serde_json::from_slice( &test::read_body( res ) ).unwrap() )
+    }
+
+    #[test]
+    fn reports_granted_visas() {
+        let ( status, decision ) = simulate_json( json!( {
+            "method": "POST",
+            "url": "/v1/events?page=2",
+            "headers": { "X-DESTINATION": "vendor" },
+            "identity": [ "billing" ],
+            "attributes": { "scopes": [ "egress:vendor" ] },
+        } ) );
+
+        assert_eq!( status, StatusCode::OK );
+        assert_eq!( decision["decision"], "allow" );
+        assert_eq!( decision["rule"], "vendor" );
+        assert_eq!( decision["destination"]["host"], "vendor.example.com" );
+        assert_eq!( decision["destination"]["url"], "https://vendor.example.com/api/v1/events?page=2" );
+        assert_eq!( decision["caller"], "billing" );
+        assert_eq!( decision["caller_attributes"]["scopes"], json!( [ "egress:vendor" ] ) );
+        assert_eq!( decision["policy_version"], 1 );
+    }
+
+    #[test]
+    fn reports_denials() {
+        let ( status, decision ) = simulate_json( json!( { "url": "/", "headers": { "X-DESTINATION": "other" } } ) );
+        assert_eq!( status, StatusCode::OK );
+        assert_eq!( decision["decision"], "deny" );
+        assert_eq!( decision["reason"], "unknown_destination" );
+        assert_eq!( decision["status"], 404 );
+        assert_eq!( decision["caller"], Value::Null );
+    }
+
+    #[test]
+    fn refuses_invalid_requests() {
+        let ( status, problem ) = simulate_json( json!( { "method": "GE T", "url": "/" } ) );
+        assert_eq!( status, StatusCode::BAD_REQUEST );
+        assert_eq!( problem["status"], 400 );
+    }
+}
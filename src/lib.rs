@@ -1,12 +1,15 @@
 #![allow(unused)]
-#![feature(duration_float)]
 #![feature(associated_type_defaults)]
 
 extern crate env_logger;
 #[macro_use] extern crate prometheus;
 
+pub mod auth;
 pub mod config;
 pub mod metrics;
 pub mod handlers;
 pub mod middleware;
 pub mod border;
+pub mod limits;
+pub mod listener;
+pub mod upstream;
@@ -0,0 +1,43 @@
+use actix_web::dev::ServiceRequest;
+use serde_derive::Deserialize;
+use crate::border::caller::CallerIdentity;
+use crate::border::source::ClientAddress;
+
+pub mod quota;
+pub mod rate;
+
+/// Which requests share a rate-limit bucket or quota.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitScope {
+    /// Shared by every caller of the destinations drawing on the limit.
+    #[default]
+    Destination,
+    /// Kept per caller, shared across the destinations drawing on the limit.
+    Caller,
+    /// Kept per caller of each destination.
+    DestinationAndCaller,
+}
+
+impl LimitScope {
+    /// The key under which usage of the named limit is kept for a request.
+    pub fn key( self, limit: &str, destination: &str, caller: &str ) -> String {
+        match self {
+            LimitScope::Destination => limit.to_string(),
+            LimitScope::Caller => format!( "{}||{}", limit, caller ),
+            LimitScope::DestinationAndCaller => format!( "{}|{}|{}", limit, destination, caller ),
+        }
+    }
+}
+
+/// Identifies the caller a limit is applied to: its identity if it has one, or else its client
+/// address.
+pub fn caller_key( req: &ServiceRequest ) -> String {
+    if let Some(identity) = CallerIdentity::of( req ) {
+        return identity.name().to_string();
+    }
+
+    ClientAddress::of( req )
+        .map( |addr| addr.to_string() )
+        .unwrap_or_else( || "unknown".to_string() )
+}
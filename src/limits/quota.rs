@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+use std::io::Result;
+use std::path::Path;
+use std::sync::Arc;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use crate::border::denial::DenialReason;
+use super::LimitScope;
+
+/// Calendar period (UTC) over which a quota's usage accumulates before it resets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaPeriod {
+    Daily,
+    Monthly,
+}
+
+impl QuotaPeriod {
+    /// The window containing `now`, e.g. `2019-07-04` for a daily or `2019-07` for a monthly quota.
+    pub fn window( self, now: &DateTime<Utc> ) -> String {
+        match self {
+            QuotaPeriod::Daily => now.format( "%Y-%m-%d" ).to_string(),
+            QuotaPeriod::Monthly => now.format( "%Y-%m" ).to_string(),
+        }
+    }
+
+    fn name( self ) -> &'static str {
+        match self {
+            QuotaPeriod::Daily => "daily",
+            QuotaPeriod::Monthly => "monthly",
+        }
+    }
+}
+
+/// Allowances for one period; an unset allowance is unlimited.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct QuotaLimit {
+    #[serde(skip)]
+    pub period: QuotaPeriod,
+    pub requests: Option<u64>,
+    pub bytes_in: Option<u64>,
+    pub bytes_out: Option<u64>,
+}
+
+impl QuotaLimit {
+    pub fn new( period: QuotaPeriod ) -> Self {
+        QuotaLimit { period, requests: None, bytes_in: None, bytes_out: None, }
+    }
+
+    pub fn requests( mut self, requests: u64 ) -> Self {
+        self.requests = Some( requests );
+        self
+    }
+
+    pub fn bytes_in( mut self, bytes: u64 ) -> Self {
+        self.bytes_in = Some( bytes );
+        self
+    }
+
+    pub fn bytes_out( mut self, bytes: u64 ) -> Self {
+        self.bytes_out = Some( bytes );
+        self
+    }
+
+    fn exhausted_by( &self, usage: &Usage ) -> bool {
+        let used_up = |allowance: Option<u64>, used: u64| allowance.is_some_and( |max| max <= used );
+
+        used_up( self.requests, usage.requests ) ||
+            used_up( self.bytes_in, usage.bytes_in ) ||
+            used_up( self.bytes_out, usage.bytes_out )
+    }
+}
+
+/// Daily and/or monthly allowances, kept per destination, caller or both.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Quota {
+    pub scope: LimitScope,
+    pub limits: Vec<QuotaLimit>,
+}
+
+impl Quota {
+    pub fn new( scope: LimitScope ) -> Self {
+        Quota { scope, limits: Vec::new(), }
+    }
+
+    pub fn with_limit( mut self, limit: QuotaLimit ) -> Self {
+        self.limits.push( limit );
+        self
+    }
+}
+
+/// Usage counted within one window. Bytes out are sent to the destination; bytes in are
+/// received from it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Usage {
+    pub requests: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+impl Usage {
+    pub fn bytes( bytes_in: u64, bytes_out: u64 ) -> Self {
+        Usage { requests: 0, bytes_in, bytes_out, }
+    }
+
+    fn add( &mut self, other: &Usage ) {
+        self.requests += other.requests;
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+    }
+
+    fn decode( bytes: &[u8] ) -> Usage {
+        serde_json::from_slice( bytes ).unwrap_or_else( |e| {
+            warn!( "discarding unreadable quota usage: {}", e );
+            Usage::default()
+        } )
+    }
+
+    fn encode( &self ) -> Vec<u8> { serde_json::to_vec( self ).unwrap() }
+}
+
+/// Current usage of a quota by one destination or caller, as reported by the admin endpoint.
+#[derive(Clone, Debug, Serialize)]
+pub struct UsageReport {
+    pub quota: String,
+    pub key: String,
+    pub period: QuotaPeriod,
+    pub window: String,
+    pub usage: Usage,
+    pub limit: QuotaLimit,
+}
+
+/// A request's claim on a quota, recording the bytes it transfers against the windows it was
+/// admitted in.
+#[derive(Clone)]
+pub struct QuotaTicket {
+    store: sled::Db,
+    keys: Vec<String>,
+}
+
+impl QuotaTicket {
+    pub fn record( &self, usage: &Usage ) {
+        for key in self.keys.iter() {
+            let update = self.store.update_and_fetch( key.as_bytes(), |current| {
+                let mut total = current.map( Usage::decode ).unwrap_or_default();
+                total.add( usage );
+                Some( total.encode() )
+            } );
+
+            if let Err(e) = update {
+                warn!( "failed to record quota usage {}: {}", key, e );
+            }
+        }
+    }
+}
+
+/// Quotas by name with their usage persisted in a local store, so restarts do not reset them. A
+/// destination draws on the quota named by its visa's `quota` obligation, or else on the quota
+/// named after the destination.
+#[derive(Clone)]
+pub struct QuotaLedger {
+    quotas: Arc<HashMap<String, Quota>>,
+    store: sled::Db,
+}
+
+impl QuotaLedger {
+    /// Opens the store at `path`, discarding usage from windows that have ended.
+    pub fn open<P: AsRef<Path>>( path: P ) -> Result<Self> {
+        let ledger = QuotaLedger { quotas: Arc::new( HashMap::new() ), store: sled::open( path )?, };
+        ledger.prune()?;
+        Ok( ledger )
+    }
+
+    /// A ledger whose usage is lost on exit.
+    pub fn temporary() -> Result<Self> {
+        let store = sled::Config::new().temporary( true ).open()?;
+        Ok( QuotaLedger { quotas: Arc::new( HashMap::new() ), store, } )
+    }
+
+    pub fn with_quota( mut self, name: &str, quota: Quota ) -> Self {
+        Arc::make_mut( &mut self.quotas ).insert( name.to_string(), quota );
+        self
+    }
+
+    pub fn is_empty( &self ) -> bool { self.quotas.is_empty() }
+
+    /// Counts a request against the named quota, refusing it once any allowance of the current
+    /// windows is used up; `None` when there is no such quota. The request is checked and counted
+    /// in every window at once, so concurrent requests cannot together exceed an allowance.
+    pub fn admit( &self, name: &str, destination: &str, caller: &str ) -> Option<std::result::Result<QuotaTicket, DenialReason>> {
+        let quota = self.quotas.get( name )?;
+        let key = quota.scope.key( name, destination, caller );
+        let now = Utc::now();
+
+        let windows = quota.limits
+            .iter()
+            .map( |limit| ( limit, store_key( limit.period, &limit.period.window( &now ), &key ) ) )
+            .collect::<Vec<_>>();
+
+        let counted = self.store.transaction( |store| {
+            for ( limit, store_key ) in windows.iter() {
+                let mut usage = store.get( store_key.as_bytes() )?.map( |u| Usage::decode( &u ) ).unwrap_or_default();
+                if limit.exhausted_by( &usage ) {
+                    return Err( ConflictableTransactionError::Abort( () ) );
+                }
+                usage.requests += 1;
+                store.insert( store_key.as_bytes(), usage.encode() )?;
+            }
+            Ok( () )
+        } );
+
+        match counted {
+            Ok(()) => (),
+            Err(TransactionError::Abort(())) => return Some( Err( DenialReason::QuotaExceeded { quota: name.to_string() } ) ),
+            Err(TransactionError::Storage(e)) => warn!( "failed to count request against quota {}: {}", key, e ),
+        }
+
+        let keys = windows.into_iter().map( |( _, store_key )| store_key ).collect();
+        Some( Ok( QuotaTicket { store: self.store.clone(), keys, } ) )
+    }
+
+    /// Usage of every quota in its current windows.
+    pub fn report( &self ) -> Vec<UsageReport> {
+        let now = Utc::now();
+        let mut reports = Vec::new();
+
+        for ( name, quota ) in self.quotas.iter() {
+            for limit in quota.limits.iter() {
+                let window = limit.period.window( &now );
+                let prefix = store_key( limit.period, &window, "" );
+
+                for entry in self.store.scan_prefix( prefix.as_bytes() ).filter_map( |e| e.ok() ) {
+                    let key = String::from_utf8_lossy( &entry.0[prefix.len()..] ).to_string();
+                    if key.split( '|' ).next() != Some( name.as_str() ) {
+                        continue;
+                    }
+
+                    reports.push( UsageReport {
+                        quota: name.clone(),
+                        key,
+                        period: limit.period,
+                        window: window.clone(),
+                        usage: Usage::decode( &entry.1 ),
+                        limit: limit.clone(),
+                    } );
+                }
+            }
+        }
+
+        reports
+    }
+
+    fn prune( &self ) -> Result<()> {
+        let now = Utc::now();
+        let current = [ QuotaPeriod::Daily, QuotaPeriod::Monthly ]
+            .iter()
+            .map( |p| store_key( *p, &p.window( &now ), "" ) )
+            .collect::<Vec<String>>();
+
+        let mut pruned = 0;
+        for entry in self.store.iter() {
+            let ( key, _ ) = entry?;
+            if !current.iter().any( |c| key.starts_with( c.as_bytes() ) ) {
+                self.store.remove( key )?;
+                pruned += 1;
+            }
+        }
+
+        if pruned > 0 {
+            info!( "pruned {} expired quota usage entries", pruned );
+        }
+        Ok( () )
+    }
+}
+
+fn store_key( period: QuotaPeriod, window: &str, key: &str ) -> String {
+    format!( "{}/{}/{}", period.name(), window, key )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use super::*;
+
+    #[test]
+    fn concurrent_requests_never_exceed_an_allowance() {
+        let quota = Quota::new( LimitScope::Destination )
+            .with_limit( QuotaLimit::new( QuotaPeriod::Daily ).requests( 20 ) )
+            .with_limit( QuotaLimit::new( QuotaPeriod::Monthly ).requests( 100 ) );
+        let ledger = QuotaLedger::temporary().unwrap().with_quota( "vendor", quota );
+
+        let workers = ( 0..8 )
+            .map( |_| {
+                let ledger = ledger.clone();
+                thread::spawn( move || ( 0..10 ).filter( |_| ledger.admit( "vendor", "vendor", "" ).unwrap().is_ok() ).count() )
+            } )
+            .collect::<Vec<_>>();
+        let admitted: usize = workers.into_iter().map( |w| w.join().unwrap() ).sum();
+
+        assert_eq!( admitted, 20 );
+        let usage = ledger.report().into_iter().map( |r| ( r.period, r.usage.requests ) ).collect::<Vec<_>>();
+        assert!( usage.contains( &( QuotaPeriod::Daily, 20 ) ) && usage.contains( &( QuotaPeriod::Monthly, 20 ) ), "{:?}", usage );
+    }
+}
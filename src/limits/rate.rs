@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use super::LimitScope;
+
+/// What to do with a request once its bucket is empty.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Exhaustion {
+    /// Refuse the request with `429 Too Many Requests` and a `Retry-After`.
+    Reject,
+    /// Hold the request until a token is available, refusing it if that takes longer than
+    /// `max_wait`.
+    Queue { max_wait: Duration },
+}
+
+/// Sustained `rate` in requests per second with bursts of up to `burst` requests.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: u32,
+    pub scope: LimitScope,
+    pub on_exhausted: Exhaustion,
+}
+
+impl RateLimit {
+    pub fn new( rate: f64, burst: u32 ) -> Self {
+        RateLimit { rate, burst, scope: LimitScope::Destination, on_exhausted: Exhaustion::Reject, }
+    }
+
+    pub fn per( mut self, scope: LimitScope ) -> Self {
+        self.scope = scope;
+        self
+    }
+
+    pub fn on_exhausted( mut self, on_exhausted: Exhaustion ) -> Self {
+        self.on_exhausted = on_exhausted;
+        self
+    }
+
+    fn max_wait( &self ) -> Duration {
+        match self.on_exhausted {
+            Exhaustion::Reject => Duration::from_secs( 0 ),
+            Exhaustion::Queue { max_wait } => max_wait,
+        }
+    }
+}
+
+/// The state of a bucket after a request drew on it, reported in `RateLimit-*` headers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BucketStatus {
+    pub limit: u32,
+    pub remaining: u32,
+    pub reset: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Acquisition {
+    Granted( BucketStatus ),
+    Delayed { wait: Duration, status: BucketStatus },
+    Exhausted { retry_after: Duration, status: BucketStatus },
+}
+
+/// How often buckets that have refilled are dropped.
+const SWEEP_INTERVAL: Duration = Duration::from_secs( 60 );
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+    /// When the bucket is full again; a full bucket is the same as none.
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn full( limit: &RateLimit, now: Instant ) -> Self {
+        TokenBucket { tokens: f64::from( limit.burst ), updated: now, refilled: now, }
+    }
+
+    fn acquire( &mut self, limit: &RateLimit, now: Instant ) -> Acquisition {
+        let acquisition = self.draw( limit, now );
+        self.refilled = now + self.status( limit ).reset;
+        acquisition
+    }
+
+    fn draw( &mut self, limit: &RateLimit, now: Instant ) -> Acquisition {
+        let elapsed = now.saturating_duration_since( self.updated ).as_secs_f64();
+        self.tokens = ( self.tokens + elapsed * limit.rate ).min( f64::from( limit.burst ) );
+        self.updated = now;
+
+        if 1.0 <= self.tokens {
+            self.tokens -= 1.0;
+            return Acquisition::Granted( self.status( limit ) );
+        }
+
+        let wait = Duration::from_secs_f64( ( 1.0 - self.tokens ) / limit.rate );
+        if wait <= limit.max_wait() {
+            // the token is reserved now so queued requests are released in order
+            self.tokens -= 1.0;
+            Acquisition::Delayed { wait, status: self.status( limit ) }
+        } else {
+            Acquisition::Exhausted { retry_after: wait, status: self.status( limit ) }
+        }
+    }
+
+    fn status( &self, limit: &RateLimit ) -> BucketStatus {
+        let missing = f64::from( limit.burst ) - self.tokens;
+        BucketStatus {
+            limit: limit.burst,
+            remaining: self.tokens.max( 0.0 ).floor() as u32,
+            reset: Duration::from_secs_f64( ( missing / limit.rate ).max( 0.0 ) ),
+        }
+    }
+}
+
+/// Token-bucket rate limits keyed by bucket name, shared by every worker. A destination draws
+/// on the bucket named by its visa's `rate_limit_bucket` obligation, or else on the bucket
+/// named after the destination.
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    limits: Arc<HashMap<String, RateLimit>>,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+/// Buckets drawn on since they were last full. Per-caller buckets come and go with callers, so
+/// refilled buckets are swept out now and then rather than kept.
+#[derive(Default)]
+struct Buckets {
+    buckets: HashMap<String, TokenBucket>,
+    swept: Option<Instant>,
+}
+
+impl Buckets {
+    fn sweep( &mut self, now: Instant ) {
+        if self.swept.is_some_and( |swept| now.saturating_duration_since( swept ) < SWEEP_INTERVAL ) {
+            return;
+        }
+
+        self.buckets.retain( |_, bucket| now < bucket.refilled );
+        self.swept = Some( now );
+    }
+}
+
+impl RateLimiter {
+    pub fn new() -> Self { RateLimiter::default() }
+
+    pub fn with_limit( mut self, bucket: &str, limit: RateLimit ) -> Self {
+        Arc::make_mut( &mut self.limits ).insert( bucket.to_string(), limit );
+        self
+    }
+
+    pub fn is_empty( &self ) -> bool { self.limits.is_empty() }
+
+    /// Draws a token for a request by the caller from the named bucket; `None` when the bucket
+    /// has no limit.
+    pub fn acquire( &self, bucket: &str, destination: &str, caller: &str ) -> Option<Acquisition> {
+        let limit = self.limits.get( bucket )?;
+        let key = limit.scope.key( bucket, destination, caller );
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.sweep( now );
+        Some(
+            buckets
+                .buckets
+                .entry( key )
+                .or_insert_with( || TokenBucket::full( limit, now ) )
+                .acquire( limit, now )
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sweeps_refilled_buckets() {
+        let limit = RateLimit::new( 1.0, 2 );
+        let start = Instant::now();
+        let mut buckets = Buckets::default();
+        for key in &[ "a", "b" ] {
+            buckets.buckets.entry( key.to_string() ).or_insert_with( || TokenBucket::full( &limit, start ) ).acquire( &limit, start );
+        }
+        buckets.sweep( start );
+        assert_eq!( buckets.buckets.len(), 2 );
+
+        let later = start + SWEEP_INTERVAL;
+        buckets.buckets.get_mut( "b" ).unwrap().acquire( &limit, later );
+        buckets.sweep( later );
+        assert_eq!( buckets.buckets.keys().collect::<Vec<_>>(), vec![ "b" ] );
+    }
+
+    fn drain( bucket: &mut TokenBucket, limit: &RateLimit, now: Instant ) {
+        for _ in 0..limit.burst {
+            match bucket.acquire( limit, now ) {
+                Acquisition::Granted(_) => {},
+                other => panic!( "burst refused: {:?}", other ),
+            }
+        }
+    }
+
+    #[test]
+    fn grants_bursts_and_reports_remaining_tokens() {
+        let limit = RateLimit::new( 2.0, 3 );
+        let now = Instant::now();
+        let mut bucket = TokenBucket::full( &limit, now );
+
+        let statuses: Vec<_> = ( 0..3 ).map( |_| bucket.acquire( &limit, now ) ).collect();
+        assert_eq!( statuses[0], Acquisition::Granted( BucketStatus { limit: 3, remaining: 2, reset: Duration::from_millis( 500 ) } ) );
+        assert_eq!( statuses[2], Acquisition::Granted( BucketStatus { limit: 3, remaining: 0, reset: Duration::from_millis( 1500 ) } ) );
+    }
+
+    #[test]
+    fn rejects_with_the_time_until_a_token_is_available() {
+        let limit = RateLimit::new( 2.0, 2 );
+        let now = Instant::now();
+        let mut bucket = TokenBucket::full( &limit, now );
+        drain( &mut bucket, &limit, now );
+
+        match bucket.acquire( &limit, now ) {
+            Acquisition::Exhausted { retry_after, status } => {
+                assert_eq!( retry_after, Duration::from_millis( 500 ) );
+                assert_eq!( status, BucketStatus { limit: 2, remaining: 0, reset: Duration::from_secs( 1 ) } );
+            },
+            other => panic!( "not exhausted: {:?}", other ),
+        }
+
+        let later = now + Duration::from_millis( 500 );
+        assert!( matches!( bucket.acquire( &limit, later ), Acquisition::Granted(_) ) );
+    }
+
+    #[test]
+    fn queues_requests_up_to_max_wait() {
+        let limit = RateLimit::new( 1.0, 1 ).on_exhausted( Exhaustion::Queue { max_wait: Duration::from_secs( 2 ) } );
+        let now = Instant::now();
+        let mut bucket = TokenBucket::full( &limit, now );
+        drain( &mut bucket, &limit, now );
+
+        // each queued request reserves the next token, so the waits grow in order
+        let waits: Vec<_> = ( 0..3 ).map( |_| bucket.acquire( &limit, now ) ).collect();
+        match waits[0] {
+            Acquisition::Delayed { wait, .. } => assert_eq!( wait, Duration::from_secs( 1 ) ),
+            other => panic!( "not delayed: {:?}", other ),
+        }
+        match waits[1] {
+            Acquisition::Delayed { wait, status } => {
+                assert_eq!( wait, Duration::from_secs( 2 ) );
+                assert_eq!( status.remaining, 0 );
+            },
+            other => panic!( "not delayed: {:?}", other ),
+        }
+        match waits[2] {
+            Acquisition::Exhausted { retry_after, .. } => assert_eq!( retry_after, Duration::from_secs( 3 ) ),
+            other => panic!( "not exhausted: {:?}", other ),
+        }
+    }
+
+    #[test]
+    fn limits_only_configured_buckets_in_their_scope() {
+        let limiter = RateLimiter::new()
+            .with_limit( "api", RateLimit::new( 1.0, 1 ).per( LimitScope::Caller ) );
+
+        assert!( limiter.acquire( "other", "other", "alice" ).is_none() );
+        assert!( matches!( limiter.acquire( "api", "api", "alice" ), Some(Acquisition::Granted(_)) ) );
+        assert!( matches!( limiter.acquire( "api", "api", "alice" ), Some(Acquisition::Exhausted { .. }) ) );
+        assert!( matches!( limiter.acquire( "api", "api", "bob" ), Some(Acquisition::Granted(_)) ) );
+    }
+}
@@ -0,0 +1,23 @@
+use std::fmt;
+use std::io;
+
+pub mod proxy_protocol;
+pub mod tls;
+pub mod tunnel;
+
+/// Why a connection was dropped: it could not be accepted, e.g. its TLS handshake or PROXY
+/// protocol header failed, or the HTTP service failed on it.
+#[derive(Debug)]
+pub enum AcceptError<E> {
+    Accept( io::Error ),
+    Service( E ),
+}
+
+impl<E: fmt::Debug> fmt::Display for AcceptError<E> {
+    fn fmt( &self, f: &mut fmt::Formatter ) -> fmt::Result {
+        match self {
+            AcceptError::Accept(e) => write!( f, "connection not accepted: {}", e ),
+            AcceptError::Service(e) => write!( f, "connection failed: {:?}", e ),
+        }
+    }
+}
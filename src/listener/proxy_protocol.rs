@@ -0,0 +1,367 @@
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use actix_server_config::{Io, IoStream, Protocol, ServerConfig};
+use actix_service::{NewService, Service};
+use futures::{Async, Future, Poll, future::{err, ok, Either, FutureResult}};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_timer::Delay;
+use crate::border::source::SourcePolicy;
+
+/// Time allowed for a load balancer to send the PROXY protocol header.
+const HEADER_TIMEOUT: Duration = Duration::from_secs( 5 );
+
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LENGTH: usize = 107;
+
+/// Reads the PROXY protocol header trusted load balancers send ahead of each connection, so
+/// requests report the client the connection was made for as their peer address. Connections
+/// from peers that are not trusted proxies, or without a valid header, are closed. A disabled
+/// acceptor passes connections through untouched.
+pub struct ProxyProtocolAcceptor<T, P> {
+    policy: Option<SourcePolicy>,
+    io: PhantomData<(T, P)>,
+}
+
+impl<T, P> ProxyProtocolAcceptor<T, P> {
+    /// Expects a header from the trusted proxies of `policy` if given.
+    pub fn new( policy: Option<SourcePolicy> ) -> Self {
+        ProxyProtocolAcceptor { policy, io: PhantomData, }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + IoStream, P> NewService for ProxyProtocolAcceptor<T, P> {
+    type Request = Io<T, P>;
+    type Response = Io<ProxiedStream<T>, P>;
+    type Error = io::Error;
+    type Config = ServerConfig;
+    type Service = ProxyProtocolService<T, P>;
+    type InitError = ();
+    type Future = FutureResult<Self::Service, Self::InitError>;
+
+    fn new_service( &self, _: &ServerConfig ) -> Self::Future {
+        ok( ProxyProtocolService { policy: self.policy.clone(), io: PhantomData, } )
+    }
+}
+
+pub struct ProxyProtocolService<T, P> {
+    policy: Option<SourcePolicy>,
+    io: PhantomData<(T, P)>,
+}
+
+impl<T: AsyncRead + AsyncWrite + IoStream, P> Service for ProxyProtocolService<T, P> {
+    type Request = Io<T, P>;
+    type Response = Io<ProxiedStream<T>, P>;
+    type Error = io::Error;
+    type Future = Either<FutureResult<Self::Response, io::Error>, ReadHeader<T, P>>;
+
+    fn poll_ready( &mut self ) -> Poll<(), Self::Error> {
+        Ok( Async::Ready( () ) )
+    }
+
+    fn call( &mut self, req: Self::Request ) -> Self::Future {
+        let ( stream, params, proto ) = req.into_parts();
+
+        let policy = match self.policy.as_ref() {
+            Some(policy) => policy,
+            None => {
+                let stream = ProxiedStream { stream, source: None, };
+                return Either::A( ok( Io::from_parts( stream, params, proto ) ) );
+            },
+        };
+
+        match stream.peer_addr() {
+            Some(peer) if policy.is_trusted_proxy( &peer.ip() ) => Either::B( ReadHeader {
+                stream: Some( stream ),
+                params: Some( ( params, proto ) ),
+                header: Vec::with_capacity( V1_MAX_LENGTH ),
+                deadline: Delay::new( Instant::now() + HEADER_TIMEOUT ),
+            } ),
+
+            peer => Either::A( err( io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!( "PROXY protocol connection from untrusted peer {:?}", peer ),
+            ) ) ),
+        }
+    }
+}
+
+/// Reads a PROXY protocol header off a connection, never past its end.
+pub struct ReadHeader<T, P> {
+    stream: Option<T>,
+    params: Option<(P, Protocol)>,
+    header: Vec<u8>,
+    deadline: Delay,
+}
+
+impl<T: AsyncRead, P> Future for ReadHeader<T, P> {
+    type Item = Io<ProxiedStream<T>, P>;
+    type Error = io::Error;
+
+    fn poll( &mut self ) -> Poll<Self::Item, Self::Error> {
+        while let Some(length) = needed( &self.header )? {
+            let mut buf = [0; 256];
+            let want = ( length - self.header.len() ).min( buf.len() );
+
+            match self.stream.as_mut().unwrap().read( &mut buf[..want] ) {
+                Ok(0) => return Err( invalid( "connection closed before the PROXY protocol header" ) ),
+                Ok(n) => self.header.extend_from_slice( &buf[..n] ),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return match self.deadline.poll() {
+                        Ok(Async::NotReady) => Ok( Async::NotReady ),
+                        _ => Err( io::Error::new( io::ErrorKind::TimedOut, "timed out reading the PROXY protocol header" ) ),
+                    };
+                },
+                Err(e) => return Err( e ),
+            }
+        }
+
+        let source = source( &self.header )?;
+        let stream = ProxiedStream { stream: self.stream.take().unwrap(), source, };
+        let ( params, proto ) = self.params.take().unwrap();
+        Ok( Async::Ready( Io::from_parts( stream, params, proto ) ) )
+    }
+}
+
+fn invalid( msg: &str ) -> io::Error {
+    io::Error::new( io::ErrorKind::InvalidData, msg )
+}
+
+/// The length the header read so far needs to reach, or none once it is complete.
+fn needed( header: &[u8] ) -> io::Result<Option<usize>> {
+    let length = header.len();
+
+    if length < V2_SIGNATURE.len() {
+        let prefix = &header[..length.min( V1_PREFIX.len() )];
+        return if V2_SIGNATURE.starts_with( header ) || V1_PREFIX.starts_with( prefix ) {
+            Ok( Some( V2_SIGNATURE.len() ) )
+        } else {
+            Err( invalid( "missing PROXY protocol header" ) )
+        };
+    }
+
+    if header.starts_with( V2_SIGNATURE ) {
+        if length < 16 {
+            return Ok( Some( 16 ) );
+        }
+        let total = 16 + u16::from_be_bytes( [ header[14], header[15] ] ) as usize;
+        return Ok( if length < total { Some( total ) } else { None } );
+    }
+
+    if !header.starts_with( V1_PREFIX ) {
+        Err( invalid( "missing PROXY protocol header" ) )
+    } else if header.ends_with( b"\r\n" ) {
+        Ok( None )
+    } else if length >= V1_MAX_LENGTH {
+        Err( invalid( "PROXY protocol header too long" ) )
+    } else {
+        Ok( Some( length + 1 ) )
+    }
+}
+
+/// The client address a complete header reports; none for health checks made by the load
+/// balancer itself (`LOCAL`, `UNKNOWN`) or for addresses other than TCP over IPv4 or IPv6.
+fn source( header: &[u8] ) -> io::Result<Option<SocketAddr>> {
+    if header.starts_with( V2_SIGNATURE ) {
+        return source_v2( header );
+    }
+
+    let line = std::str::from_utf8( &header[..header.len() - 2] ).map_err( |_| invalid( "invalid PROXY protocol header" ) )?;
+    let fields = line.split( ' ' ).collect::<Vec<&str>>();
+
+    match fields.as_slice() {
+        [ _, "UNKNOWN", .. ] => Ok( None ),
+        [ _, "TCP4", src, _, port, _ ] | [ _, "TCP6", src, _, port, _ ] => {
+            let ip = src.parse::<IpAddr>().map_err( |_| invalid( "invalid PROXY protocol source address" ) )?;
+            let port = port.parse::<u16>().map_err( |_| invalid( "invalid PROXY protocol source port" ) )?;
+            Ok( Some( SocketAddr::new( ip, port ) ) )
+        },
+        _ => Err( invalid( "invalid PROXY protocol header" ) ),
+    }
+}
+
+fn source_v2( header: &[u8] ) -> io::Result<Option<SocketAddr>> {
+    let ( version, command, family ) = ( header[12] >> 4, header[12] & 0x0f, header[13] >> 4 );
+    if version != 2 || command > 1 {
+        return Err( invalid( "unsupported PROXY protocol version or command" ) );
+    }
+
+    let addresses = &header[16..];
+    let source = match ( command, family ) {
+        // LOCAL connections are made by the load balancer itself
+        ( 0, _ ) => None,
+
+        ( _, 1 ) if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new( addresses[0], addresses[1], addresses[2], addresses[3] );
+            Some( SocketAddr::new( ip.into(), u16::from_be_bytes( [ addresses[8], addresses[9] ] ) ) )
+        },
+
+        ( _, 2 ) if addresses.len() >= 36 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice( &addresses[..16] );
+            let ip = Ipv6Addr::from( octets );
+            Some( SocketAddr::new( ip.into(), u16::from_be_bytes( [ addresses[32], addresses[33] ] ) ) )
+        },
+
+        ( _, 1 ) | ( _, 2 ) => return Err( invalid( "truncated PROXY protocol addresses" ) ),
+        _ => None,
+    };
+
+    Ok( source )
+}
+
+/// A connection accepted behind a load balancer, reporting the client it was made for as its
+/// peer address.
+#[derive(Debug)]
+pub struct ProxiedStream<S> {
+    stream: S,
+    source: Option<SocketAddr>,
+}
+
+impl<S: Read> Read for ProxiedStream<S> {
+    fn read( &mut self, buf: &mut [u8] ) -> io::Result<usize> {
+        self.stream.read( buf )
+    }
+}
+
+impl<S: Write> Write for ProxiedStream<S> {
+    fn write( &mut self, buf: &[u8] ) -> io::Result<usize> {
+        self.stream.write( buf )
+    }
+
+    fn flush( &mut self ) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for ProxiedStream<S> {}
+
+impl<S: AsyncWrite> AsyncWrite for ProxiedStream<S> {
+    fn shutdown( &mut self ) -> Poll<(), io::Error> {
+        self.stream.shutdown()
+    }
+}
+
+impl<S: IoStream> IoStream for ProxiedStream<S> {
+    fn peer_addr( &self ) -> Option<SocketAddr> {
+        self.source.or_else( || self.stream.peer_addr() )
+    }
+
+    fn set_nodelay( &mut self, nodelay: bool ) -> io::Result<()> {
+        self.stream.set_nodelay( nodelay )
+    }
+
+    fn set_linger( &mut self, dur: Option<Duration> ) -> io::Result<()> {
+        self.stream.set_linger( dur )
+    }
+
+    fn set_keepalive( &mut self, dur: Option<Duration> ) -> io::Result<()> {
+        self.stream.set_keepalive( dur )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads a header the way `ReadHeader` does, a byte at a time as needed.
+    fn read( mut input: &[u8] ) -> io::Result<Option<SocketAddr>> {
+        let mut header = Vec::new();
+        while let Some(length) = needed( &header )? {
+            if input.is_empty() {
+                return Err( invalid( "truncated" ) );
+            }
+            let n = ( length - header.len() ).min( input.len() );
+            header.extend_from_slice( &input[..n] );
+            input = &input[n..];
+        }
+        source( &header )
+    }
+
+    fn v2( command: u8, family: u8, addresses: &[u8] ) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push( 0x20 | command );
+        header.push( family << 4 | 1 );
+        header.extend_from_slice( &( addresses.len() as u16 ).to_be_bytes() );
+        header.extend_from_slice( addresses );
+        header
+    }
+
+    #[test]
+    fn reads_v1_sources() {
+        assert_eq!( read( b"PROXY TCP4 10.1.2.3 10.0.0.1 51234 8080\r\nGET /" ).unwrap(), Some( "10.1.2.3:51234".parse().unwrap() ) );
+        assert_eq!( read( b"PROXY TCP6 2001:db8::1 2001:db8::2 51234 8080\r\n" ).unwrap(), Some( "[2001:db8::1]:51234".parse().unwrap() ) );
+        assert_eq!( read( b"PROXY UNKNOWN\r\n" ).unwrap(), None );
+        assert_eq!( read( b"PROXY UNKNOWN 10.1.2.3 10.0.0.1 51234 8080\r\n" ).unwrap(), None );
+    }
+
+    #[test]
+    fn refuses_invalid_v1_headers() {
+        assert!( read( b"PROXY TCP4 10.1.2.3 10.0.0.1 51234\r\n" ).is_err() );
+        assert!( read( b"PROXY TCP4 10.1.2.3 10.0.0.1 port 8080\r\n" ).is_err() );
+        assert!( read( b"PROXY TCP4 10.1.2.3 10.0.0.1 51234 8080" ).is_err() );
+        assert!( read( b"GET / HTTP/1.1\r\n\r\n" ).is_err() );
+    }
+
+    #[test]
+    fn refuses_oversized_v1_headers() {
+        let mut header = b"PROXY TCP4 10.1.2.3 10.0.0.1 51234 8080 ".to_vec();
+        header.resize( 200, b'x' );
+        header.extend_from_slice( b"\r\n" );
+        assert!( needed( &header[..V1_MAX_LENGTH] ).is_err() );
+        assert!( read( &header ).is_err() );
+    }
+
+    #[test]
+    fn reads_no_further_than_the_header() {
+        let header = v2( 1, 1, &[ 10, 1, 2, 3, 10, 0, 0, 1, 0xc8, 0x22, 0x1f, 0x90 ] );
+        assert_eq!( needed( &header[..10] ).unwrap(), Some( V2_SIGNATURE.len() ) );
+        assert_eq!( needed( &header[..12] ).unwrap(), Some( 16 ) );
+        assert_eq!( needed( &header[..16] ).unwrap(), Some( header.len() ) );
+        assert_eq!( needed( &header ).unwrap(), None );
+        assert_eq!( needed( b"PROXY TCP4" ).unwrap(), Some( V2_SIGNATURE.len() ) );
+        assert_eq!( needed( b"PROXY TCP4 10" ).unwrap(), Some( 14 ) );
+    }
+
+    #[test]
+    fn reads_v2_sources() {
+        let tcp4 = v2( 1, 1, &[ 10, 1, 2, 3, 10, 0, 0, 1, 0xc8, 0x22, 0x1f, 0x90 ] );
+        assert_eq!( read( &tcp4 ).unwrap(), Some( "10.1.2.3:51234".parse().unwrap() ) );
+
+        let mut addresses = Ipv6Addr::new( 0x2001, 0xdb8, 0, 0, 0, 0, 0, 1 ).octets().to_vec();
+        addresses.extend_from_slice( &Ipv6Addr::new( 0x2001, 0xdb8, 0, 0, 0, 0, 0, 2 ).octets() );
+        addresses.extend_from_slice( &[ 0xc8, 0x22, 0x1f, 0x90 ] );
+        assert_eq!( read( &v2( 1, 2, &addresses ) ).unwrap(), Some( "[2001:db8::1]:51234".parse().unwrap() ) );
+
+        // TLVs after the addresses are skipped
+        let mut tlv = tcp4[16..].to_vec();
+        tlv.extend_from_slice( &[ 0x04, 0x00, 0x01, 0x00 ] );
+        assert_eq!( read( &v2( 1, 1, &tlv ) ).unwrap(), Some( "10.1.2.3:51234".parse().unwrap() ) );
+    }
+
+    #[test]
+    fn reads_no_source_for_local_and_unspecified() {
+        assert_eq!( read( &v2( 0, 0, &[] ) ).unwrap(), None );
+        assert_eq!( read( &v2( 0, 1, &[ 10, 1, 2, 3, 10, 0, 0, 1, 0xc8, 0x22, 0x1f, 0x90 ] ) ).unwrap(), None );
+        assert_eq!( read( &v2( 1, 0, &[] ) ).unwrap(), None );
+        assert_eq!( read( &v2( 1, 3, &[ 0; 216 ] ) ).unwrap(), None );
+    }
+
+    #[test]
+    fn refuses_invalid_v2_headers() {
+        assert!( read( &v2( 1, 1, &[ 10, 1, 2, 3 ] ) ).is_err() );
+        assert!( read( &v2( 1, 2, &[ 0; 12 ] ) ).is_err() );
+        assert!( read( &v2( 2, 1, &[ 0; 12 ] ) ).is_err() );
+
+        let mut version = v2( 1, 1, &[ 0; 12 ] );
+        version[12] = 0x11;
+        assert!( read( &version ).is_err() );
+
+        // a header claiming more addresses than the connection sends
+        let mut short = v2( 1, 1, &[ 0; 12 ] );
+        short.truncate( 20 );
+        assert!( read( &short ).is_err() );
+    }
+}
@@ -0,0 +1,287 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use actix_server_config::{Io, IoStream, Protocol, ServerConfig};
+use actix_service::{NewService, Service};
+use futures::{Async, Future, Poll, future::{ok, FutureResult}};
+use openssl::error::ErrorStack;
+use openssl::nid::Nid;
+use openssl::ssl::{
+    ErrorCode, HandshakeError, MidHandshakeSslStream, SslAcceptor, SslFiletype, SslMethod, SslStream, SslVerifyMode,
+};
+use serde_derive::Deserialize;
+use tokio_io::{AsyncRead, AsyncWrite};
+use crate::border::caller::{CallerIdentity, ConnectionIdentity};
+
+/// Whether a TLS listener verifying client certificates also admits clients without one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuth {
+    #[default]
+    Required,
+    Optional,
+}
+
+/// The certificate files a TLS listener serves, and the CA bundle client certificates are
+/// verified against.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ServerTls {
+    cert: PathBuf,
+    key: PathBuf,
+    client_ca: Option<PathBuf>,
+    client_auth: ClientAuth,
+}
+
+impl ServerTls {
+    /// Serves the PEM certificate chain in `cert` with the PEM private key in `key`.
+    pub fn new<P: Into<PathBuf>>( cert: P, key: P ) -> Self {
+        ServerTls { cert: cert.into(), key: key.into(), client_ca: None, client_auth: ClientAuth::default(), }
+    }
+
+    /// Verifies client certificates against the PEM certificates in `ca`.
+    pub fn with_client_ca<P: Into<PathBuf>>( mut self, ca: P, auth: ClientAuth ) -> Self {
+        self.client_ca = Some( ca.into() );
+        self.client_auth = auth;
+        self
+    }
+
+    /// The files the acceptor is built from.
+    pub fn files( &self ) -> Vec<&Path> {
+        let mut files = vec![ self.cert.as_path(), self.key.as_path() ];
+        files.extend( self.client_ca.as_deref() );
+        files
+    }
+
+    /// Reads the certificate files into a new acceptor.
+    pub fn acceptor( &self ) -> io::Result<SslAcceptor> {
+        let mut builder = SslAcceptor::mozilla_intermediate_v5( SslMethod::tls_server() ).map_err( io::Error::other )?;
+        builder.set_certificate_chain_file( &self.cert ).map_err( context( &self.cert ) )?;
+        builder.set_private_key_file( &self.key, SslFiletype::PEM ).map_err( context( &self.key ) )?;
+        builder.check_private_key().map_err( context( &self.key ) )?;
+
+        if let Some(ref ca) = self.client_ca {
+            builder.set_ca_file( ca ).map_err( context( ca ) )?;
+            builder.set_verify( match self.client_auth {
+                ClientAuth::Required => SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+                ClientAuth::Optional => SslVerifyMode::PEER,
+            } );
+        }
+
+        Ok( builder.build() )
+    }
+}
+
+fn context( path: &Path ) -> impl FnOnce( ErrorStack ) -> io::Error + '_ {
+    move |e| io::Error::new( io::ErrorKind::InvalidData, format!( "failed to load {}: {}", path.display(), e ) )
+}
+
+/// A listener's TLS acceptor, rebuilt from its certificate files while the proxy runs. A
+/// connection is accepted with the acceptor current when it arrives.
+#[derive(Clone)]
+pub struct ReloadableAcceptor {
+    tls: ServerTls,
+    acceptor: Arc<RwLock<SslAcceptor>>,
+}
+
+impl fmt::Debug for ReloadableAcceptor {
+    fn fmt( &self, f: &mut fmt::Formatter ) -> fmt::Result {
+        f.debug_struct( "ReloadableAcceptor" ).field( "tls", &self.tls ).finish()
+    }
+}
+
+impl ReloadableAcceptor {
+    pub fn new( tls: ServerTls ) -> io::Result<Self> {
+        let acceptor = tls.acceptor()?;
+        Ok( ReloadableAcceptor { tls, acceptor: Arc::new( RwLock::new( acceptor ) ), } )
+    }
+
+    pub fn files( &self ) -> Vec<&Path> {
+        self.tls.files()
+    }
+
+    /// Rereads the certificate files; the current acceptor stays in use if they are invalid.
+    pub fn reload( &self ) -> io::Result<()> {
+        let acceptor = self.tls.acceptor()?;
+        *self.acceptor.write().unwrap() = acceptor;
+        Ok( () )
+    }
+
+    /// The acceptor as a service completing the TLS handshake of accepted connections.
+    pub fn service<T, P>( &self ) -> TlsAcceptor<T, P> {
+        TlsAcceptor { acceptor: self.clone(), io: PhantomData, }
+    }
+
+    fn current( &self ) -> SslAcceptor {
+        self.acceptor.read().unwrap().clone()
+    }
+}
+
+pub struct TlsAcceptor<T, P> {
+    acceptor: ReloadableAcceptor,
+    io: PhantomData<(T, P)>,
+}
+
+impl<T: AsyncRead + AsyncWrite, P> NewService for TlsAcceptor<T, P> {
+    type Request = Io<T, P>;
+    type Response = Io<TlsStream<T>, P>;
+    type Error = io::Error;
+    type Config = ServerConfig;
+    type Service = TlsAcceptorService<T, P>;
+    type InitError = ();
+    type Future = FutureResult<Self::Service, Self::InitError>;
+
+    fn new_service( &self, cfg: &ServerConfig ) -> Self::Future {
+        cfg.set_secure();
+        ok( TlsAcceptorService { acceptor: self.acceptor.clone(), io: PhantomData, } )
+    }
+}
+
+pub struct TlsAcceptorService<T, P> {
+    acceptor: ReloadableAcceptor,
+    io: PhantomData<(T, P)>,
+}
+
+impl<T: AsyncRead + AsyncWrite, P> Service for TlsAcceptorService<T, P> {
+    type Request = Io<T, P>;
+    type Response = Io<TlsStream<T>, P>;
+    type Error = io::Error;
+    type Future = AcceptFuture<T, P>;
+
+    fn poll_ready( &mut self ) -> Poll<(), Self::Error> {
+        Ok( Async::Ready( () ) )
+    }
+
+    fn call( &mut self, req: Self::Request ) -> Self::Future {
+        let ( stream, params, _ ) = req.into_parts();
+        AcceptFuture {
+            handshake: Handshake::Start { acceptor: self.acceptor.current(), stream: Some( stream ) },
+            params: Some( params ),
+        }
+    }
+}
+
+pub struct AcceptFuture<T, P> {
+    handshake: Handshake<T>,
+    params: Option<P>,
+}
+
+impl<T: Read + Write, P> Future for AcceptFuture<T, P> {
+    type Item = Io<TlsStream<T>, P>;
+    type Error = io::Error;
+
+    fn poll( &mut self ) -> Poll<Self::Item, Self::Error> {
+        let stream = futures::try_ready!( self.handshake.poll() );
+        Ok( Async::Ready( Io::from_parts( stream, self.params.take().unwrap(), Protocol::Unknown ) ) )
+    }
+}
+
+enum Handshake<S> {
+    Start { acceptor: SslAcceptor, stream: Option<S> },
+    Midway( Option<MidHandshakeSslStream<S>> ),
+}
+
+impl<S: Read + Write> Future for Handshake<S> {
+    type Item = TlsStream<S>;
+    type Error = io::Error;
+
+    fn poll( &mut self ) -> Poll<Self::Item, Self::Error> {
+        let result = match self {
+            Handshake::Start { acceptor, stream } => acceptor.accept( stream.take().unwrap() ),
+            Handshake::Midway( mid ) => mid.take().unwrap().handshake(),
+        };
+
+        match result {
+            Ok(stream) => Ok( Async::Ready( TlsStream( stream ) ) ),
+            Err(HandshakeError::WouldBlock(mid)) => {
+                *self = Handshake::Midway( Some( mid ) );
+                Ok( Async::NotReady )
+            },
+            Err(HandshakeError::Failure(mid)) => Err( io::Error::other( mid.into_error() ) ),
+            Err(HandshakeError::SetupFailure(e)) => Err( io::Error::other( e ) ),
+        }
+    }
+}
+
+/// A TLS session over an accepted connection.
+#[derive(Debug)]
+pub struct TlsStream<S>( SslStream<S> );
+
+impl<S> TlsStream<S> {
+    /// The caller named by the verified client certificate, known by its subject common name
+    /// and its DNS, URI and email alternative names.
+    pub fn peer_identity( &self ) -> ConnectionIdentity {
+        let cert = match self.0.ssl().peer_certificate() {
+            Some(cert) => cert,
+            None => return ConnectionIdentity::default(),
+        };
+
+        let mut names = cert.subject_name()
+            .entries_by_nid( Nid::COMMONNAME )
+            .filter_map( |entry| entry.data().to_string().ok() )
+            .collect::<Vec<String>>();
+
+        if let Some(alt_names) = cert.subject_alt_names() {
+            names.extend(
+                alt_names.iter()
+                    .filter_map( |n| n.dnsname().or_else( || n.uri() ).or_else( || n.email() ) )
+                    .map( |n| n.to_string() )
+            );
+        }
+
+        ConnectionIdentity( CallerIdentity::from_names( names ) )
+    }
+}
+
+impl<S: Read + Write> Read for TlsStream<S> {
+    fn read( &mut self, buf: &mut [u8] ) -> io::Result<usize> {
+        self.0.read( buf )
+    }
+}
+
+impl<S: Read + Write> Write for TlsStream<S> {
+    fn write( &mut self, buf: &[u8] ) -> io::Result<usize> {
+        self.0.write( buf )
+    }
+
+    fn flush( &mut self ) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> AsyncRead for TlsStream<S> {}
+
+impl<S: AsyncRead + AsyncWrite> AsyncWrite for TlsStream<S> {
+    fn shutdown( &mut self ) -> Poll<(), io::Error> {
+        match self.0.shutdown() {
+            Ok(_) => self.0.get_mut().shutdown(),
+            Err(ref e) if e.code() == ErrorCode::ZERO_RETURN => self.0.get_mut().shutdown(),
+            Err(e) => match e.into_io_error() {
+                Ok(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok( Async::NotReady ),
+                Ok(e) => Err( e ),
+                Err(e) => Err( io::Error::other( e ) ),
+            },
+        }
+    }
+}
+
+impl<S: IoStream> IoStream for TlsStream<S> {
+    fn peer_addr( &self ) -> Option<SocketAddr> {
+        self.0.get_ref().peer_addr()
+    }
+
+    fn set_nodelay( &mut self, nodelay: bool ) -> io::Result<()> {
+        self.0.get_mut().set_nodelay( nodelay )
+    }
+
+    fn set_linger( &mut self, dur: Option<Duration> ) -> io::Result<()> {
+        self.0.get_mut().set_linger( dur )
+    }
+
+    fn set_keepalive( &mut self, dur: Option<Duration> ) -> io::Result<()> {
+        self.0.get_mut().set_keepalive( dur )
+    }
+}
@@ -0,0 +1,340 @@
+use std::cell::{Cell, RefCell};
+use std::io::{self, Read};
+use std::marker::PhantomData;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use actix_codec::Framed;
+use actix_connect::Connect;
+use actix_http::{HttpMessage, Request, Response};
+use actix_http::body::{BodySize, MessageBody};
+use actix_http::h1::{Codec, Message};
+use actix_http::http::{Method, Uri};
+use actix_server_config::ServerConfig;
+use actix_service::{NewService, Service};
+use actix_web::Error;
+use actix_web::dev::ServiceResponse;
+use bytes::BytesMut;
+use futures::{try_ready, stream, Async, Future, Poll, Sink, Stream, future::Either};
+use lazy_static::*;
+use log::{debug, warn};
+use prometheus::{HistogramVec, IntCounter, IntCounterVec, IntGaugeVec};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::io::{copy, shutdown, write_all};
+use tokio_timer::{Delay, Timeout};
+use crate::border::denial::DenialReason;
+use crate::border::visa::Visa;
+use crate::limits::quota::{QuotaTicket, Usage};
+use crate::upstream::CONNECT_TIMEOUT;
+
+lazy_static! {
+    pub static ref TUNNEL_BYTES_TOTAL: IntCounterVec = register_int_counter_vec!(
+        opts!(
+            "egress_tunnel_bytes_total",
+            "Total bytes relayed through CONNECT tunnels, out to the destination or in from it."
+        ).const_labels( crate::metrics::const_labels() ),
+        &["destination", "direction"]
+    )
+    .unwrap();
+
+    pub static ref TUNNELS_OPEN: IntGaugeVec = register_int_gauge_vec!(
+        opts!(
+            "egress_tunnels_open",
+            "Number of CONNECT tunnels currently open."
+        ).const_labels( crate::metrics::const_labels() ),
+        &["destination"]
+    )
+    .unwrap();
+
+    pub static ref TUNNEL_DURATION_HISTOGRAM: HistogramVec = register_histogram_vec!(
+        histogram_opts!(
+            "egress_tunnel_duration_seconds",
+            "How long CONNECT tunnels stay open."
+        ).const_labels( crate::metrics::const_labels() ),
+        &["destination"]
+    )
+    .unwrap();
+}
+
+const ESTABLISHED: &[u8] = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+
+/// How long a tunnel may stay open with neither side sending anything.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs( 300 );
+
+/// Opens `CONNECT` tunnels for callers allowed their target. Each CONNECT request is put to
+/// `app`, the proxy's own service, so it is authenticated, decided on, rate-limited and counted
+/// against quotas as any other request; tunnel bytes count against byte quotas once it closes.
+/// The target host selects the destination as the host of an absolute-form request URI would,
+/// and its port must be the destination's. Tunnels relay bytes as they are, so destination
+/// routes and header obligations do not apply to them. Served as the upgrade handler of the
+/// HTTP service.
+pub struct Tunnels<A, T> {
+    app: A,
+    io: PhantomData<T>,
+}
+
+impl<A, T> Tunnels<A, T> {
+    pub fn new( app: A ) -> Self {
+        Tunnels { app, io: PhantomData, }
+    }
+}
+
+/// The host and port a CONNECT request asks to tunnel to.
+pub fn target( uri: &Uri ) -> Option<( String, u16 )> {
+    let authority = uri.authority_part()?;
+    Some( ( authority.host().to_string(), authority.port_u16()? ) )
+}
+
+/// The visa for a tunnel to `target`, if it is for a destination named by, or at, the target
+/// host and for the target port; a header may select another destination than the one the
+/// tunnel is for.
+pub fn admit_target( target: &( String, u16 ), visa: Visa ) -> Result<Visa, DenialReason> {
+    let ( ref host, port ) = *target;
+    let named = visa.rule.eq_ignore_ascii_case( host ) || visa.destination.host.to_string().eq_ignore_ascii_case( host );
+    if !named {
+        return Err( DenialReason::UnknownDestination( host.clone() ) );
+    }
+    if visa.destination.port != port {
+        return Err( DenialReason::PortNotAllowed { port, destination: visa.rule } );
+    }
+    Ok( visa )
+}
+
+impl<A, B, T> NewService for Tunnels<A, T>
+where
+    A: NewService<Config = ServerConfig, Request = Request, Response = ServiceResponse<B>, Error = Error>,
+    A::Service: 'static,
+    A::Future: 'static,
+    B: MessageBody + 'static,
+    T: AsyncRead + AsyncWrite + 'static,
+{
+    type Request = ( Request, Framed<T, Codec> );
+    type Response = ();
+    type Error = io::Error;
+    type Config = ServerConfig;
+    type Service = TunnelHandler<A::Service, T>;
+    type InitError = ();
+    type Future = Box<dyn Future<Item = Self::Service, Error = Self::InitError>>;
+
+    fn new_service( &self, config: &ServerConfig ) -> Self::Future {
+        Box::new(
+            self.app.new_service( config )
+                .map( |app| TunnelHandler { app: Rc::new( RefCell::new( app ) ), io: PhantomData, } )
+                .map_err( |_| () )
+        )
+    }
+}
+
+pub struct TunnelHandler<S, T> {
+    app: Rc<RefCell<S>>,
+    io: PhantomData<T>,
+}
+
+impl<S, B, T> Service for TunnelHandler<S, T>
+where
+    S: Service<Request = Request, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+    T: AsyncRead + AsyncWrite + 'static,
+{
+    type Request = ( Request, Framed<T, Codec> );
+    type Response = ();
+    type Error = io::Error;
+    type Future = Box<dyn Future<Item = (), Error = io::Error>>;
+
+    fn poll_ready( &mut self ) -> Poll<(), Self::Error> {
+        Ok( Async::Ready( () ) )
+    }
+
+    fn call( &mut self, ( req, framed ): Self::Request ) -> Self::Future {
+        // other protocol upgrades, e.g. websockets, are not proxied
+        if req.method() != Method::CONNECT {
+            return Box::new( respond( framed, Response::NotImplemented().finish() ) );
+        }
+
+        if target( req.uri() ).is_none() {
+            return Box::new( respond( framed, Response::BadRequest().finish() ) );
+        }
+
+        // the proxy answers a granted CONNECT with an empty 200, leaving the tunnel to be opened
+        // here under the visa it was granted
+        Box::new( self.app.borrow_mut().call( req ).then( move |res| match res {
+            Ok(res) => {
+                let granted = res.status().is_success();
+                let visa = res.request().extensions().get::<Visa>().cloned().filter( |_| granted );
+                let ticket = res.request().extensions().get::<QuotaTicket>().cloned();
+                match visa {
+                    Some(visa) => {
+                        debug!( "tunnel granted under rule {} to {}:{}", visa.rule, visa.destination.host, visa.destination.port );
+                        Either::A( open( framed, visa, ticket ) )
+                    },
+                    None => Either::B( Either::A( respond( framed, res.into() ) ) ),
+                }
+            },
+
+            Err(e) => Either::B( Either::B( respond( framed, e.as_response_error().error_response() ) ) ),
+        } ) )
+    }
+}
+
+/// Writes a complete response to the caller in place of a tunnel.
+fn respond<T, B>( framed: Framed<T, Codec>, mut res: Response<B> ) -> impl Future<Item = (), Error = io::Error>
+where
+    T: AsyncRead + AsyncWrite,
+    B: MessageBody,
+{
+    let mut body = res.take_body();
+    let head = res.drop_body();
+
+    stream::poll_fn( move || body.poll_next() )
+        .map_err( |e| io::Error::other( e.to_string() ) )
+        .fold( BytesMut::new(), |mut bytes, chunk| {
+            bytes.extend_from_slice( &chunk );
+            Ok::<_, io::Error>( bytes )
+        } )
+        .and_then( move |body| {
+            let body = body.freeze();
+            framed.send( Message::Item( ( head, BodySize::Sized( body.len() ) ) ) )
+                .and_then( move |framed| framed.send( Message::Chunk( Some( body ) ) ) )
+        } )
+        .and_then( |framed| framed.send( Message::Chunk( None ) ) )
+        .map( |_| () )
+}
+
+/// Connects to the visa's destination, at its checked address, and relays the caller's
+/// connection to it.
+fn open<T>( framed: Framed<T, Codec>, visa: Visa, ticket: Option<QuotaTicket> ) -> impl Future<Item = (), Error = io::Error>
+where
+    T: AsyncRead + AsyncWrite + 'static,
+{
+    let host = visa.destination.host.to_string();
+    let connect = Connect::new( host.clone() ).set_port( visa.destination.port ).set_addr( visa.resolved );
+    let connection = Timeout::new( actix_connect::default_connector().call( connect ), CONNECT_TIMEOUT );
+
+    connection.then( move |connection| match connection {
+        Ok(connection) => {
+            let ( upstream, _ ) = connection.into_parts();
+            let parts = framed.into_parts();
+            Either::A( relay( parts.io, parts.read_buf, upstream, visa.rule, ticket ) )
+        },
+
+        Err(e) => {
+            warn!( "egress tunnel to {} under rule {} failed to connect: {:?}", host, visa.rule, e );
+            Either::B( respond( framed, Response::BadGateway().finish() ) )
+        },
+    } )
+}
+
+/// Relays bytes between the caller and the destination until both sides have closed, or
+/// neither has sent anything for `IDLE_TIMEOUT`. Each side closing is passed on to the other
+/// as it happens. Bytes the caller sent ahead of the tunnel being established are passed on
+/// first.
+fn relay<C, U>( client: C, early: BytesMut, upstream: U, rule: String, ticket: Option<QuotaTicket> ) -> impl Future<Item = (), Error = io::Error>
+where
+    C: AsyncRead + AsyncWrite + 'static,
+    U: AsyncRead + AsyncWrite + 'static,
+{
+    let opened = Instant::now();
+    let activity = Rc::new( Cell::new( opened ) );
+    let sent = Metered::new( TUNNEL_BYTES_TOTAL.with( &labels!{ "destination" => rule.as_str(), "direction" => "out", } ), &activity );
+    let received = Metered::new( TUNNEL_BYTES_TOTAL.with( &labels!{ "destination" => rule.as_str(), "direction" => "in", } ), &activity );
+    let ( sent_total, received_total ) = ( sent.total.clone(), received.total.clone() );
+    let open_gauge = TUNNELS_OPEN.with( &labels!{ "destination" => rule.as_str(), } );
+
+    open_gauge.inc();
+    sent.count( early.len() );
+
+    let idle = Idle { activity: activity.clone(), delay: Delay::new( opened + IDLE_TIMEOUT ), };
+    let relayed = write_all( client, ESTABLISHED )
+        .join( write_all( upstream, early ) )
+        .and_then( move |( ( client, _ ), ( upstream, _ ) )| {
+            let ( client_reader, client_writer ) = client.split();
+            let ( upstream_reader, upstream_writer ) = upstream.split();
+
+            let outbound = copy( sent.reading( client_reader ), upstream_writer ).and_then( |( _, _, writer )| shutdown( writer ) );
+            let inbound = copy( received.reading( upstream_reader ), client_writer ).and_then( |( _, _, writer )| shutdown( writer ) );
+            outbound.join( inbound )
+        } );
+
+    relayed.select2( idle )
+        .then( move |result| {
+            open_gauge.dec();
+            let elapsed = opened.elapsed();
+            TUNNEL_DURATION_HISTOGRAM.with( &labels!{ "destination" => rule.as_str(), } )
+                .observe( elapsed.as_secs() as f64 + f64::from( elapsed.subsec_nanos() ) / 1e9 );
+
+            if let Some(ticket) = ticket {
+                ticket.record( &Usage::bytes( received_total.get(), sent_total.get() ) );
+            }
+
+            match result {
+                Ok(Either::A(_)) => debug!( "egress tunnel under rule {} closed after {:?}", rule, elapsed ),
+                Ok(Either::B(_)) => debug!( "egress tunnel under rule {} closed idle after {:?}", rule, elapsed ),
+                Err(Either::A(( ref e, _ ))) | Err(Either::B(( ref e, _ ))) => {
+                    debug!( "egress tunnel under rule {} failed after {:?}: {}", rule, elapsed, e )
+                },
+            }
+            Ok( () )
+        } )
+}
+
+/// Counts the bytes relayed one way through a tunnel, noting when any last passed.
+struct Metered {
+    bytes: IntCounter,
+    total: Rc<Cell<u64>>,
+    activity: Rc<Cell<Instant>>,
+}
+
+impl Metered {
+    fn new( bytes: IntCounter, activity: &Rc<Cell<Instant>> ) -> Self {
+        Metered { bytes, total: Rc::new( Cell::new( 0 ) ), activity: activity.clone(), }
+    }
+
+    fn count( &self, n: usize ) {
+        self.bytes.inc_by( n as i64 );
+        self.total.set( self.total.get() + n as u64 );
+        if n > 0 {
+            self.activity.set( Instant::now() );
+        }
+    }
+
+    fn reading<R>( self, inner: R ) -> MeteredReader<R> {
+        MeteredReader { inner, meter: self, }
+    }
+}
+
+struct MeteredReader<R> {
+    inner: R,
+    meter: Metered,
+}
+
+impl<R: Read> Read for MeteredReader<R> {
+    fn read( &mut self, buf: &mut [u8] ) -> io::Result<usize> {
+        let n = self.inner.read( buf )?;
+        self.meter.count( n );
+        Ok( n )
+    }
+}
+
+impl<R: AsyncRead> AsyncRead for MeteredReader<R> {}
+
+/// Resolves once nothing has passed through a tunnel for `IDLE_TIMEOUT`.
+struct Idle {
+    activity: Rc<Cell<Instant>>,
+    delay: Delay,
+}
+
+impl Future for Idle {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll( &mut self ) -> Poll<(), io::Error> {
+        loop {
+            try_ready!( self.delay.poll().map_err( io::Error::other ) );
+
+            let deadline = self.activity.get() + IDLE_TIMEOUT;
+            if deadline <= Instant::now() {
+                return Ok( Async::Ready( () ) );
+            }
+            self.delay.reset( deadline );
+        }
+    }
+}
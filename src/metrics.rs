@@ -1,29 +1,44 @@
 use lazy_static::*;
 use prometheus::IntGauge;
+use std::collections::HashMap;
 use std::rc::Rc;
+use std::sync::RwLock;
 
 lazy_static! {
+    static ref CONST_LABELS: RwLock<HashMap<String, String>> = RwLock::new(
+        labels! {
+            String::from("realm") => String::from("ex-realm"),
+            String::from("pipeline_id") => String::from("ex-pipeline-id"),
+        }
+    );
+
     pub static ref HTTP_BODY_GAUGE_BYTES: IntGauge = register_int_gauge!(
         opts!(
             "egress_http_response_size_bytes",
-            "The HTTP response sizes in bytes.",
-            labels! {
-                "realm" => "ex-realm",
-                "pipeline_id" => "ex-pipeline-id",
-            }
-        )
+            "The HTTP response sizes in bytes."
+        ).const_labels( const_labels() )
     )
     .unwrap();
 }
 
+/// Overrides the constant labels attached to every egress metric. Metrics register lazily on
+/// first use, so this must be called before the proxy starts serving.
+pub fn set_const_labels<I: IntoIterator<Item = (String, String)>>( labels: I ) {
+    CONST_LABELS.write().unwrap().extend( labels );
+}
+
+pub fn const_labels() -> HashMap<String, String> {
+    CONST_LABELS.read().unwrap().clone()
+}
+
 pub struct MetricsCollection(pub Rc<Family> );
 
 pub struct Family {
     pub body_size: &'static IntGauge,
 }
 
-impl MetricsCollection {
-    pub fn new() -> Self {
+impl Default for MetricsCollection {
+    fn default() -> Self {
         MetricsCollection(
             Rc::new(
                 Family {
@@ -32,4 +47,10 @@ impl MetricsCollection {
             )
         )
     }
+}
+
+impl MetricsCollection {
+    pub fn new() -> Self {
+        MetricsCollection::default()
+    }
 }
\ No newline at end of file
@@ -0,0 +1,111 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use prometheus::IntCounterVec;
+use log::info;
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::{Error, HttpMessage, ResponseError};
+use futures::{Future, Poll, future::{ok, Either, FutureResult}};
+use crate::auth::Authenticator;
+use super::proxy_filter::BLOCKED_TOTAL;
+
+/// Authenticates callers before the proxy filter decides on their requests, attaching the
+/// identity they authenticate as, so it must be wrapped outside the proxy filter.
+pub struct AuthenticationCollection( Rc<Family> );
+
+struct Family {
+    authenticator: Option<Authenticator>,
+    blocked: &'static IntCounterVec,
+}
+
+impl Default for AuthenticationCollection {
+    fn default() -> Self {
+        AuthenticationCollection( Rc::new( Family { authenticator: None, blocked: &BLOCKED_TOTAL, } ) )
+    }
+}
+
+impl AuthenticationCollection {
+    pub fn new() -> Self {
+        AuthenticationCollection::default()
+    }
+
+    pub fn with_authenticator( mut self, authenticator: Authenticator ) -> Self {
+        let family = Rc::get_mut( &mut self.0 ).unwrap();
+        family.authenticator = Some( authenticator );
+        self
+    }
+}
+
+impl<S, B> Transform<S> for AuthenticationCollection
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AuthenticationMiddleware<S>;
+    type Future = FutureResult<Self::Transform, Self::InitError>;
+
+    fn new_transform( &self, service: S ) -> Self::Future {
+        ok( AuthenticationMiddleware { service: Rc::new( RefCell::new( service ) ), family: self.0.clone(), } )
+    }
+}
+
+pub struct AuthenticationMiddleware<S> {
+    family: Rc<Family>,
+    service: Rc<RefCell<S>>,
+}
+
+impl<S, B> Service for AuthenticationMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn poll_ready( &mut self ) -> Poll<(), Self::Error> {
+        self.service.borrow_mut().poll_ready()
+    }
+
+    fn call( &mut self, mut req: ServiceRequest ) -> Self::Future {
+        let authentication = match self.family.authenticator.as_ref() {
+            Some(authenticator) => authenticator.authenticate( &req ),
+            None => return Box::new( self.service.borrow_mut().call( req ) ),
+        };
+
+        let family = self.family.clone();
+        let service = self.service.clone();
+        Box::new( authentication.then( move |identity| match identity {
+            Ok(identity) => {
+                if let Some(identity) = identity {
+                    req.extensions_mut().insert( identity );
+                }
+                if let Some(ref authenticator) = family.authenticator {
+                    authenticator.strip_credentials( &mut req );
+                }
+                Either::A( service.borrow_mut().call( req ) )
+            },
+
+            Err(reason) => {
+                let blocked = family.blocked.with(
+                    &labels!{
+                        "method" => req.method().as_str(),
+                        "destination" => "",
+                        "reason" => reason.code(),
+                    }
+                );
+                blocked.inc();
+
+                info!( "egress request {} {} blocked: {}", req.method(), req.uri(), reason );
+                Either::B( ok( req.into_response( reason.error_response().into_body() ) ) )
+            },
+        } ) )
+    }
+}
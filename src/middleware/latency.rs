@@ -18,12 +18,7 @@ lazy_static! {
         histogram_opts!(
             "egress_http_proxy_total_duration_seconds",
             "The total time in the egress proxy and egress request"
-        ).const_labels(
-            labels! {
-                String::from("realm") => String::from("ex-realm"),
-                String::from("pipeline_id") => String::from("ex-pipeline_id"),
-            }
-        ),
+        ).const_labels( crate::metrics::const_labels() ),
         &["method"]
     )
     .unwrap();
@@ -32,12 +27,7 @@ lazy_static! {
         histogram_opts!(
             "egress_http_proxy_overhead_duration_seconds",
             "The overhead time in egress proxy outside of egress request"
-        ).const_labels(
-            labels! {
-                String::from("realm") => String::from("ex-realm"),
-                String::from("pipeline_id") => String::from("ex-pipeline_id"),
-            }
-        ),
+        ).const_labels( crate::metrics::const_labels() ),
         &["method"]
     )
     .unwrap();
@@ -46,12 +36,7 @@ lazy_static! {
         histogram_opts!(
             "egress_http_request_duration_seconds",
             "The external HTTP request latencies in seconds."
-        ).const_labels(
-            labels! {
-                String::from("realm") => String::from("ex-realm"),
-                String::from("pipeline_id") => String::from("ex-pipeline-id"),
-            }
-        ),
+        ).const_labels( crate::metrics::const_labels() ),
         &["method"]
     )
     .unwrap();
@@ -66,8 +51,8 @@ struct Family {
     overhead: &'static HistogramVec,
 }
 
-impl MeasureLatencyCollection {
-    pub fn new() -> Self {
+impl Default for MeasureLatencyCollection {
+    fn default() -> Self {
         MeasureLatencyCollection(
             Rc::new(
                 Family {
@@ -80,6 +65,12 @@ impl MeasureLatencyCollection {
     }
 }
 
+impl MeasureLatencyCollection {
+    pub fn new() -> Self {
+        MeasureLatencyCollection::default()
+    }
+}
+
 impl<S, B> Transform<S> for MeasureLatencyCollection
 where
     S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
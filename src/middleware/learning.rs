@@ -0,0 +1,80 @@
+use std::rc::Rc;
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::{Error, HttpMessage};
+use futures::{Poll, future::{ok, FutureResult}};
+use crate::border::learning::Learner;
+use crate::border::visa::Visa;
+
+/// Records every request granted a visa with a `Learner`, so it must be wrapped inside the
+/// proxy filter.
+pub struct LearningCollection( Rc<Family> );
+
+struct Family {
+    learner: Option<Learner>,
+}
+
+impl Default for LearningCollection {
+    fn default() -> Self {
+        LearningCollection( Rc::new( Family { learner: None, } ) )
+    }
+}
+
+impl LearningCollection {
+    pub fn new() -> Self {
+        LearningCollection::default()
+    }
+
+    pub fn with_learner( mut self, learner: Learner ) -> Self {
+        let family = Rc::get_mut( &mut self.0 ).unwrap();
+        family.learner = Some( learner );
+        self
+    }
+}
+
+impl<S, B> Transform<S> for LearningCollection
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = LearningMiddleware<S>;
+    type Future = FutureResult<Self::Transform, Self::InitError>;
+
+    fn new_transform( &self, service: S ) -> Self::Future {
+        ok( LearningMiddleware { service, family: self.0.clone(), } )
+    }
+}
+
+pub struct LearningMiddleware<S> {
+    family: Rc<Family>,
+    service: S,
+}
+
+impl<S, B> Service for LearningMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = S::Future;
+
+    fn poll_ready( &mut self ) -> Poll<(), Self::Error> {
+        self.service.poll_ready()
+    }
+
+    fn call( &mut self, req: ServiceRequest ) -> Self::Future {
+        if let ( Some(learner), Some(visa) ) = ( self.family.learner.as_ref(), req.extensions().get::<Visa>() ) {
+            learner.observe( visa, req.method(), visa.forwarded_path( req.path() ) );
+        }
+
+        self.service.call( req )
+    }
+}
@@ -1,2 +1,6 @@
+pub mod authentication;
 pub mod latency;
-pub mod proxy_filter;
\ No newline at end of file
+pub mod learning;
+pub mod proxy_filter;
+pub mod quota;
+pub mod rate_limit;
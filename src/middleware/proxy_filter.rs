@@ -1,48 +1,93 @@
+use std::cell::RefCell;
 use std::rc::Rc;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use prometheus::IntCounterVec;
 use lazy_static::*;
+use log::{debug, info, warn};
 use actix_service::{Service, Transform};
 use actix_web::dev::{ServiceRequest, ServiceResponse};
-use actix_web::{http, Error, HttpResponse};
-use futures::{Poll, future::{ok, Either, FutureResult}};
-use crate::border::{BorderControl, host_control::HostControlBuilder, BorderControlBuilder};
+use actix_web::{http, Error, HttpMessage, HttpResponse, ResponseError};
+use futures::{Future, Poll, future::{ok, Either, FutureResult}};
+use tokio_timer::Timeout;
+use crate::border::{AsyncBorderControl, BorderControl, BorderControlBuilder};
+use crate::border::denial::DenialReason;
+use crate::border::visa::Visa;
+use crate::border::host_control::HostControlBuilder;
+use crate::listener::tunnel;
 
 
 lazy_static! {
     pub static ref ALLOWED_TOTAL: IntCounterVec = register_int_counter_vec!(
         opts!(
             "egress_http_request_allowed_total",
-            "Total number of egress HTTP requests allowed.",
-            labels! {
-                "realm" => "ex-realm",
-                "pipeline_id" => "ex-pipeline-id",
-            }
-        ),
-        &["method"]
+            "Total number of egress HTTP requests allowed."
+        ).const_labels( crate::metrics::const_labels() ),
+        &["method", "destination"]
     )
     .unwrap();
 
     pub static ref BLOCKED_TOTAL: IntCounterVec = register_int_counter_vec!(
         opts!(
             "egress_http_request_blocked_total",
-            "Total number of egress HTTP requests blocked.",
-            labels! {
-                "realm" => "ex-realm",
-                "pipeline_id" => "ex-pipeline-id",
-            }
-        ),
-        &["method"]
+            "Total number of egress HTTP requests blocked."
+        ).const_labels( crate::metrics::const_labels() ),
+        &["method", "destination", "reason"]
     )
     .unwrap();
+
+    pub static ref SHADOW_DENIED_TOTAL: IntCounterVec = register_int_counter_vec!(
+        opts!(
+            "egress_http_request_shadow_denied_total",
+            "Total number of allowed egress HTTP requests the shadow policy would have blocked."
+        ).const_labels( crate::metrics::const_labels() ),
+        &["method", "destination", "reason"]
+    )
+    .unwrap();
+
+    pub static ref SHADOW_ALLOWED_TOTAL: IntCounterVec = register_int_counter_vec!(
+        opts!(
+            "egress_http_request_shadow_allowed_total",
+            "Total number of blocked egress HTTP requests the shadow policy would have allowed."
+        ).const_labels( crate::metrics::const_labels() ),
+        &["method", "destination", "reason"]
+    )
+    .unwrap();
+
+    pub static ref SHADOW_DIVERGED_TOTAL: IntCounterVec = register_int_counter_vec!(
+        opts!(
+            "egress_http_request_shadow_diverged_total",
+            "Total number of allowed egress HTTP requests the shadow policy would have sent under another rule or to another destination."
+        ).const_labels( crate::metrics::const_labels() ),
+        &["method", "destination", "shadow_destination"]
+    )
+    .unwrap();
+}
+
+pub const DEFAULT_DECISION_TIMEOUT: Duration = Duration::from_secs( 5 );
+
+/// What the filter does when the border policy cannot decide in time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FailMode {
+    /// Refuse the request as `policy_unavailable`.
+    Closed,
+    /// Decide by the policy if it can without I/O, else refuse as `Closed` does. Policies that
+    /// check the addresses destinations resolve to cannot, so they always fail closed.
+    Open,
 }
 
 pub struct ProxyFilterCollection( Rc<Family> );
 
 struct Family {
-    border: Box<dyn BorderControl>,
+    border: Box<dyn AsyncBorderControl>,
+    decision_timeout: Duration,
+    fail_mode: FailMode,
+    shadow: Option<Box<dyn AsyncBorderControl>>,
     allowed: &'static IntCounterVec,
     blocked: &'static IntCounterVec,
+    shadow_denied: &'static IntCounterVec,
+    shadow_allowed: &'static IntCounterVec,
+    shadow_diverged: &'static IntCounterVec,
 }
 
 impl Default for ProxyFilterCollection {
@@ -50,9 +95,15 @@ impl Default for ProxyFilterCollection {
         ProxyFilterCollection(
             Rc::new(
                 Family {
-                    border: HostControlBuilder::new().build(),
+                    border: Box::new( HostControlBuilder::new().build() ),
+                    decision_timeout: DEFAULT_DECISION_TIMEOUT,
+                    fail_mode: FailMode::Closed,
+                    shadow: None,
                     allowed: &ALLOWED_TOTAL,
                     blocked: &BLOCKED_TOTAL,
+                    shadow_denied: &SHADOW_DENIED_TOTAL,
+                    shadow_allowed: &SHADOW_ALLOWED_TOTAL,
+                    shadow_diverged: &SHADOW_DIVERGED_TOTAL,
                 }
             )
         )
@@ -64,17 +115,119 @@ impl ProxyFilterCollection {
         ProxyFilterCollection::default()
     }
 
-    pub fn with_border( mut self, border: Box<dyn BorderControl> ) -> Self {
+    pub fn with_border( self, border: Box<dyn BorderControl> ) -> Self {
+        self.with_async_border( Box::new( border ) )
+    }
+
+    pub fn with_async_border( mut self, border: Box<dyn AsyncBorderControl> ) -> Self {
         let family = Rc::get_mut( &mut self.0 ).unwrap();
         family.border = border;
         self
     }
+
+    /// Bounds how long the filter waits for the border policy to decide.
+    pub fn with_decision_timeout( mut self, timeout: Duration ) -> Self {
+        let family = Rc::get_mut( &mut self.0 ).unwrap();
+        family.decision_timeout = timeout;
+        self
+    }
+
+    pub fn with_fail_mode( mut self, fail_mode: FailMode ) -> Self {
+        let family = Rc::get_mut( &mut self.0 ).unwrap();
+        family.fail_mode = fail_mode;
+        self
+    }
+
+    /// Evaluates a candidate policy alongside the enforced one. The candidate never blocks a
+    /// request, nor holds it up; decisions that would differ are logged and counted.
+    pub fn with_shadow_border( mut self, border: Box<dyn AsyncBorderControl> ) -> Self {
+        let family = Rc::get_mut( &mut self.0 ).unwrap();
+        family.shadow = Some( border );
+        self
+    }
+}
+
+impl Family {
+    fn decide_on_failure( &self, req: &ServiceRequest ) -> Result<Visa, DenialReason> {
+        match self.fail_mode {
+            FailMode::Closed => Err( DenialReason::PolicyUnavailable ),
+            FailMode::Open => self.border.decide_without_io( req ).unwrap_or( Err( DenialReason::PolicyUnavailable ) ),
+        }
+    }
+
+    /// Puts the request to the shadow policy, comparing its decision to the enforced one once
+    /// it is made.
+    fn audit( family: &Rc<Family>, req: &ServiceRequest, target: Option<( String, u16 )>, decision: &Result<Visa, DenialReason> ) {
+        let shadow = match family.shadow {
+            Some(ref shadow) => shadow.apply_for_visa( req ),
+            None => return,
+        };
+
+        let family = family.clone();
+        let method = req.method().clone();
+        let uri = req.uri().clone();
+        let decision = decision.clone();
+        actix_rt::spawn( shadow.then( move |shadow| {
+            family.compare( &method, &uri, &decision, admit_target( target.as_ref(), shadow ) );
+            Ok( () )
+        } ) );
+    }
+
+    fn compare( &self, method: &http::Method, uri: &http::Uri, decision: &Result<Visa, DenialReason>, shadow: Result<Visa, DenialReason> ) {
+        match ( decision, shadow ) {
+            ( Ok(visa), Err(reason) ) => {
+                let shadow_denied = self.shadow_denied.with(
+                    &labels!{
+                        "method" => method.as_str(),
+                        "destination" => visa.rule.as_str(),
+                        "reason" => reason.code(),
+                    }
+                );
+                shadow_denied.inc();
+                info!( "shadow policy would block egress request {} {}: {}", method, uri, reason );
+            },
+
+            ( Err(reason), Ok(visa) ) => {
+                let shadow_allowed = self.shadow_allowed.with(
+                    &labels!{
+                        "method" => method.as_str(),
+                        "destination" => visa.rule.as_str(),
+                        "reason" => reason.code(),
+                    }
+                );
+                shadow_allowed.inc();
+                info!(
+                    "shadow policy would allow egress request {} {} under rule {}",
+                    method, uri, visa.rule
+                );
+            },
+
+            ( Ok(enforced), Ok(visa) ) => {
+                if enforced.rule != visa.rule || enforced.destination.to_string() != visa.destination.to_string() {
+                    let shadow_diverged = self.shadow_diverged.with(
+                        &labels!{
+                            "method" => method.as_str(),
+                            "destination" => enforced.rule.as_str(),
+                            "shadow_destination" => visa.rule.as_str(),
+                        }
+                    );
+                    shadow_diverged.inc();
+                    info!(
+                        "shadow policy would send egress request {} {} to {} under rule {} instead of {} under rule {}",
+                        method, uri, visa.destination, visa.rule, enforced.destination, enforced.rule
+                    );
+                }
+            },
+
+            ( Err(_), Err(_) ) => (),
+        }
+    }
 }
 
 
 impl<S, B> Transform<S> for ProxyFilterCollection
 where
-    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
     B: 'static,
 {
@@ -86,48 +239,141 @@ where
     type Future = FutureResult<Self::Transform, Self::InitError>;
 
     fn new_transform( &self, service: S ) -> Self::Future {
-        ok( ProxyFilterMiddleware { service, family: self.0.clone(), } )
+        ok( ProxyFilterMiddleware { service: Rc::new( RefCell::new( service ) ), family: self.0.clone(), } )
     }
 }
 
 pub struct ProxyFilterMiddleware<S> {
     family: Rc<Family>,
-    service: S,
+    service: Rc<RefCell<S>>,
 }
 
 impl<S, B> Service for ProxyFilterMiddleware<S>
 where
-    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
+    B: 'static,
 {
     type Request = ServiceRequest;
     type Response = ServiceResponse<B>;
     type Error = Error;
-    type Future = Either<S::Future, FutureResult<Self::Response, Self::Error>>;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
 
-    fn poll_ready( &mut self ) -> Poll<(), Self::Error> { self.service.poll_ready() }
+    fn poll_ready( &mut self ) -> Poll<(), Self::Error> { self.service.borrow_mut().poll_ready() }
 
     fn call( &mut self, req: ServiceRequest ) -> Self::Future {
-        let method_sel = labels!{ "method" => req.method().as_str(), };
-
-        if let Ok(destination) = self.family.border.request_visa( &req ) {
-            let allowed = self.family.allowed.with( &method_sel );
-            allowed.inc();
-
-            Either::A( self.service.call(req) )
-        } else {
-            let blocked = self.family.blocked.with( &method_sel );
-            blocked.inc();
-
-            Either::B(
-                ok(
-                    req.into_response(
-                        HttpResponse::NotAcceptable()
-                            .finish()
-                            .into_body(),
-                    )
-                )
-            )
-        }
+        let family = self.family.clone();
+        let service = self.service.clone();
+        let decision = Timeout::new( family.border.apply_for_visa( &req ), family.decision_timeout );
+        let target = if req.method() == http::Method::CONNECT { tunnel::target( req.uri() ) } else { None };
+
+        Box::new(
+            decision.then( move |decision| {
+                let decision = decision.or_else( |err| {
+                    if err.is_inner() {
+                        Err( err.into_inner().unwrap() )
+                    } else {
+                        warn!( "egress policy undecided for {} {}: {}", req.method(), req.uri(), err );
+                        family.decide_on_failure( &req )
+                    }
+                } );
+                let decision = admit_target( target.as_ref(), decision );
+                Family::audit( &family, &req, target, &decision );
+
+                match decision {
+                    Ok(visa) => {
+                        let allowed = family.allowed.with(
+                            &labels!{ "method" => req.method().as_str(), "destination" => visa.rule.as_str(), }
+                        );
+                        allowed.inc();
+
+                        debug!( "visa granted under rule {} to {}://{}", visa.rule, visa.scheme, visa.destination );
+                        req.extensions_mut().insert( visa );
+
+                        Either::A( service.borrow_mut().call( req ) )
+                    },
+
+                    Err(reason) => {
+                        let blocked = family.blocked.with(
+                            &labels!{
+                                "method" => req.method().as_str(),
+                                "destination" => reason.destination().unwrap_or( "" ),
+                                "reason" => reason.code(),
+                            }
+                        );
+                        blocked.inc();
+
+                        info!( "egress request {} {} blocked: {}", req.method(), req.uri(), reason );
+                        Either::B( ok( req.into_response( reason.error_response().into_body() ) ) )
+                    },
+                }
+            } )
+        )
+    }
+}
+
+/// Refuses visas for a tunnel to a destination other than the one its target names.
+fn admit_target( target: Option<&( String, u16 )>, decision: Result<Visa, DenialReason> ) -> Result<Visa, DenialReason> {
+    match target {
+        Some(target) => decision.and_then( |visa| tunnel::admit_target( target, visa ) ),
+        None => decision,
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+    use crate::border::host_control::Destination;
+    use super::*;
+
+    fn visa( rule: &str, url: &str ) -> Result<Visa, DenialReason> {
+        let destination: Destination = Url::parse( url ).unwrap().into();
+        Ok( destination.visa( rule ) )
+    }
+
+    fn compare( decision: Result<Visa, DenialReason>, shadow: Result<Visa, DenialReason> ) {
+        let filter = ProxyFilterCollection::new();
+        filter.0.compare( &http::Method::GET, &"/".parse().unwrap(), &decision, shadow );
+    }
+
+    fn count( counter: &IntCounterVec, labels: &[&str] ) -> i64 {
+        counter.with_label_values( labels ).get()
+    }
+
+    #[test]
+    fn counts_requests_the_shadow_would_block() {
+        let labels = [ "GET", "shadow-denied", "no_matching_rule" ];
+        let before = count( &SHADOW_DENIED_TOTAL, &labels );
+        compare( visa( "shadow-denied", "https://a.example.com" ), Err( DenialReason::NoMatchingRule ) );
+        assert_eq!( count( &SHADOW_DENIED_TOTAL, &labels ), before + 1 );
+    }
+
+    #[test]
+    fn counts_requests_the_shadow_would_allow() {
+        let labels = [ "GET", "shadow-allowed", "no_default_destination" ];
+        let before = count( &SHADOW_ALLOWED_TOTAL, &labels );
+        compare( Err( DenialReason::NoDefaultDestination ), visa( "shadow-allowed", "https://a.example.com" ) );
+        assert_eq!( count( &SHADOW_ALLOWED_TOTAL, &labels ), before + 1 );
+    }
+
+    #[test]
+    fn counts_requests_the_shadow_would_send_elsewhere() {
+        let by_rule = [ "GET", "enforced-rule", "shadow-rule" ];
+        let by_destination = [ "GET", "diverged-destination", "diverged-destination" ];
+        let ( rule_before, destination_before ) = ( count( &SHADOW_DIVERGED_TOTAL, &by_rule ), count( &SHADOW_DIVERGED_TOTAL, &by_destination ) );
+
+        compare( visa( "enforced-rule", "https://a.example.com" ), visa( "shadow-rule", "https://a.example.com" ) );
+        compare( visa( "diverged-destination", "https://a.example.com" ), visa( "diverged-destination", "https://b.example.com" ) );
+        assert_eq!( count( &SHADOW_DIVERGED_TOTAL, &by_rule ), rule_before + 1 );
+        assert_eq!( count( &SHADOW_DIVERGED_TOTAL, &by_destination ), destination_before + 1 );
+    }
+
+    #[test]
+    fn does_not_count_agreeing_decisions() {
+        let labels = [ "GET", "agreed", "agreed" ];
+        let before = count( &SHADOW_DIVERGED_TOTAL, &labels );
+        compare( visa( "agreed", "https://a.example.com" ), visa( "agreed", "https://a.example.com" ) );
+        compare( Err( DenialReason::NoMatchingRule ), Err( DenialReason::ClosedBorder ) );
+        assert_eq!( count( &SHADOW_DIVERGED_TOTAL, &labels ), before );
+    }
+}
@@ -0,0 +1,176 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use prometheus::IntCounterVec;
+use log::info;
+use actix_service::{Service, Transform};
+use actix_web::dev::{Body, BodySize, MessageBody, Payload, ResponseBody, ServiceRequest, ServiceResponse};
+use actix_web::{web::Bytes, Error, HttpMessage, ResponseError};
+use actix_http::error::PayloadError;
+use futures::{Async, Future, Poll, Stream, future::{ok, FutureResult}};
+use crate::border::denial::DenialReason;
+use crate::border::visa::Visa;
+use crate::limits::caller_key;
+use crate::limits::quota::{QuotaLedger, QuotaTicket, Usage};
+use super::proxy_filter::BLOCKED_TOTAL;
+
+/// Enforces request and byte quotas on requests already granted a visa, so it must be wrapped
+/// inside the proxy filter. Bytes are counted as the request and response bodies stream through,
+/// and recorded by CONNECT tunnels on the ticket their request leaves in its extensions.
+pub struct QuotaCollection( Rc<Family> );
+
+struct Family {
+    ledger: Option<QuotaLedger>,
+    blocked: &'static IntCounterVec,
+}
+
+impl Default for QuotaCollection {
+    fn default() -> Self {
+        QuotaCollection( Rc::new( Family { ledger: None, blocked: &BLOCKED_TOTAL, } ) )
+    }
+}
+
+impl QuotaCollection {
+    pub fn new() -> Self {
+        QuotaCollection::default()
+    }
+
+    pub fn with_ledger( mut self, ledger: QuotaLedger ) -> Self {
+        let family = Rc::get_mut( &mut self.0 ).unwrap();
+        family.ledger = Some( ledger );
+        self
+    }
+}
+
+impl<S, B> Transform<S> for QuotaCollection
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = QuotaMiddleware<S>;
+    type Future = FutureResult<Self::Transform, Self::InitError>;
+
+    fn new_transform( &self, service: S ) -> Self::Future {
+        ok( QuotaMiddleware { service: Rc::new( RefCell::new( service ) ), family: self.0.clone(), } )
+    }
+}
+
+pub struct QuotaMiddleware<S> {
+    family: Rc<Family>,
+    service: Rc<RefCell<S>>,
+}
+
+impl<S, B> Service for QuotaMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn poll_ready( &mut self ) -> Poll<(), Self::Error> { self.service.borrow_mut().poll_ready() }
+
+    fn call( &mut self, mut req: ServiceRequest ) -> Self::Future {
+        let ( rule, admission ) = match ( self.family.ledger.as_ref(), req.extensions().get::<Visa>() ) {
+            ( Some(ledger), Some(visa) ) => {
+                let quota = visa.obligations.quota.as_ref().unwrap_or( &visa.rule );
+                ( visa.rule.clone(), ledger.admit( quota, &visa.rule, &caller_key( &req ) ) )
+            },
+            _ => ( String::new(), None ),
+        };
+
+        match admission {
+            None => Box::new( self.service.borrow_mut().call( req ) ),
+
+            Some(Ok(ticket)) => {
+                // tunnels count the bytes they relay on the same ticket
+                req.extensions_mut().insert( ticket.clone() );
+                let payload = CountingPayload { inner: req.take_payload(), ticket: ticket.clone(), bytes: 0, };
+                req.set_payload( Payload::Stream( Box::new( payload ) ) );
+
+                Box::new(
+                    self.service.borrow_mut()
+                        .call( req )
+                        .map( move |res| {
+                            res.map_body( |_, body| {
+                                ResponseBody::Other( Body::from_message( CountingBody { inner: body, ticket, bytes: 0, } ) )
+                            } )
+                        } )
+                )
+            },
+
+            Some(Err(reason)) => {
+                let blocked = self.family.blocked.with(
+                    &labels!{
+                        "method" => req.method().as_str(),
+                        "destination" => rule.as_str(),
+                        "reason" => reason.code(),
+                    }
+                );
+                blocked.inc();
+
+                info!( "egress request {} {} blocked: {}", req.method(), req.uri(), reason );
+                Box::new( ok( req.into_response( reason.error_response().into_body() ) ) )
+            },
+        }
+    }
+}
+
+/// Counts the request bytes sent to the destination, recording them once the body is dropped.
+struct CountingPayload {
+    inner: Payload,
+    ticket: QuotaTicket,
+    bytes: u64,
+}
+
+impl Stream for CountingPayload {
+    type Item = Bytes;
+    type Error = PayloadError;
+
+    fn poll( &mut self ) -> Poll<Option<Bytes>, PayloadError> {
+        let chunk = self.inner.poll()?;
+        if let Async::Ready(Some(ref bytes)) = chunk {
+            self.bytes += bytes.len() as u64;
+        }
+        Ok( chunk )
+    }
+}
+
+impl Drop for CountingPayload {
+    fn drop( &mut self ) {
+        self.ticket.record( &Usage::bytes( 0, self.bytes ) );
+    }
+}
+
+/// Counts the response bytes received from the destination, recording them once the body is
+/// dropped.
+struct CountingBody<B> {
+    inner: ResponseBody<B>,
+    ticket: QuotaTicket,
+    bytes: u64,
+}
+
+impl<B: MessageBody> MessageBody for CountingBody<B> {
+    fn size( &self ) -> BodySize { self.inner.size() }
+
+    fn poll_next( &mut self ) -> Poll<Option<Bytes>, Error> {
+        let chunk = self.inner.poll_next()?;
+        if let Async::Ready(Some(ref bytes)) = chunk {
+            self.bytes += bytes.len() as u64;
+        }
+        Ok( chunk )
+    }
+}
+
+impl<B> Drop for CountingBody<B> {
+    fn drop( &mut self ) {
+        self.ticket.record( &Usage::bytes( self.bytes, 0 ) );
+    }
+}
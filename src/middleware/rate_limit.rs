@@ -0,0 +1,216 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use prometheus::IntCounterVec;
+use log::{debug, info};
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::{http, Error, HttpMessage, ResponseError};
+use actix_http::http::{HeaderMap, HeaderName, HeaderValue};
+use futures::{Future, Poll, future::{ok, Either, FutureResult}};
+use tokio_timer::Delay;
+use crate::border::denial::DenialReason;
+use crate::border::visa::Visa;
+use crate::limits::caller_key;
+use crate::limits::rate::{Acquisition, BucketStatus, RateLimiter};
+use super::proxy_filter::BLOCKED_TOTAL;
+
+const RATELIMIT_LIMIT: &str = "ratelimit-limit";
+const RATELIMIT_REMAINING: &str = "ratelimit-remaining";
+const RATELIMIT_RESET: &str = "ratelimit-reset";
+
+/// Applies token-bucket rate limits to requests already granted a visa, so it must be wrapped
+/// inside the proxy filter.
+pub struct RateLimitCollection( Rc<Family> );
+
+struct Family {
+    limiter: RateLimiter,
+    blocked: &'static IntCounterVec,
+}
+
+impl Default for RateLimitCollection {
+    fn default() -> Self {
+        RateLimitCollection( Rc::new( Family { limiter: RateLimiter::new(), blocked: &BLOCKED_TOTAL, } ) )
+    }
+}
+
+impl RateLimitCollection {
+    pub fn new() -> Self {
+        RateLimitCollection::default()
+    }
+
+    /// Limiters are cheap to clone and share their buckets, so every worker should be given a
+    /// clone of the same limiter.
+    pub fn with_limiter( mut self, limiter: RateLimiter ) -> Self {
+        let family = Rc::get_mut( &mut self.0 ).unwrap();
+        family.limiter = limiter;
+        self
+    }
+}
+
+impl<S, B> Transform<S> for RateLimitCollection
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S>;
+    type Future = FutureResult<Self::Transform, Self::InitError>;
+
+    fn new_transform( &self, service: S ) -> Self::Future {
+        ok( RateLimitMiddleware { service: Rc::new( RefCell::new( service ) ), family: self.0.clone(), } )
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    family: Rc<Family>,
+    service: Rc<RefCell<S>>,
+}
+
+impl<S, B> Service for RateLimitMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn poll_ready( &mut self ) -> Poll<(), Self::Error> { self.service.borrow_mut().poll_ready() }
+
+    fn call( &mut self, req: ServiceRequest ) -> Self::Future {
+        let acquisition = req.extensions()
+            .get::<Visa>()
+            .and_then( |visa| {
+                let bucket = visa.obligations.rate_limit_bucket.as_ref().unwrap_or( &visa.rule );
+                self.family.limiter.acquire( bucket, &visa.rule, &caller_key( &req ) )
+            } );
+
+        match acquisition {
+            None => Box::new( self.service.borrow_mut().call( req ) ),
+
+            Some(Acquisition::Granted(status)) => {
+                Box::new(
+                    self.service.borrow_mut()
+                        .call( req )
+                        .map( move |mut res| {
+                            add_status_headers( res.headers_mut(), &status );
+                            res
+                        } )
+                )
+            },
+
+            Some(Acquisition::Delayed { wait, status }) => {
+                debug!( "egress request {} {} queued for {:?} by rate limit", req.method(), req.uri(), wait );
+                let service = self.service.clone();
+
+                Box::new(
+                    Delay::new( Instant::now() + wait )
+                        .then( move |_| service.borrow_mut().call( req ) )
+                        .map( move |mut res| {
+                            add_status_headers( res.headers_mut(), &status );
+                            res
+                        } )
+                )
+            },
+
+            Some(Acquisition::Exhausted { retry_after, status }) => {
+                let reason = DenialReason::RateLimited { retry_after: round_up( retry_after ) };
+                let rule = req.extensions().get::<Visa>().map( |v| v.rule.clone() ).unwrap_or_default();
+
+                let blocked = self.family.blocked.with(
+                    &labels!{
+                        "method" => req.method().as_str(),
+                        "destination" => rule.as_str(),
+                        "reason" => reason.code(),
+                    }
+                );
+                blocked.inc();
+
+                info!( "egress request {} {} blocked: {}", req.method(), req.uri(), reason );
+                let mut res = req.into_response( reason.error_response().into_body() );
+                add_status_headers( res.headers_mut(), &status );
+                Box::new( ok( res ) )
+            },
+        }
+    }
+}
+
+fn add_status_headers( headers: &mut HeaderMap, status: &BucketStatus ) {
+    headers.insert( HeaderName::from_static( RATELIMIT_LIMIT ), HeaderValue::from( status.limit ) );
+    headers.insert( HeaderName::from_static( RATELIMIT_REMAINING ), HeaderValue::from( status.remaining ) );
+    headers.insert( HeaderName::from_static( RATELIMIT_RESET ), HeaderValue::from( round_up( status.reset ).as_secs() ) );
+}
+
+/// Whole seconds, so clients never retry before the bucket has refilled.
+fn round_up( duration: Duration ) -> Duration {
+    let secs = duration.as_secs() + if duration.subsec_nanos() > 0 { 1 } else { 0 };
+    Duration::from_secs( secs )
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, web, App, HttpResponse};
+    use url::Url;
+    use crate::border::host_control::Destination;
+    use crate::limits::rate::{Exhaustion, RateLimit};
+    use super::*;
+
+    fn app( limit: RateLimit ) -> impl Service<Request = actix_http::Request, Response = ServiceResponse, Error = Error> {
+        let limiter = RateLimiter::new().with_limit( "api", limit );
+        test::init_service(
+            App::new()
+                .wrap( RateLimitCollection::new().with_limiter( limiter ) )
+                .wrap_fn( |req, srv| {
+                    let destination: Destination = Url::parse( "https://api.example.com" ).unwrap().into();
+                    req.extensions_mut().insert( destination.visa( "api" ) );
+                    srv.call( req )
+                } )
+                .default_service( web::to( HttpResponse::Ok ) )
+        )
+    }
+
+    fn header( res: &ServiceResponse, name: &str ) -> String {
+        res.headers().get( name ).unwrap().to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn reports_the_bucket_on_granted_requests() {
+        let mut app = app( RateLimit::new( 1.0, 2 ) );
+        let res = test::call_service( &mut app, test::TestRequest::default().to_request() );
+        assert_eq!( res.status(), http::StatusCode::OK );
+        assert_eq!( header( &res, RATELIMIT_LIMIT ), "2" );
+        assert_eq!( header( &res, RATELIMIT_REMAINING ), "1" );
+        assert_eq!( header( &res, RATELIMIT_RESET ), "1" );
+    }
+
+    #[test]
+    fn refuses_exhausted_requests_with_retry_after_in_whole_seconds() {
+        let mut app = app( RateLimit::new( 0.4, 1 ) );
+        test::call_service( &mut app, test::TestRequest::default().to_request() );
+        let res = test::call_service( &mut app, test::TestRequest::default().to_request() );
+        assert_eq!( res.status(), http::StatusCode::TOO_MANY_REQUESTS );
+        assert_eq!( header( &res, "retry-after" ), "3" );
+        assert_eq!( header( &res, RATELIMIT_LIMIT ), "1" );
+        assert_eq!( header( &res, RATELIMIT_REMAINING ), "0" );
+        assert_eq!( header( &res, RATELIMIT_RESET ), "3" );
+    }
+
+    #[test]
+    fn queues_requests_until_a_token_is_available() {
+        let mut app = app( RateLimit::new( 20.0, 1 ).on_exhausted( Exhaustion::Queue { max_wait: Duration::from_secs( 1 ) } ) );
+        test::call_service( &mut app, test::TestRequest::default().to_request() );
+
+        let start = Instant::now();
+        let res = test::call_service( &mut app, test::TestRequest::default().to_request() );
+        assert_eq!( res.status(), http::StatusCode::OK );
+        assert!( Duration::from_millis( 40 ) <= start.elapsed() );
+        assert_eq!( header( &res, RATELIMIT_REMAINING ), "0" );
+    }
+}
@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::io::Result;
+use std::time::Duration;
+use actix_web::client::{Client, ClientRequest, Connector};
+use actix_http::RequestHead;
+use log::error;
+use url::Url;
+use crate::border::visa::Visa;
+
+pub mod tls;
+
+use self::tls::{plaintext_url, TlsProfile};
+
+/// Time allowed to connect to a destination, including the TLS handshake.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs( 5 );
+
+/// The TLS profiles of destinations with their own TLS settings, by destination name, along
+/// with the profile used for every other `https` destination.
+#[derive(Clone, Debug)]
+pub struct TlsProfiles {
+    default: TlsProfile,
+    named: HashMap<String, TlsProfile>,
+}
+
+impl TlsProfiles {
+    pub fn new() -> Result<Self> {
+        Ok( TlsProfiles { default: TlsProfile::system()?, named: HashMap::new(), } )
+    }
+
+    pub fn with_profile( mut self, name: &str, profile: TlsProfile ) -> Self {
+        self.named.insert( name.to_string(), profile );
+        self
+    }
+
+    pub fn contains( &self, name: &str ) -> bool {
+        self.named.contains_key( name )
+    }
+}
+
+/// The clients a worker forwards requests with: one for plain `http` destinations and one per
+/// TLS profile, so each keeps its own connection pool.
+pub struct UpstreamClients {
+    plain: Client,
+    default_tls: Client,
+    named_tls: HashMap<String, Client>,
+}
+
+impl UpstreamClients {
+    pub fn new( profiles: &TlsProfiles ) -> Self {
+        let named_tls = profiles.named
+            .iter()
+            .map( |( name, profile )| ( name.clone(), tls_client( profile ) ) )
+            .collect();
+
+        UpstreamClients { plain: Client::new(), default_tls: tls_client( &profiles.default ), named_tls, }
+    }
+
+    /// Starts the upstream request for a visa to `url`, connecting to the visa's checked
+    /// address when it has one; `None` when the visa names a TLS profile that is not loaded.
+    pub fn request_from( &self, visa: &Visa, url: &Url, head: &RequestHead ) -> Option<ClientRequest> {
+        let req = if visa.scheme == "https" {
+            self.tls_client( visa )?.request_from( plaintext_url( url ).as_str(), head )
+        } else {
+            self.plain.request_from( url.as_str(), head )
+        };
+
+        match visa.resolved {
+            Some(addr) => Some( req.address( addr ) ),
+            None => Some( req ),
+        }
+    }
+
+    /// Never falls back to the default profile for a profile that is not loaded, as that could
+    /// drop the destination's client certificate or pinned CAs.
+    fn tls_client( &self, visa: &Visa ) -> Option<&Client> {
+        match visa.tls.as_ref() {
+            None => Some( &self.default_tls ),
+            Some(name) => {
+                let client = self.named_tls.get( name );
+                if client.is_none() {
+                    error!( "TLS profile {} is not loaded; restart to apply new TLS settings", name );
+                }
+                client
+            },
+        }
+    }
+}
+
+fn tls_client( profile: &TlsProfile ) -> Client {
+    let connector = Connector::new()
+        .connector( profile.connector( actix_connect::default_connector() ) )
+        .timeout( CONNECT_TIMEOUT )
+        .finish();
+
+    Client::build().connector( connector ).finish()
+}
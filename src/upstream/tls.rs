@@ -0,0 +1,233 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use actix_connect::{ConnectError, Connect, Connection};
+use actix_service::Service;
+use futures::{Async, Future, Poll};
+use native_tls::{Certificate, HandshakeError, Identity, MidHandshakeTlsStream, Protocol};
+use serde_derive::Deserialize;
+use tokio_io::{AsyncRead, AsyncWrite};
+use actix_http::http::Uri;
+use url::Url;
+
+/// Lowest TLS version an upstream connection may negotiate.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize)]
+pub enum TlsVersion {
+    #[serde(rename = "1.0")]
+    Tls10,
+    #[serde(rename = "1.1")]
+    Tls11,
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl TlsVersion {
+    fn protocol( self ) -> Protocol {
+        match self {
+            TlsVersion::Tls10 => Protocol::Tlsv10,
+            TlsVersion::Tls11 => Protocol::Tlsv11,
+            TlsVersion::Tls12 => Protocol::Tlsv12,
+            TlsVersion::Tls13 => Protocol::Tlsv13,
+        }
+    }
+}
+
+/// How the proxy opens TLS connections to a destination: which certificates it trusts, the
+/// server name it sends and verifies, and the client certificate it presents.
+#[derive(Clone)]
+pub struct TlsProfile {
+    connector: native_tls::TlsConnector,
+    server_name: Option<String>,
+}
+
+impl fmt::Debug for TlsProfile {
+    fn fmt( &self, f: &mut fmt::Formatter ) -> fmt::Result {
+        f.debug_struct( "TlsProfile" ).field( "server_name", &self.server_name ).finish()
+    }
+}
+
+impl TlsProfile {
+    /// Trusts the system roots, requires TLS 1.2 and verifies the destination host name.
+    pub fn system() -> io::Result<Self> {
+        TlsProfileBuilder::new().build()
+    }
+
+    /// Wraps connections made by a TCP connector in TLS under this profile.
+    pub fn connector<T>( &self, tcp: T ) -> TlsConnector<T> {
+        TlsConnector { tcp, tls: self.connector.clone(), server_name: self.server_name.clone(), }
+    }
+}
+
+#[derive(Default)]
+pub struct TlsProfileBuilder {
+    ca_bundle: Vec<Certificate>,
+    server_name: Option<String>,
+    min_version: TlsVersion,
+    identity: Option<Identity>,
+}
+
+impl TlsProfileBuilder {
+    pub fn new() -> Self { TlsProfileBuilder::default() }
+
+    /// Trusts only the PEM certificates in `pem` instead of the system roots.
+    pub fn with_ca_bundle( mut self, pem: &[u8] ) -> io::Result<Self> {
+        let certs = Certificate::stack_from_pem( pem ).map_err( tls_error )?;
+        if certs.is_empty() {
+            return Err( io::Error::new( io::ErrorKind::InvalidData, "no certificates in CA bundle" ) );
+        }
+
+        self.ca_bundle.extend( certs );
+        Ok( self )
+    }
+
+    /// Sends and verifies `name` rather than the destination host, e.g. when a destination is
+    /// addressed by IP.
+    pub fn with_server_name( mut self, name: &str ) -> Self {
+        self.server_name = Some( name.to_string() );
+        self
+    }
+
+    pub fn with_min_version( mut self, version: TlsVersion ) -> Self {
+        self.min_version = version;
+        self
+    }
+
+    /// Presents a client certificate, from a PEM certificate chain and PKCS #8 PEM key.
+    pub fn with_client_identity( mut self, cert: &[u8], key: &[u8] ) -> io::Result<Self> {
+        self.identity = Some( Identity::from_pkcs8( cert, key ).map_err( tls_error )? );
+        Ok( self )
+    }
+
+    pub fn build( self ) -> io::Result<TlsProfile> {
+        let mut builder = native_tls::TlsConnector::builder();
+        builder.min_protocol_version( Some( self.min_version.protocol() ) );
+
+        if !self.ca_bundle.is_empty() {
+            builder.disable_built_in_roots( true );
+            for cert in self.ca_bundle {
+                builder.add_root_certificate( cert );
+            }
+        }
+
+        if let Some(identity) = self.identity {
+            builder.identity( identity );
+        }
+
+        let connector = builder.build().map_err( tls_error )?;
+        Ok( TlsProfile { connector, server_name: self.server_name, } )
+    }
+}
+
+/// Service connecting to upstream hosts over TLS, for use as the connector of a `Client`.
+/// Requests are addressed to the client with an `http` URL, since the client itself knows
+/// nothing of TLS; see `plaintext_url`.
+#[derive(Clone)]
+pub struct TlsConnector<T> {
+    tcp: T,
+    tls: native_tls::TlsConnector,
+    server_name: Option<String>,
+}
+
+impl<T, S> Service for TlsConnector<T>
+where
+    T: Service<Request = Connect<Uri>, Response = Connection<Uri, S>, Error = ConnectError>,
+    T::Future: 'static,
+    S: AsyncRead + AsyncWrite + fmt::Debug + 'static,
+{
+    type Request = Connect<Uri>;
+    type Response = Connection<Uri, TlsStream<S>>;
+    type Error = ConnectError;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn poll_ready( &mut self ) -> Poll<(), Self::Error> {
+        self.tcp.poll_ready()
+    }
+
+    fn call( &mut self, req: Self::Request ) -> Self::Future {
+        let domain = self.server_name.clone().unwrap_or_else( || req.host().to_string() );
+        let tls = self.tls.clone();
+
+        Box::new(
+            self.tcp.call( req ).and_then( move |conn| {
+                let ( stream, uri ) = conn.into_parts();
+                Handshake::Start { tls, domain, stream: Some( stream ) }
+                    .map( move |stream| Connection::new( stream, uri ) )
+                    .map_err( ConnectError::Io )
+            } )
+        )
+    }
+}
+
+/// Rewrites an `https` upstream URL for a client using a `TlsConnector`, keeping its port.
+pub fn plaintext_url( url: &Url ) -> Url {
+    let mut plain = url.clone();
+    if let Some(port) = url.port_or_known_default() {
+        plain.set_scheme( "http" ).unwrap();
+        plain.set_port( Some( port ) ).unwrap();
+    }
+    plain
+}
+
+enum Handshake<S> {
+    Start { tls: native_tls::TlsConnector, domain: String, stream: Option<S> },
+    Midway( Option<MidHandshakeTlsStream<S>> ),
+}
+
+impl<S: Read + Write> Future for Handshake<S> {
+    type Item = TlsStream<S>;
+    type Error = io::Error;
+
+    fn poll( &mut self ) -> Poll<Self::Item, Self::Error> {
+        let result = match self {
+            Handshake::Start { tls, domain, stream } => tls.connect( domain, stream.take().unwrap() ),
+            Handshake::Midway( mid ) => mid.take().unwrap().handshake(),
+        };
+
+        match result {
+            Ok(stream) => Ok( Async::Ready( TlsStream( stream ) ) ),
+            Err(HandshakeError::WouldBlock(mid)) => {
+                *self = Handshake::Midway( Some( mid ) );
+                Ok( Async::NotReady )
+            },
+            Err(HandshakeError::Failure(e)) => Err( tls_error( e ) ),
+        }
+    }
+}
+
+/// A TLS session over a non-blocking upstream connection.
+#[derive(Debug)]
+pub struct TlsStream<S>( native_tls::TlsStream<S> );
+
+impl<S: Read + Write> Read for TlsStream<S> {
+    fn read( &mut self, buf: &mut [u8] ) -> io::Result<usize> {
+        self.0.read( buf )
+    }
+}
+
+impl<S: Read + Write> Write for TlsStream<S> {
+    fn write( &mut self, buf: &[u8] ) -> io::Result<usize> {
+        self.0.write( buf )
+    }
+
+    fn flush( &mut self ) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> AsyncRead for TlsStream<S> {}
+
+impl<S: AsyncRead + AsyncWrite> AsyncWrite for TlsStream<S> {
+    fn shutdown( &mut self ) -> Poll<(), io::Error> {
+        match self.0.shutdown() {
+            Ok(_) => self.0.get_mut().shutdown(),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok( Async::NotReady ),
+            Err(e) => Err( e ),
+        }
+    }
+}
+
+fn tls_error( e: native_tls::Error ) -> io::Error {
+    io::Error::other( e )
+}